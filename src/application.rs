@@ -19,8 +19,11 @@
  */
 
 use crate::IconicWindow;
-use crate::config::{APP_ICON, VERSION};
+use crate::config::{APP_ICON, APP_ID, VERSION};
 use crate::glib::WeakRef;
+use crate::objects::regeneration_backup::{RegenerationLock, remove_temporary_files};
+use crate::objects::regeneration_report::{RegenerationReport, ReportEntry};
+use crate::objects::regenerator::{Regenerator, system_accent_color};
 use crate::settings::settings::PreferencesDialog;
 use adw::prelude::{AdwApplicationWindowExt, AdwDialogExt};
 use adw::subclass::prelude::*;
@@ -28,7 +31,9 @@ use gettextrs::gettext;
 use gtk::License;
 use gtk::prelude::*;
 use gtk::{gio, glib};
+use log::*;
 use std::cell::OnceCell;
+use std::ops::ControlFlow;
 
 mod imp {
 
@@ -52,10 +57,22 @@ mod imp {
             let obj = self.obj();
             obj.setup_gactions();
             obj.setup_accels();
+            obj.setup_command_line_options();
         }
     }
 
     impl ApplicationImpl for IconicApplication {
+        // Options that do not need a window are handled here, before the application is activated
+        fn handle_local_options(&self, options: &glib::VariantDict) -> ControlFlow<glib::ExitCode> {
+            if options.contains("regenerate") {
+                return ControlFlow::Break(self.obj().regenerate_headless());
+            }
+            if options.contains("regeneration-report") {
+                return ControlFlow::Break(self.obj().print_regeneration_report());
+            }
+            self.parent_handle_local_options(options)
+        }
+
         // We connect to the activate callback to create a window when the application
         // has been launched. Additionally, this callback notifies us when the user
         // tries to launch a "second instance" of the application. When they try
@@ -125,6 +142,125 @@ impl IconicApplication {
         ]);
     }
 
+    fn setup_command_line_options(&self) {
+        self.add_main_option(
+            "regenerate",
+            glib::Char::from(b'r'),
+            glib::OptionFlags::NONE,
+            glib::OptionArg::None,
+            &gettext("Regenerate the icons if the accent color changed, without opening a window"),
+            None,
+        );
+        self.add_main_option(
+            "regeneration-report",
+            glib::Char::from(b'n'),
+            glib::OptionFlags::NONE,
+            glib::OptionArg::None,
+            &gettext("Show which icons would be regenerated, without changing any files"),
+            None,
+        );
+    }
+
+    // Regenerates the icons if the accent color changed since Iconic last ran, without opening a window
+    // Meant to be started from an autostart entry or a systemd user unit, so the summary is written to the log
+    fn regenerate_headless(&self) -> glib::ExitCode {
+        let settings = gio::Settings::new(APP_ID);
+        let previous_accent: String = settings.string("previous-system-accent-color").into();
+        let current_accent = system_accent_color();
+        if previous_accent == current_accent {
+            info!("Accent color is still {current_accent}, nothing to regenerate");
+            return glib::ExitCode::SUCCESS;
+        }
+        if !settings.boolean("automatic-regeneration") {
+            info!("Automatic regeneration is disabled, not regenerating");
+            return glib::ExitCode::SUCCESS;
+        }
+        // An open window regenerates the icons itself
+        let _regeneration_lock = match RegenerationLock::try_acquire() {
+            Ok(Some(lock)) => lock,
+            Ok(None) => {
+                info!("Iconic is already regenerating the icons, not regenerating");
+                return glib::ExitCode::SUCCESS;
+            }
+            Err(error) => {
+                error!("Regeneration failed: {error}");
+                return glib::ExitCode::FAILURE;
+            }
+        };
+        info!("Accent color changed from {previous_accent} to {current_accent}");
+        remove_temporary_files(&IconicWindow::get_data_path());
+        glib::MainContext::default().block_on(IconicWindow::run_migrations(&settings));
+        let mut report = match RegenerationReport::scan(
+            &IconicWindow::get_data_path(),
+            settings.boolean("strict-regeneration"),
+            settings.boolean("ignore-custom"),
+        ) {
+            Ok(report) => report,
+            Err(error) => {
+                error!("Regeneration failed: {error}");
+                return glib::ExitCode::FAILURE;
+            }
+        };
+        let compatible_files: Vec<ReportEntry> = report.regeneratable().cloned().collect();
+        let regenerator = match Regenerator::new(&settings, current_accent.clone()) {
+            Ok(regenerator) => regenerator,
+            Err(error) => {
+                error!("Regeneration failed: {error}");
+                return glib::ExitCode::FAILURE;
+            }
+        };
+        let mut regenerated = 0;
+        glib::MainContext::default().block_on(regenerator.regenerate_all(
+            compatible_files,
+            || false,
+            |entry, result| match result {
+                Ok(_) => regenerated += 1,
+                Err(error) => {
+                    error!("Error while generating {:?}: {error}", &entry.file_name);
+                    report.mark_failed(&entry.path, error.to_string());
+                }
+            },
+        ));
+        if let Err(error) = regenerator.finish() {
+            warn!("Cleaning up the regeneration backup failed: {error}");
+        }
+        let _ = settings.set_string("previous-system-accent-color", &current_accent);
+        // Make sure the new accent color is written before exiting
+        gio::Settings::sync();
+
+        let failed: Vec<&ReportEntry> = report.failed().collect();
+        info!(
+            "Regenerated {regenerated} icons for accent color {current_accent}, {} skipped, {} failed",
+            report.skipped_count(),
+            failed.len()
+        );
+        for entry in &failed {
+            error!("{}: {}", entry.file_name, entry.status_text());
+        }
+        match failed.is_empty() {
+            true => glib::ExitCode::SUCCESS,
+            false => glib::ExitCode::FAILURE,
+        }
+    }
+
+    fn print_regeneration_report(&self) -> glib::ExitCode {
+        let settings = gio::Settings::new(APP_ID);
+        match RegenerationReport::scan(
+            &IconicWindow::get_data_path(),
+            settings.boolean("strict-regeneration"),
+            settings.boolean("ignore-custom"),
+        ) {
+            Ok(report) => {
+                print!("{}", report.to_text());
+                glib::ExitCode::SUCCESS
+            }
+            Err(error) => {
+                eprintln!("{error}");
+                glib::ExitCode::FAILURE
+            }
+        }
+    }
+
     fn setup_accels(&self) {
         self.set_accels_for_action("app.save_button", &["<primary>s"]);
         self.set_accels_for_action("app.open_top_icon", &["<primary>o"]);
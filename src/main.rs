@@ -39,7 +39,7 @@ use gtk::prelude::*;
 use gtk::{gio, glib};
 
 fn main() -> glib::ExitCode {
-    pretty_env_logger::init();
+    init_logger();
     // Set up gettext translations
     bindtextdomain(GETTEXT_PACKAGE, LOCALEDIR).expect("Unable to bind the text domain");
     bind_textdomain_codeset(GETTEXT_PACKAGE, "UTF-8")
@@ -72,3 +72,15 @@ fn main() -> glib::ExitCode {
     // terminal.
     app.run()
 }
+
+// The headless regeneration writes its summary to the log, so show info messages when no filter is set
+fn init_logger() {
+    let headless = std::env::args().any(|argument| argument == "--regenerate" || argument == "-r");
+    if headless && std::env::var_os("RUST_LOG").is_none() {
+        pretty_env_logger::formatted_builder()
+            .filter_level(log::LevelFilter::Info)
+            .init();
+    } else {
+        pretty_env_logger::init();
+    }
+}
@@ -0,0 +1,157 @@
+use std::io::{BufRead, Cursor, Seek};
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, DynamicImage, Frames, ImageDecoder, RgbaImage, imageops};
+
+use crate::GenResult;
+use crate::objects::errors::IntoResult;
+use crate::objects::file::FileError;
+
+// Only the first frames of longer animations can be picked
+const MAX_FRAMES: usize = 256;
+// Every frame is composed onto the full canvas, so animations with a larger canvas are refused
+const MAX_CANVAS_PIXELS: u64 = 4096 * 4096;
+
+// Image formats that can hold an animation
+// The first frame of an animation is often blank or halfway through a transition, so the user picks one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnimationFormat {
+    Gif,
+    WebP,
+    Png,
+}
+
+// A single frame of an animation
+#[derive(Debug, Clone)]
+pub struct DecodedFrame {
+    pub image: DynamicImage,
+    pub index: usize,
+}
+
+impl AnimationFormat {
+    pub fn from_mime_type(mime_type: &str) -> Option<Self> {
+        match mime_type {
+            "image/gif" => Some(Self::Gif),
+            "image/webp" => Some(Self::WebP),
+            "image/png" | "image/apng" | "image/vnd.mozilla.apng" => Some(Self::Png),
+            _ => None,
+        }
+    }
+
+    // The size of the canvas if the image is animated, only the header is read
+    // Gif files do not tell in their header, so they are always treated as animated
+    pub fn animated_canvas(self, reader: impl BufRead + Seek) -> GenResult<Option<(u32, u32)>> {
+        Ok(match self {
+            Self::Gif => Some(GifDecoder::new(reader)?.dimensions()),
+            Self::WebP => {
+                let decoder = WebPDecoder::new(reader)?;
+                decoder.has_animation().then(|| decoder.dimensions())
+            }
+            Self::Png => {
+                let decoder = PngDecoder::new(reader)?;
+                decoder.is_apng()?.then(|| decoder.dimensions())
+            }
+        })
+    }
+
+    // The frames of the image, already composed onto the full canvas. None if the image is not animated
+    fn frames<'a>(self, reader: impl BufRead + Seek + 'a) -> GenResult<Option<Frames<'a>>> {
+        match self {
+            Self::Gif => {
+                let decoder = GifDecoder::new(reader)?;
+                check_canvas(decoder.dimensions())?;
+                Ok(Some(decoder.into_frames()))
+            }
+            Self::WebP => {
+                let decoder = WebPDecoder::new(reader)?;
+                if !decoder.has_animation() {
+                    return Ok(None);
+                }
+                check_canvas(decoder.dimensions())?;
+                Ok(Some(decoder.into_frames()))
+            }
+            Self::Png => {
+                let decoder = PngDecoder::new(reader)?;
+                if !decoder.is_apng()? {
+                    return Ok(None);
+                }
+                check_canvas(decoder.dimensions())?;
+                Ok(Some(decoder.apng()?.into_frames()))
+            }
+        }
+    }
+
+    // Decodes the frame at index, or the first frame if the animation is shorter
+    // None if the image is not animated, or has only one frame, so it can be loaded like any other image
+    // check_cancelled is called before every frame, so decoding a long animation can be stopped
+    pub fn decode_frame(
+        self,
+        data: &[u8],
+        index: usize,
+        check_cancelled: impl Fn() -> GenResult<()>,
+    ) -> GenResult<Option<DecodedFrame>> {
+        let Some(frames) = self.frames(Cursor::new(data))? else {
+            return Ok(None);
+        };
+        // A frame can build on the frames before it, so every frame up to index is decoded
+        // The frames after it are not, except the second one, which tells whether the image is animated
+        let mut first: Option<RgbaImage> = None;
+        let mut chosen = None;
+        let mut animated = false;
+        for (position, frame) in frames.take(MAX_FRAMES).enumerate() {
+            check_cancelled()?;
+            let buffer = frame?.into_buffer();
+            animated = position > 0;
+            if position == index {
+                chosen = Some(buffer);
+            } else if position == 0 {
+                first = Some(buffer);
+            }
+            if animated && chosen.is_some() {
+                break;
+            }
+        }
+        if !animated {
+            return Ok(None);
+        }
+        let (index, buffer) = match chosen {
+            Some(buffer) => (index, buffer),
+            None => (0, first.into_result()?),
+        };
+        Ok(Some(DecodedFrame {
+            image: DynamicImage::ImageRgba8(buffer),
+            index,
+        }))
+    }
+
+    // A small copy of the first frames, to show while picking a frame
+    // Every frame is scaled down as soon as it is decoded
+    pub fn frame_previews(
+        self,
+        reader: impl BufRead + Seek,
+        size: u32,
+    ) -> GenResult<Vec<DynamicImage>> {
+        let Some(frames) = self.frames(reader)? else {
+            return Ok(vec![]);
+        };
+        frames
+            .take(MAX_FRAMES)
+            .map(|frame| -> GenResult<DynamicImage> {
+                Ok(DynamicImage::ImageRgba8(frame?.into_buffer()).resize(
+                    size,
+                    size,
+                    imageops::FilterType::Triangle,
+                ))
+            })
+            .collect()
+    }
+}
+
+fn check_canvas((width, height): (u32, u32)) -> GenResult<()> {
+    match width as u64 * height as u64 > MAX_CANVAS_PIXELS {
+        true => Err(FileError::TooLarge(width, height).into()),
+        false => Ok(()),
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use crate::GenResult;
+use crate::IconicWindow;
+use crate::objects::regeneration_backup::replace_file_atomically;
+
+// Abandoned drag outputs are kept this long, in case the drop target only reads the file later
+const ABANDONED_GRACE_SECONDS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DragState {
+    Pending,   // The drag has started, but not yet ended
+    Dropped,   // The drag ended in a drop
+    Abandoned, // The drag was cancelled, or Iconic stopped during the drag
+}
+
+// An icon in the data directory that was created by dragging it out of Iconic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DragOutput {
+    pub file_name: String,
+    pub design_hash: String,
+    pub state: DragState,
+    pub changed: u64, // Seconds since the unix epoch
+}
+
+// Keeps track of the drag outputs, so dragging the same design twice reuses one file
+// and cancelled drags can be cleaned up later
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DragIndex {
+    pub outputs: Vec<DragOutput>,
+}
+
+impl DragIndex {
+    fn path() -> PathBuf {
+        IconicWindow::get_cache_path().join("drag_index.json")
+    }
+
+    pub fn load() -> Self {
+        let Ok(data) = fs::read(Self::path()) else {
+            return Self::default();
+        };
+        serde_json::from_slice(&data).unwrap_or_else(|error| {
+            warn!("The drag index could not be read, starting a new one: {error}");
+            Self::default()
+        })
+    }
+
+    pub fn save(&self) -> GenResult<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        replace_file_atomically(&Self::path(), "index", |temporary_path| {
+            fs::write(temporary_path, data)?;
+            Ok(())
+        })
+    }
+
+    // Loads the index, lets change modify it, and saves it again
+    pub fn update<T>(change: impl FnOnce(&mut Self) -> T) -> GenResult<T> {
+        let mut index = Self::load();
+        let result = change(&mut index);
+        index.save()?;
+        Ok(result)
+    }
+
+    // The output of a design that was dragged before, if its file still exists
+    pub fn find(&self, design_hash: &str, data_path: &Path) -> Option<&DragOutput> {
+        self.outputs.iter().find(|output| {
+            output.design_hash == design_hash && data_path.join(&output.file_name).exists()
+        })
+    }
+
+    // Marks the output of a design as pending, and adds it if it is new
+    // An output that was dropped before stays dropped, a folder may still use it
+    pub fn start(&mut self, file_name: &str, design_hash: &str) {
+        if self
+            .outputs
+            .iter()
+            .any(|output| output.file_name == file_name && output.state == DragState::Dropped)
+        {
+            return;
+        }
+        self.outputs.retain(|output| output.file_name != file_name);
+        self.outputs.push(DragOutput {
+            file_name: file_name.to_string(),
+            design_hash: design_hash.to_string(),
+            state: DragState::Pending,
+            changed: now(),
+        });
+    }
+
+    // Only a pending output can end, so a drag that was cancelled stays abandoned when the drag ends
+    pub fn end(&mut self, file_name: &str, state: DragState) {
+        if let Some(output) = self
+            .outputs
+            .iter_mut()
+            .find(|output| output.file_name == file_name && output.state == DragState::Pending)
+        {
+            output.state = state;
+            output.changed = now();
+        }
+    }
+
+    // The outputs that have been abandoned for longer than the grace period
+    // Outputs that are still pending from an earlier run of Iconic are abandoned as well
+    pub fn expired(&self) -> Vec<DragOutput> {
+        let now = now();
+        self.outputs
+            .iter()
+            .filter(|output| output.state != DragState::Dropped)
+            .filter(|output| now.saturating_sub(output.changed) > ABANDONED_GRACE_SECONDS)
+            .cloned()
+            .collect()
+    }
+
+    // Removes the files of expired outputs that no folder uses, and forgets outputs whose file is gone
+    // Expired outputs that are in use were dropped after all, so they are kept
+    // Returns the names of the removed files
+    pub fn clean_up(
+        &mut self,
+        expired: &[DragOutput],
+        used_icons: &HashSet<PathBuf>,
+        data_path: &Path,
+    ) -> Vec<String> {
+        let mut removed = vec![];
+        for output in self.outputs.iter_mut() {
+            // The output may have been dragged again since it expired
+            let Some(expired) = expired
+                .iter()
+                .find(|expired| expired.file_name == output.file_name)
+            else {
+                continue;
+            };
+            // Outputs that were ever dropped are never removed
+            if expired.changed != output.changed || output.state == DragState::Dropped {
+                continue;
+            }
+            let path = data_path.join(&output.file_name);
+            if used_icons.contains(&path) {
+                debug!("{} is used by a folder, keeping it", output.file_name);
+                output.state = DragState::Dropped;
+            } else if fs::remove_file(&path).is_ok() {
+                info!("Removed abandoned drag output {}", output.file_name);
+                output.state = DragState::Abandoned;
+                removed.push(output.file_name.clone());
+            }
+        }
+        // Pending outputs may not be written yet
+        self.outputs.retain(|output| {
+            output.state == DragState::Pending || data_path.join(&output.file_name).exists()
+        });
+        removed
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_data_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("iconic-drag-index-{name}"));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    // Lets the outputs expire without waiting for the grace period
+    fn age(index: &mut DragIndex) {
+        for output in index.outputs.iter_mut() {
+            output.changed = output.changed.saturating_sub(ABANDONED_GRACE_SECONDS + 1);
+        }
+    }
+
+    #[test]
+    fn dropped_output_is_never_removed() {
+        let data_path = test_data_path("dropped");
+        fs::write(data_path.join("folder-a.png"), b"icon").unwrap();
+        let mut index = DragIndex::default();
+        index.start("folder-a.png", "design");
+        index.end("folder-a.png", DragState::Dropped);
+        // Dragging the same design again reuses the output, and that drag is cancelled
+        index.start("folder-a.png", "design");
+        index.end("folder-a.png", DragState::Abandoned);
+        assert_eq!(index.outputs.len(), 1);
+        assert_eq!(index.outputs[0].state, DragState::Dropped);
+
+        age(&mut index);
+        let expired = index.expired();
+        assert!(expired.is_empty());
+        // Even if it was marked as expired, it is not in use and it is kept
+        let mut expired_output = index.outputs[0].clone();
+        expired_output.state = DragState::Abandoned;
+        let removed = index.clean_up(&[expired_output], &HashSet::new(), &data_path);
+        assert!(removed.is_empty());
+        assert!(data_path.join("folder-a.png").exists());
+        assert_eq!(index.outputs.len(), 1);
+        fs::remove_dir_all(&data_path).unwrap();
+    }
+
+    #[test]
+    fn abandoned_output_is_removed() {
+        let data_path = test_data_path("abandoned");
+        fs::write(data_path.join("folder-b.png"), b"icon").unwrap();
+        fs::write(data_path.join("folder-c.png"), b"icon").unwrap();
+        let mut index = DragIndex::default();
+        index.start("folder-b.png", "design b");
+        index.end("folder-b.png", DragState::Abandoned);
+        index.start("folder-c.png", "design c");
+        index.end("folder-c.png", DragState::Abandoned);
+        // Not expired yet
+        assert!(index.expired().is_empty());
+
+        age(&mut index);
+        let expired = index.expired();
+        assert_eq!(expired.len(), 2);
+        // A folder uses folder-c.png, so it was dropped after all
+        let used_icons = HashSet::from([data_path.join("folder-c.png")]);
+        let removed = index.clean_up(&expired, &used_icons, &data_path);
+        assert_eq!(removed, vec!["folder-b.png".to_string()]);
+        assert!(!data_path.join("folder-b.png").exists());
+        assert_eq!(index.outputs.len(), 1);
+        assert_eq!(index.outputs[0].state, DragState::Dropped);
+        fs::remove_dir_all(&data_path).unwrap();
+    }
+}
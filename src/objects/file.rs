@@ -1,17 +1,60 @@
 use adw::prelude::FileExt;
-use gio::{Cancellable, FileQueryInfoFlags};
-use gtk::gio;
+use gio::FileQueryInfoFlags;
+use gtk::gdk_pixbuf::{Pixbuf, PixbufLoader};
+use gtk::prelude::PixbufLoaderExt;
+use gtk::{gio, glib};
 use image::*;
 use log::*;
 use resvg::tiny_skia::Pixmap;
 use resvg::usvg::{Options, Transform, Tree};
 use std::ffi::OsStr;
 use std::fs;
-use std::hash::{DefaultHasher, Hash, Hasher};
-use std::path::PathBuf;
+use std::io::{BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
 
 use crate::GenResult;
+use crate::objects::animation::AnimationFormat;
 use crate::objects::errors::IntoResult;
+use crate::objects::icon_container::{ContainerKind, IconContainer};
+use crate::objects::properties::TopImageSource;
+
+// Images with more pixels are refused, decoding them at full size would take gigabytes of memory
+const MAX_IMAGE_PIXELS: u64 = 16384 * 16384;
+const MAX_FILE_SIZE: i64 = 512 * 1024 * 1024;
+// Large images are read in chunks, so loading can be followed and stopped in between
+const READ_CHUNK_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Error)]
+pub enum FileError {
+    #[error("The image is too large ({0}×{1} pixels)")]
+    TooLarge(u32, u32),
+    #[error("The file is too large ({0} MB)")]
+    FileTooLarge(i64),
+    #[error("Loading the image was cancelled")]
+    Cancelled,
+}
+
+// Lets the window follow and stop the loading of an image
+#[derive(Debug, Clone)]
+pub struct LoadProgress {
+    pub cancellable: gio::Cancellable,
+    // Receives the fraction of the file that has been read
+    pub sender: async_channel::Sender<f64>,
+}
+
+impl LoadProgress {
+    fn check_cancelled(&self) -> GenResult<()> {
+        match self.cancellable.is_cancelled() {
+            true => Err(FileError::Cancelled.into()),
+            false => Ok(()),
+        }
+    }
+
+    fn report(&self, fraction: f64) {
+        let _ = self.sender.try_send(fraction);
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct File {
@@ -22,7 +65,11 @@ pub struct File {
     pub dynamic_image: DynamicImage,
     pub dynamic_image_resized: bool,
     pub thumbnail: DynamicImage,
-    pub hash: u64,
+    pub hash: String,
+    pub source: TopImageSource,
+    // The index of the embedded image, if the file is an .ico or .icns container,
+    // or of the frame, if the file is an animation
+    pub image_index: Option<usize>,
 }
 
 impl File {
@@ -31,9 +78,28 @@ impl File {
     }
 
     pub fn new(file: gio::File, size: u32, thumbnail_size: u32) -> GenResult<Self> {
+        Self::with_image_index(file, size, thumbnail_size, None, None)
+    }
+
+    // Containers load the given embedded image, or the one that best matches size if there is none
+    // Animations load the given frame, or the first frame if there is none
+    pub fn with_image_index(
+        file: gio::File,
+        size: u32,
+        thumbnail_size: u32,
+        image_index: Option<usize>,
+        progress: Option<&LoadProgress>,
+    ) -> GenResult<Self> {
+        let check_cancelled = || progress.map_or(Ok(()), LoadProgress::check_cancelled);
         let temp_path = file.path().into_reason_result("Can't get file path")?;
-        let file_info =
-            file.query_info("standard::", FileQueryInfoFlags::NONE, Cancellable::NONE)?;
+        let file_info = file.query_info(
+            "standard::",
+            FileQueryInfoFlags::NONE,
+            progress.map(|progress| &progress.cancellable),
+        )?;
+        if file_info.size() > MAX_FILE_SIZE {
+            return Err(FileError::FileTooLarge(file_info.size() / (1024 * 1024)).into());
+        }
         let file_name_pathbuf = PathBuf::from(file_info.name().into_os_string());
         let file_name = file_name_pathbuf
             .file_stem()
@@ -41,30 +107,66 @@ impl File {
             .to_str()
             .into_result()?
             .to_string();
-        let file_extension = file_name_pathbuf
-            .extension()
-            .unwrap_or(OsStr::new("png"))
-            .to_str()
-            .into_result()?
-            .to_string();
         let mime_type = file_info.content_type();
         debug!("Mime type: {:?}", mime_type);
-        let mut dynamic_image = if mime_type == Some("image/svg+xml".into()) {
-            let path = temp_path.as_os_str().to_str().into_result()?;
-            Self::load_svg(path, size)?
-        } else {
-            match image::open(temp_path.clone().into_os_string()) {
-                Err(_) => {
-                    let image = ImageReader::open(temp_path.clone().into_os_string())?
-                        .with_guessed_format()?;
-                    image.decode()?
+        let container_kind = mime_type.as_deref().and_then(ContainerKind::from_mime_type);
+        let animation_format = mime_type
+            .as_deref()
+            .and_then(AnimationFormat::from_mime_type);
+        // The hash is based on the original file, so the same file always gets the same cache name
+        let mut chosen_index = None;
+        let mut dynamic_image_resized = false;
+        let mut svg_data = None;
+        // Only the header is read to find out whether the image is animated, the frames are decoded after the size is checked
+        let mut animation = None;
+        if let Some(format) = animation_format {
+            let reader = BufReader::new(fs::File::open(&temp_path)?);
+            if let Some((width, height)) = format.animated_canvas(reader)? {
+                if width as u64 * height as u64 > MAX_IMAGE_PIXELS {
+                    return Err(FileError::TooLarge(width, height).into());
+                }
+                animation = Some((format, Self::read_with_progress(&temp_path, progress)?));
+            }
+        }
+        let (mut dynamic_image, hash) = if mime_type == Some("image/svg+xml".into()) {
+            let (data, hash) = Self::read_with_progress(&temp_path, progress)?;
+            let image = Self::load_svg_data(&data, size)?;
+            svg_data = Some(data);
+            (image, hash)
+        } else if let Some(kind) = container_kind {
+            let (data, _) = Self::read_with_progress(&temp_path, progress)?;
+            let container = IconContainer::parse(kind, &data)?;
+            let index = image_index
+                .filter(|index| *index < container.entries.len())
+                .unwrap_or_else(|| container.best_match(size));
+            let entry = &container.entries[index];
+            debug!(
+                "Loading {}x{} image from container",
+                entry.width, entry.height
+            );
+            chosen_index = Some(index);
+            // Every embedded image gets its own cache name
+            (entry.decode()?, Self::create_hash(&entry.data)?)
+        } else if let Some((format, (data, hash))) = animation {
+            match format.decode_frame(&data, image_index.unwrap_or(0), check_cancelled)? {
+                Some(frame) => {
+                    debug!("Loading frame {}", frame.index + 1);
+                    chosen_index = Some(frame.index);
+                    // Every frame gets its own cache name
+                    let frame_hash =
+                        Self::create_hash(&[data, frame.index.to_le_bytes().to_vec()].concat())?;
+                    (frame.image, frame_hash)
                 }
-                Ok(x) => x,
+                // Images with a single frame are loaded from the data that was already read
+                None => (Self::decode_data_with_limits(&data)?, hash),
             }
+        } else {
+            let (image, hash, resized) = Self::load_raster(&temp_path, size, progress)?;
+            dynamic_image_resized = resized;
+            (image, hash)
         };
-        let hash = Self::create_hash(&dynamic_image);
+        check_cancelled()?;
         debug!("hash of created file: {}", hash);
-        let mut dynamic_image_resized = false;
         // Resize the image if the file is larger than the set size
         // Reduces cache file size, but will take even longer to load image
         if dynamic_image.width() > size as u32 || dynamic_image.height() > size as u32 {
@@ -75,9 +177,8 @@ impl File {
 
         let mut thumbnail = DynamicImage::new_rgb8(0, 0);
         if thumbnail_size > 0 {
-            thumbnail = if file_extension == "svg" {
-                let path = &temp_path.as_os_str().to_str().unwrap();
-                Self::load_svg(path, thumbnail_size)?
+            thumbnail = if let Some(svg_data) = &svg_data {
+                Self::load_svg_data(svg_data, thumbnail_size)?
             } else {
                 dynamic_image.clone().resize(
                     thumbnail_size as u32,
@@ -95,6 +196,8 @@ impl File {
             dynamic_image_resized,
             thumbnail,
             hash,
+            source: TopImageSource::File,
+            image_index: chosen_index,
         })
     }
 
@@ -113,14 +216,14 @@ impl File {
         thumbnail_size: u32,
         max_size: Option<u32>,
         filename: &str,
-    ) -> Self {
+    ) -> GenResult<Self> {
         let mut image = image;
         let thumbnail = image.clone().resize(
             thumbnail_size,
             thumbnail_size,
             imageops::FilterType::Nearest,
         );
-        let hash = Self::create_hash(&image);
+        let hash = Self::create_hash(image.as_bytes())?;
         let mut dynamic_image_resized = false;
         // Resize the image if the file is larger than the set size
         // Reduces cache file size, but will take even longer to load image
@@ -130,7 +233,7 @@ impl File {
                 image = image.resize(size, size, imageops::FilterType::Nearest);
             }
         }
-        Self {
+        Ok(Self {
             files: None,
             path: "".into(),
             extension: ".dynamic".to_string(),
@@ -139,18 +242,147 @@ impl File {
             dynamic_image: image,
             thumbnail,
             dynamic_image_resized,
+            source: TopImageSource::File,
+            image_index: None,
+        })
+    }
+
+    // Images that are larger than size are scaled down while they are decoded,
+    // which takes a lot less memory and time than decoding them at full size first
+    // Returns the hash of the file, and whether the image was scaled down
+    fn load_raster(
+        path: &Path,
+        size: u32,
+        progress: Option<&LoadProgress>,
+    ) -> GenResult<(DynamicImage, String, bool)> {
+        // Only reads the header, so huge images are refused before any pixels are decoded
+        let dimensions = ImageReader::open(path)?
+            .with_guessed_format()?
+            .into_dimensions();
+        if let Ok((width, height)) = dimensions {
+            if width as u64 * height as u64 > MAX_IMAGE_PIXELS {
+                return Err(FileError::TooLarge(width, height).into());
+            }
+            if width <= size && height <= size {
+                let (data, hash) = Self::read_with_progress(path, progress)?;
+                return Ok((Self::decode_data_with_limits(&data)?, hash, false));
+            }
         }
+        // Also tried when the header can't be read, gdk-pixbuf supports some formats the image crate does not
+        match Self::load_scaled(path, size, progress) {
+            Ok((image, hash)) => Ok((image, hash, true)),
+            Err(error) if error.downcast_ref::<FileError>().is_some() => Err(error),
+            Err(error) => {
+                warn!("Could not load scaled image, decoding it at full size: {error}");
+                let (data, hash) = Self::read_with_progress(path, progress)?;
+                Ok((Self::decode_data_with_limits(&data)?, hash, false))
+            }
+        }
+    }
+
+    fn decode_data_with_limits(data: &[u8]) -> GenResult<DynamicImage> {
+        let mut limits = Limits::default();
+        limits.max_alloc = Some(MAX_IMAGE_PIXELS * 4);
+        let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+        reader.limits(limits);
+        Ok(reader.decode()?)
+    }
+
+    // Reads the whole file in chunks, and returns its content and hash
+    fn read_with_progress(
+        path: &Path,
+        progress: Option<&LoadProgress>,
+    ) -> GenResult<(Vec<u8>, String)> {
+        let mut data = vec![];
+        let hash = Self::read_chunks(path, progress, |chunk| {
+            data.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        Ok((data, hash))
     }
 
-    pub fn load_svg(path: &str, size: u32) -> GenResult<DynamicImage> {
-        // Load the SVG file content
-        let svg_data = match fs::read(path) {
-            Ok(x) => x,
-            Err(_) => fs::read("/usr/share/icons/Adwaita/scalable/places/folder.svg")?,
+    // Feeds the file to a pixbuf loader in chunks, which scales the image to fit in size while decoding
+    // The file is hashed along the way, so it does not have to be read twice
+    fn load_scaled(
+        path: &Path,
+        size: u32,
+        progress: Option<&LoadProgress>,
+    ) -> GenResult<(DynamicImage, String)> {
+        let loader = PixbufLoader::new();
+        loader.connect_size_prepared(move |loader, width, height| {
+            let scale = (size as f64 / width.max(height) as f64).min(1.0);
+            debug!("Decoding {width}x{height} image at scale {scale:.3}");
+            loader.set_size(
+                ((width as f64 * scale).round() as i32).max(1),
+                ((height as f64 * scale).round() as i32).max(1),
+            );
+        });
+        let hash = match Self::read_chunks(path, progress, |chunk| Ok(loader.write(chunk)?)) {
+            Ok(hash) => hash,
+            Err(error) => {
+                // Closing an unfinished loader reports an error, which does not matter here
+                let _ = loader.close();
+                return Err(error);
+            }
         };
+        loader.close()?;
+        let pixbuf = loader
+            .pixbuf()
+            .into_reason_result("The image could not be decoded")?;
+        Ok((Self::pixbuf_to_image(&pixbuf)?, hash))
+    }
+
+    // Reads a file in chunks, so reading can be followed and stopped in between, and returns its hash
+    fn read_chunks(
+        path: &Path,
+        progress: Option<&LoadProgress>,
+        mut on_chunk: impl FnMut(&[u8]) -> GenResult<()>,
+    ) -> GenResult<String> {
+        let mut file = fs::File::open(path)?;
+        let length = file.metadata()?.len().max(1);
+        let mut checksum = glib::Checksum::new(glib::ChecksumType::Sha256).into_result()?;
+        let mut buffer = vec![0; READ_CHUNK_SIZE];
+        let mut read = 0;
+        loop {
+            if let Some(progress) = progress {
+                progress.check_cancelled()?;
+            }
+            let count = file.read(&mut buffer)?;
+            if count == 0 {
+                break;
+            }
+            on_chunk(&buffer[..count])?;
+            checksum.update(&buffer[..count]);
+            read += count as u64;
+            if let Some(progress) = progress {
+                progress.report(read as f64 / length as f64);
+            }
+        }
+        Ok(checksum.string().into_result()?.to_string())
+    }
+
+    fn pixbuf_to_image(pixbuf: &Pixbuf) -> GenResult<DynamicImage> {
+        // Makes sure there are always four channels of eight bits
+        let pixbuf = pixbuf.add_alpha(false, 0, 0, 0)?;
+        let (width, height) = (pixbuf.width() as usize, pixbuf.height() as usize);
+        let rowstride = pixbuf.rowstride() as usize;
+        let bytes = pixbuf.read_pixel_bytes();
+        // Rows can be padded, only the pixels of each row are copied
+        let mut pixels = Vec::with_capacity(width * height * 4);
+        for row in 0..height {
+            let start = row * rowstride;
+            pixels.extend_from_slice(&bytes[start..start + width * 4]);
+        }
+        let image = RgbaImage::from_raw(width as u32, height as u32, pixels)
+            .into_reason_result("The decoded image has an unexpected size")?;
+        Ok(DynamicImage::ImageRgba8(image))
+    }
+
+    // Render SVG data that is not stored in a file
+    pub fn load_svg_data(svg_data: &[u8], size: u32) -> GenResult<DynamicImage> {
         // Create an SVG tree
         let opt = Options::default();
-        let rtree = Tree::from_data(&svg_data, &opt)?;
+        let rtree = Tree::from_data(svg_data, &opt)?;
 
         // Specify the output dimensions (you can adjust these as needed)
         let width = rtree.size().width();
@@ -173,11 +405,14 @@ impl File {
         Ok(Self::pixmap_to_image(pixmap))
     }
 
-    // Adds hash of file to file name
-    fn create_hash(image: &DynamicImage) -> u64 {
-        let mut hasher = DefaultHasher::new();
-        let _ = image.as_bytes().hash(&mut hasher);
-        hasher.finish()
+    // SHA-256 of the content, used as the name of the top image in the cache
+    // Unlike the std hasher, this stays the same across Rust releases
+    pub fn create_hash(data: &[u8]) -> GenResult<String> {
+        Ok(
+            glib::compute_checksum_for_data(glib::ChecksumType::Sha256, data)
+                .into_reason_result("The image could not be hashed")?
+                .to_string(),
+        )
     }
 
     fn pixmap_to_image(pixmap: Pixmap) -> DynamicImage {
@@ -0,0 +1,667 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageFormat, RgbaImage, imageops};
+use log::*;
+
+use crate::GenResult;
+
+const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+// The keyword of the iTXt chunk that holds the XMP packet of a PNG image
+const XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+
+// The sizes an icon is saved in, largest first
+const ICO_SIZES: [u32; 6] = [256, 128, 64, 48, 32, 16];
+const ICNS_TYPES: [(&[u8; 4], u32); 7] = [
+    (b"ic10", 1024),
+    (b"ic09", 512),
+    (b"ic08", 256),
+    (b"ic07", 128),
+    (b"icp6", 64),
+    (b"icp5", 32),
+    (b"icp4", 16),
+];
+
+// Windows .ico and macOS .icns files hold the same icon in several sizes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContainerKind {
+    Ico,
+    Icns,
+}
+
+impl ContainerKind {
+    pub fn from_mime_type(mime_type: &str) -> Option<Self> {
+        match mime_type {
+            "image/vnd.microsoft.icon" | "image/x-icon" | "image/x-ico" => Some(Self::Ico),
+            "image/x-icns" | "image/icns" => Some(Self::Icns),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryFormat {
+    Png,
+    // A Windows bitmap without file header, decoded as an icon with only this entry
+    Bitmap { directory_entry: [u8; 16] },
+    // Old icns images, compressed color channels with an optional separate mask
+    PackedRgb { mask: Option<Vec<u8>> },
+    // Small icns images, compressed color channels that start with the alpha channel
+    PackedArgb,
+}
+
+// One of the images embedded in a container
+#[derive(Debug, Clone)]
+pub struct ContainerEntry {
+    pub width: u32,
+    pub height: u32,
+    // Bits per pixel, higher is better if a container has several images of the same size
+    pub bit_depth: u16,
+    pub format: EntryFormat,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IconContainer {
+    // One entry per size, largest first
+    pub entries: Vec<ContainerEntry>,
+}
+
+impl IconContainer {
+    pub fn parse(kind: ContainerKind, data: &[u8]) -> GenResult<Self> {
+        let mut entries = match kind {
+            ContainerKind::Ico => Self::parse_ico(data)?,
+            ContainerKind::Icns => Self::parse_icns(data)?,
+        };
+        // Old icons have variants with fewer colors, only the best variant of every size is kept
+        entries.sort_by(|a, b| {
+            (b.width, b.height, b.bit_depth).cmp(&(a.width, a.height, a.bit_depth))
+        });
+        entries.dedup_by(|a, b| (a.width, a.height) == (b.width, b.height));
+        if entries.is_empty() {
+            return Err("The icon file does not contain any supported images".into());
+        }
+        debug!(
+            "Icon container holds {:?}",
+            entries
+                .iter()
+                .map(|entry| (entry.width, entry.height))
+                .collect::<Vec<_>>()
+        );
+        Ok(Self { entries })
+    }
+
+    // The smallest image that is at least as large as size, or the largest image if none is
+    pub fn best_match(&self, size: u32) -> usize {
+        self.entries
+            .iter()
+            .rposition(|entry| entry.width.max(entry.height) >= size)
+            .unwrap_or(0)
+    }
+
+    // Renders the image at every size the container uses, up to the size of the image itself
+    pub fn from_image(kind: ContainerKind, image: &DynamicImage) -> GenResult<Self> {
+        let sizes: Vec<u32> = match kind {
+            ContainerKind::Ico => ICO_SIZES.to_vec(),
+            ContainerKind::Icns => ICNS_TYPES.iter().map(|(_, size)| *size).collect(),
+        };
+        let largest = image.width().max(image.height());
+        let smallest = sizes.last().copied().unwrap_or(16);
+        let mut entries = vec![];
+        for size in sizes
+            .into_iter()
+            .filter(|size| *size <= largest || *size == smallest)
+        {
+            // Images that are not square are centered on a square canvas
+            let resized = image.resize(size, size, imageops::FilterType::Lanczos3);
+            let mut canvas = RgbaImage::new(size, size);
+            imageops::overlay(
+                &mut canvas,
+                &resized.to_rgba8(),
+                ((size - resized.width()) / 2) as i64,
+                ((size - resized.height()) / 2) as i64,
+            );
+            let mut data = Cursor::new(vec![]);
+            DynamicImage::ImageRgba8(canvas).write_to(&mut data, ImageFormat::Png)?;
+            entries.push(ContainerEntry {
+                width: size,
+                height: size,
+                bit_depth: 32,
+                format: EntryFormat::Png,
+                data: data.into_inner(),
+            });
+        }
+        Ok(Self { entries })
+    }
+
+    // Only containers where every image is a PNG can be written, like the ones Iconic saves
+    pub fn to_bytes(&self, kind: ContainerKind) -> GenResult<Vec<u8>> {
+        if self
+            .entries
+            .iter()
+            .any(|entry| entry.format != EntryFormat::Png)
+        {
+            return Err("Only icon files with PNG images can be written".into());
+        }
+        match kind {
+            ContainerKind::Ico => Ok(self.write_ico()),
+            ContainerKind::Icns => self.write_icns(),
+        }
+    }
+
+    // The metadata is stored in the largest image, as neither container has a place for it
+    pub fn xmp_packet(&self) -> Option<String> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.format == EntryFormat::Png)
+            .find_map(|entry| read_png_xmp(&entry.data))
+    }
+
+    pub fn set_xmp_packet(&mut self, packet: &str) -> GenResult<()> {
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.format == EntryFormat::Png)
+            .ok_or("The icon file does not contain a PNG image to store the metadata in")?;
+        entry.data = write_png_xmp(&entry.data, packet)?;
+        Ok(())
+    }
+
+    fn write_ico(&self) -> Vec<u8> {
+        let mut header = vec![0, 0, 1, 0];
+        header.extend_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        let mut images = vec![];
+        let mut offset = 6 + self.entries.len() * 16;
+        for entry in &self.entries {
+            // A width or height of 256 is stored as 0
+            header.push(entry.width.min(256) as u8);
+            header.push(entry.height.min(256) as u8);
+            // No palette, a reserved byte and one color plane
+            header.extend_from_slice(&[0, 0, 1, 0]);
+            header.extend_from_slice(&entry.bit_depth.to_le_bytes());
+            header.extend_from_slice(&(entry.data.len() as u32).to_le_bytes());
+            header.extend_from_slice(&(offset as u32).to_le_bytes());
+            offset += entry.data.len();
+            images.extend_from_slice(&entry.data);
+        }
+        header.extend(images);
+        header
+    }
+
+    fn write_icns(&self) -> GenResult<Vec<u8>> {
+        let mut body = vec![];
+        for entry in &self.entries {
+            let (os_type, _) = ICNS_TYPES
+                .iter()
+                .find(|(_, size)| *size == entry.width && *size == entry.height)
+                .ok_or("Icns files can not hold an image of this size")?;
+            body.extend_from_slice(*os_type);
+            body.extend_from_slice(&(entry.data.len() as u32 + 8).to_be_bytes());
+            body.extend_from_slice(&entry.data);
+        }
+        let mut data = b"icns".to_vec();
+        data.extend_from_slice(&(body.len() as u32 + 8).to_be_bytes());
+        data.extend(body);
+        Ok(data)
+    }
+
+    fn parse_ico(data: &[u8]) -> GenResult<Vec<ContainerEntry>> {
+        // Type 1 is an icon, type 2 a cursor, which has the same layout
+        if data.len() < 6 || read_u16_le(data, 0) != 0 || !matches!(read_u16_le(data, 2), 1 | 2) {
+            return Err("Not a valid ico file".into());
+        }
+        let count = read_u16_le(data, 4) as usize;
+        let mut entries = vec![];
+        for index in 0..count {
+            let Some(directory_entry) = data.get(6 + index * 16..6 + index * 16 + 16) else {
+                warn!("Ico directory is cut off after {index} entries");
+                break;
+            };
+            let size = read_u32_le(directory_entry, 8) as usize;
+            let offset = read_u32_le(directory_entry, 12) as usize;
+            let Some(image_data) = data.get(offset..offset.saturating_add(size)) else {
+                warn!("Ico entry {index} points outside of the file");
+                continue;
+            };
+            if let Some(entry) = Self::png_entry(image_data) {
+                entries.push(entry);
+                continue;
+            }
+            // A width or height of 0 means 256
+            let dimension = |value: u8| if value == 0 { 256 } else { value as u32 };
+            let mut bit_depth = read_u16_le(directory_entry, 6);
+            if bit_depth == 0 && image_data.len() >= 16 {
+                bit_depth = read_u16_le(image_data, 14);
+            }
+            entries.push(ContainerEntry {
+                width: dimension(directory_entry[0]),
+                height: dimension(directory_entry[1]),
+                bit_depth,
+                format: EntryFormat::Bitmap {
+                    directory_entry: directory_entry.try_into()?,
+                },
+                data: image_data.to_vec(),
+            });
+        }
+        Ok(entries)
+    }
+
+    fn parse_icns(data: &[u8]) -> GenResult<Vec<ContainerEntry>> {
+        if data.len() < 8 || &data[0..4] != b"icns" {
+            return Err("Not a valid icns file".into());
+        }
+        let length = (read_u32_be(data, 4) as usize).min(data.len());
+        let mut entries = vec![];
+        let mut masks: HashMap<[u8; 4], Vec<u8>> = HashMap::new();
+        let mut offset = 8;
+        while offset + 8 <= length {
+            let os_type: [u8; 4] = data[offset..offset + 4].try_into()?;
+            let entry_length = read_u32_be(data, offset + 4) as usize;
+            if entry_length < 8 || offset + entry_length > length {
+                warn!("Icns entry {:?} has an invalid length", os_type);
+                break;
+            }
+            let entry_data = &data[offset + 8..offset + entry_length];
+            offset += entry_length;
+
+            if let Some(entry) = Self::png_entry(entry_data) {
+                entries.push(entry);
+                continue;
+            }
+            match &os_type {
+                b"s8mk" | b"l8mk" | b"h8mk" | b"t8mk" => {
+                    masks.insert(os_type, entry_data.to_vec());
+                }
+                b"is32" | b"il32" | b"ih32" | b"it32" => {
+                    let size = match &os_type {
+                        b"is32" => 16,
+                        b"il32" => 32,
+                        b"ih32" => 48,
+                        _ => 128,
+                    };
+                    // it32 starts with four bytes that are always zero
+                    let skip = if &os_type == b"it32" { 4 } else { 0 };
+                    entries.push(ContainerEntry {
+                        width: size,
+                        height: size,
+                        bit_depth: 24,
+                        format: EntryFormat::PackedRgb { mask: None },
+                        data: entry_data.get(skip..).unwrap_or_default().to_vec(),
+                    });
+                }
+                b"ic04" | b"ic05" | b"icsb" if entry_data.starts_with(b"ARGB") => {
+                    let size = match &os_type {
+                        b"ic04" => 16,
+                        b"ic05" => 32,
+                        _ => 18,
+                    };
+                    entries.push(ContainerEntry {
+                        width: size,
+                        height: size,
+                        bit_depth: 32,
+                        format: EntryFormat::PackedArgb,
+                        data: entry_data[4..].to_vec(),
+                    });
+                }
+                // Most other entries are JPEG 2000 images, which can not be decoded
+                _ => debug!("Skipping unsupported icns entry {:?}", os_type),
+            }
+        }
+        // The masks are separate entries, they are added to their images afterwards
+        for entry in entries.iter_mut() {
+            if let EntryFormat::PackedRgb { mask } = &mut entry.format {
+                let mask_type = match entry.width {
+                    16 => b"s8mk",
+                    32 => b"l8mk",
+                    48 => b"h8mk",
+                    _ => b"t8mk",
+                };
+                *mask = masks.get(mask_type).cloned();
+                if mask.is_some() {
+                    entry.bit_depth = 32;
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn png_entry(data: &[u8]) -> Option<ContainerEntry> {
+        if !data.starts_with(PNG_MAGIC) || data.len() < 26 {
+            return None;
+        }
+        // The size is read from the IHDR chunk, which always comes first
+        let channels = match data[25] {
+            2 => 3,
+            4 => 2,
+            6 => 4,
+            _ => 1,
+        };
+        Some(ContainerEntry {
+            width: read_u32_be(data, 16),
+            height: read_u32_be(data, 20),
+            bit_depth: data[24] as u16 * channels,
+            format: EntryFormat::Png,
+            data: data.to_vec(),
+        })
+    }
+}
+
+impl ContainerEntry {
+    pub fn decode(&self) -> GenResult<DynamicImage> {
+        match &self.format {
+            EntryFormat::Png => Ok(image::load_from_memory_with_format(
+                &self.data,
+                ImageFormat::Png,
+            )?),
+            EntryFormat::Bitmap { directory_entry } => {
+                // Wrap the bitmap in an icon file with only this entry, so the ico decoder reads the mask as well
+                let mut icon = vec![0, 0, 1, 0, 1, 0];
+                icon.extend_from_slice(&directory_entry[0..12]);
+                icon.extend_from_slice(&22u32.to_le_bytes());
+                icon.extend_from_slice(&self.data);
+                Ok(image::load_from_memory_with_format(
+                    &icon,
+                    ImageFormat::Ico,
+                )?)
+            }
+            EntryFormat::PackedRgb { mask } => {
+                let pixels = (self.width * self.height) as usize;
+                let color =
+                    unpack_channels(&self.data, pixels * 3).ok_or("Icns image data is cut off")?;
+                let mut image = RgbaImage::new(self.width, self.height);
+                for (index, pixel) in image.pixels_mut().enumerate() {
+                    let alpha = mask
+                        .as_ref()
+                        .and_then(|mask| mask.get(index).copied())
+                        .unwrap_or(255);
+                    pixel.0 = [
+                        color[index],
+                        color[pixels + index],
+                        color[pixels * 2 + index],
+                        alpha,
+                    ];
+                }
+                Ok(DynamicImage::ImageRgba8(image))
+            }
+            EntryFormat::PackedArgb => {
+                let pixels = (self.width * self.height) as usize;
+                let channels =
+                    unpack_channels(&self.data, pixels * 4).ok_or("Icns image data is cut off")?;
+                let mut image = RgbaImage::new(self.width, self.height);
+                for (index, pixel) in image.pixels_mut().enumerate() {
+                    pixel.0 = [
+                        channels[pixels + index],
+                        channels[pixels * 2 + index],
+                        channels[pixels * 3 + index],
+                        channels[index],
+                    ];
+                }
+                Ok(DynamicImage::ImageRgba8(image))
+            }
+        }
+    }
+
+    pub fn format_name(&self) -> &'static str {
+        match self.format {
+            EntryFormat::Png => "PNG",
+            EntryFormat::Bitmap { .. } => "BMP",
+            EntryFormat::PackedRgb { .. } | EntryFormat::PackedArgb => "Icns",
+        }
+    }
+}
+
+// Only uncompressed packets are read, which is how they are written
+fn read_png_xmp(data: &[u8]) -> Option<String> {
+    let mut offset = PNG_MAGIC.len();
+    while offset + 8 <= data.len() {
+        let length = read_u32_be(data, offset) as usize;
+        let chunk = data.get(offset + 8..offset + 8 + length)?;
+        if &data[offset + 4..offset + 8] == b"iTXt" && is_xmp_chunk(chunk) {
+            // The keyword is followed by the compression flag and method, and a language and translated keyword
+            let rest = &chunk[XMP_KEYWORD.len() + 1..];
+            if rest.first() != Some(&0) {
+                return None;
+            }
+            let mut fields = rest.get(2..)?.splitn(3, |byte| *byte == 0);
+            let (_language, _translated, text) = (fields.next()?, fields.next()?, fields.next()?);
+            return String::from_utf8(text.to_vec()).ok();
+        }
+        offset += 12 + length;
+    }
+    None
+}
+
+// Replaces the XMP packet of a PNG image, the new packet goes right after the header chunk
+fn write_png_xmp(data: &[u8], packet: &str) -> GenResult<Vec<u8>> {
+    if !data.starts_with(PNG_MAGIC) {
+        return Err("Not a valid PNG image".into());
+    }
+    let mut xmp_chunk = XMP_KEYWORD.to_vec();
+    // Null separator, no compression, and an empty language and translated keyword
+    xmp_chunk.extend_from_slice(&[0, 0, 0, 0, 0]);
+    xmp_chunk.extend_from_slice(packet.as_bytes());
+    let mut output = PNG_MAGIC.to_vec();
+    let mut offset = PNG_MAGIC.len();
+    while offset + 8 <= data.len() {
+        let length = read_u32_be(data, offset) as usize;
+        let end = offset + 12 + length;
+        let whole_chunk = data.get(offset..end).ok_or("PNG image is cut off")?;
+        let chunk_type = &whole_chunk[4..8];
+        let chunk = &whole_chunk[8..whole_chunk.len() - 4];
+        if !(chunk_type == b"iTXt" && is_xmp_chunk(chunk)) {
+            output.extend_from_slice(whole_chunk);
+        }
+        if chunk_type == b"IHDR" {
+            write_png_chunk(&mut output, b"iTXt", &xmp_chunk);
+        }
+        offset = end;
+    }
+    Ok(output)
+}
+
+fn is_xmp_chunk(chunk: &[u8]) -> bool {
+    chunk.starts_with(XMP_KEYWORD) && chunk.get(XMP_KEYWORD.len()) == Some(&0)
+}
+
+fn write_png_chunk(output: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    output.extend_from_slice(chunk_type);
+    output.extend_from_slice(data);
+    let checksum = crc32(&[chunk_type.as_slice(), data].concat());
+    output.extend_from_slice(&checksum.to_be_bytes());
+}
+
+// The checksum of a PNG chunk, over its type and data
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xEDB8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+// The channels of old icns images are stored one after another, each compressed with a run length encoding
+// A byte below 0x80 is followed by that many plus one literal bytes,
+// other bytes repeat the next byte that many minus 125 times
+fn unpack_channels(data: &[u8], length: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(length);
+    let mut position = 0;
+    while output.len() < length {
+        let control = *data.get(position)? as usize;
+        position += 1;
+        if control < 0x80 {
+            output.extend_from_slice(data.get(position..position + control + 1)?);
+            position += control + 1;
+        } else {
+            let value = *data.get(position)?;
+            position += 1;
+            output.extend(std::iter::repeat_n(value, control - 125));
+        }
+    }
+    output.truncate(length);
+    Some(output)
+}
+
+fn read_u16_le(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32_le(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u32_be(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(size: u32) -> Vec<u8> {
+        let mut data = Cursor::new(vec![]);
+        DynamicImage::new_rgba8(size, size)
+            .write_to(&mut data, ImageFormat::Png)
+            .unwrap();
+        data.into_inner()
+    }
+
+    // Compresses a channel of a single value with repeat runs, which are at most 130 bytes long
+    fn pack_channel(value: u8, mut length: usize) -> Vec<u8> {
+        let mut packed = vec![];
+        while length > 0 {
+            let run = length.min(130);
+            packed.extend_from_slice(&[(run + 125) as u8, value]);
+            length -= run;
+        }
+        packed
+    }
+
+    fn icns_entry(os_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut entry = os_type.to_vec();
+        entry.extend_from_slice(&(data.len() as u32 + 8).to_be_bytes());
+        entry.extend_from_slice(data);
+        entry
+    }
+
+    fn icns(entries: &[Vec<u8>]) -> Vec<u8> {
+        let body = entries.concat();
+        let mut data = b"icns".to_vec();
+        data.extend_from_slice(&(body.len() as u32 + 8).to_be_bytes());
+        data.extend(body);
+        data
+    }
+
+    #[test]
+    fn crc_of_known_values() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn unpack_literal_and_repeat_runs() {
+        assert_eq!(
+            unpack_channels(&[0x02, 1, 2, 3, 0x80, 9], 6),
+            Some(vec![1, 2, 3, 9, 9, 9])
+        );
+        // Runs that go past the requested length are cut off
+        assert_eq!(unpack_channels(&[0x83, 7], 4), Some(vec![7; 4]));
+        assert_eq!(unpack_channels(&[0x02, 1, 2], 3), None);
+        assert_eq!(unpack_channels(&[0x80], 3), None);
+    }
+
+    #[test]
+    fn ico_with_truncated_directory() {
+        let mut data = vec![0, 0, 1, 0, 2, 0];
+        data.extend_from_slice(&[16, 16, 0, 0, 1, 0, 32, 0]);
+        data.extend_from_slice(&6u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        // The second directory entry is cut off, the first one is still read
+        data.extend_from_slice(&[32, 32, 0, 0, 1, 0, 32, 0]);
+        let container = IconContainer::parse(ContainerKind::Ico, &data).unwrap();
+        assert_eq!(container.entries.len(), 1);
+        assert_eq!(container.entries[0].width, 16);
+        assert!(matches!(
+            container.entries[0].format,
+            EntryFormat::Bitmap { .. }
+        ));
+
+        // An entry that points past the end of the file is skipped
+        data[4] = 1;
+        data[18..22].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(IconContainer::parse(ContainerKind::Ico, &data).is_err());
+        assert!(IconContainer::parse(ContainerKind::Ico, &[0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn icns_with_truncated_entry() {
+        let mut data = icns(&[icns_entry(b"ic07", &png(128))]);
+        // The entries after an entry that is cut off are not read
+        data.extend_from_slice(&icns_entry(b"ic08", &png(256))[..20]);
+        let length = data.len() as u32;
+        data[4..8].copy_from_slice(&length.to_be_bytes());
+        let container = IconContainer::parse(ContainerKind::Icns, &data).unwrap();
+        assert_eq!(container.entries.len(), 1);
+        assert_eq!(container.entries[0].width, 128);
+    }
+
+    #[test]
+    fn icns_packed_images_with_mask() {
+        let packed = |size: usize, red: u8, green: u8, blue: u8| {
+            [
+                pack_channel(red, size * size),
+                pack_channel(green, size * size),
+                pack_channel(blue, size * size),
+            ]
+            .concat()
+        };
+        let it32 = [vec![0; 4], packed(128, 10, 20, 30)].concat();
+        let data = icns(&[
+            icns_entry(b"it32", &it32),
+            icns_entry(b"t8mk", &[128; 128 * 128]),
+            icns_entry(b"is32", &packed(16, 200, 100, 50)),
+            icns_entry(b"s8mk", &[64; 16 * 16]),
+        ]);
+        let container = IconContainer::parse(ContainerKind::Icns, &data).unwrap();
+        assert_eq!(container.entries.len(), 2);
+
+        let large = &container.entries[0];
+        assert_eq!((large.width, large.bit_depth), (128, 32));
+        let image = large.decode().unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), (128, 128));
+        assert_eq!(image.get_pixel(127, 127).0, [10, 20, 30, 128]);
+
+        let small = &container.entries[1];
+        assert_eq!((small.width, small.bit_depth), (16, 32));
+        let image = small.decode().unwrap().to_rgba8();
+        assert_eq!(image.get_pixel(0, 0).0, [200, 100, 50, 64]);
+    }
+
+    #[test]
+    fn replace_existing_xmp_packet() {
+        let data = write_png_xmp(&png(4), "first").unwrap();
+        assert_eq!(read_png_xmp(&data).as_deref(), Some("first"));
+        let data = write_png_xmp(&data, "second").unwrap();
+        assert_eq!(read_png_xmp(&data).as_deref(), Some("second"));
+        let packets = data
+            .windows(XMP_KEYWORD.len())
+            .filter(|window| *window == XMP_KEYWORD)
+            .count();
+        assert_eq!(packets, 1);
+        // The chunks are still valid, so the image can be decoded
+        image::load_from_memory_with_format(&data, ImageFormat::Png).unwrap();
+    }
+
+    #[test]
+    fn write_xmp_into_cut_off_png() {
+        let data = png(4);
+        assert!(write_png_xmp(&data[..data.len() - 2], "packet").is_err());
+        assert!(write_png_xmp(b"not a png", "packet").is_err());
+    }
+}
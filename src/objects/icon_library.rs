@@ -0,0 +1,269 @@
+use gio::prelude::*;
+use gtk::gio;
+use log::*;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// The places where icon themes can be installed
+// Inside of the flatpak the icons of the host are available under /run/host
+// Flatpak applications export their icons to a separate directory
+const ICON_DIRECTORIES: [&str; 5] = [
+    "/run/host/usr/share/icons",
+    "/run/host/share/icons",
+    "/usr/share/icons",
+    "/app/share/icons",
+    "/var/lib/flatpak/exports/share/icons",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstalledIcon {
+    pub name: String,
+    pub path: PathBuf,
+    pub symbolic: bool,
+}
+
+fn icon_directories() -> Vec<PathBuf> {
+    let mut directories: Vec<PathBuf> = ICON_DIRECTORIES.iter().map(PathBuf::from).collect();
+    if let Ok(home) = env::var("HOME") {
+        directories.push(PathBuf::from(&home).join(".local/share/icons"));
+        directories.push(PathBuf::from(&home).join(".local/share/flatpak/exports/share/icons"));
+        directories.push(PathBuf::from(home).join(".icons"));
+    }
+    directories
+}
+
+// Find every SVG icon that is installed on the system.
+// If multiple themes provide an icon with the same name, only the first one found is used
+pub fn find_installed_icons() -> Vec<InstalledIcon> {
+    let mut icons: HashMap<String, InstalledIcon> = HashMap::new();
+    for directory in icon_directories() {
+        walk_icon_directory(&directory, &mut |path| {
+            if let Some(icon) = icon_from_path(path) {
+                icons.entry(icon.name.clone()).or_insert(icon);
+            }
+            false
+        });
+    }
+    let mut icons: Vec<InstalledIcon> = icons.into_values().collect();
+    icons.sort_by(|a, b| a.name.cmp(&b.name));
+    debug!("Found {} installed icons", icons.len());
+    icons
+}
+
+// The names of every installed icon, so many icons can be looked up with a single walk of the icon directories
+#[derive(Debug, Default)]
+pub struct IconNameIndex {
+    // Only scalable icons can be picked as top image, the first one found is used
+    scalable: HashMap<String, PathBuf>,
+    // Application icons can also be PNG, so every size is kept
+    all: HashMap<String, Vec<(u32, PathBuf)>>,
+}
+
+impl IconNameIndex {
+    pub fn build() -> Self {
+        let mut index = Self::default();
+        for directory in icon_directories() {
+            walk_icon_directory(&directory, &mut |path| {
+                let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                    return false;
+                };
+                // Scalable icons get the size 0, so they are not mistaken for a small icon
+                let size = match path.extension().and_then(|extension| extension.to_str()) {
+                    Some("svg") => {
+                        index
+                            .scalable
+                            .entry(name.to_string())
+                            .or_insert_with(|| path.to_path_buf());
+                        0
+                    }
+                    Some("png") => icon_size_from_path(path).unwrap_or(1),
+                    _ => return false,
+                };
+                index
+                    .all
+                    .entry(name.to_string())
+                    .or_default()
+                    .push((size, path.to_path_buf()));
+                false
+            });
+        }
+        debug!("Indexed {} installed icon names", index.all.len());
+        index
+    }
+
+    // Whether resolve_icon would find this icon
+    pub fn has_icon(&self, name: &str) -> bool {
+        self.scalable.contains_key(name)
+    }
+
+    // Whether resolve_application_icon would find an icon for this application
+    pub fn has_application_icon(&self, app_id: &str) -> bool {
+        application_icon(app_id).is_some_and(|icon| self.icon_path(&icon, 1).is_some())
+    }
+
+    // Find the path of a single icon by name, used to re-resolve icons during regeneration
+    pub fn resolve_icon(&self, name: &str) -> Option<PathBuf> {
+        let found = self.scalable.get(name).cloned();
+        info!("Resolved icon {name} to {found:?}");
+        found
+    }
+
+    // Unlike resolve_icon, this also finds PNG icons, as most application icons are not scalable
+    // Scalable icons are preferred, otherwise the smallest icon that is at least size pixels large
+    fn resolve_sized_icon(&self, name: &str, size: u32) -> Option<PathBuf> {
+        let candidates = self.all.get(name)?;
+        if let Some((_, path)) = candidates.iter().find(|(icon_size, _)| *icon_size == 0) {
+            return Some(path.clone());
+        }
+        let large_enough = candidates
+            .iter()
+            .filter(|(icon_size, _)| *icon_size >= size)
+            .min_by_key(|(icon_size, _)| *icon_size);
+        large_enough
+            .or_else(|| candidates.iter().max_by_key(|(icon_size, _)| *icon_size))
+            .map(|(_, path)| path.clone())
+    }
+
+    fn icon_path(&self, icon: &gio::Icon, size: u32) -> Option<PathBuf> {
+        if let Some(file_icon) = icon.downcast_ref::<gio::FileIcon>() {
+            return file_icon.file().path();
+        }
+        icon.downcast_ref::<gio::ThemedIcon>()?
+            .names()
+            .iter()
+            .find_map(|name| self.resolve_sized_icon(name, size))
+    }
+}
+
+// The installed icons that a batch of icons use as top image, looked up once before they are regenerated
+#[derive(Debug, Default)]
+pub struct ResolvedIcons {
+    icons: HashMap<String, PathBuf>,
+    applications: HashMap<String, PathBuf>,
+}
+
+impl ResolvedIcons {
+    // This blocks, so only call it from a worker thread
+    // The icon directories are walked at most once, no matter how many icons are looked up
+    pub fn resolve(icon_names: &HashSet<String>, app_ids: &HashSet<String>, size: u32) -> Self {
+        if icon_names.is_empty() && app_ids.is_empty() {
+            return Self::default();
+        }
+        let index = IconNameIndex::build();
+        let icons = icon_names
+            .iter()
+            .filter_map(|name| Some((name.clone(), index.resolve_icon(name)?)))
+            .collect();
+        let applications = match app_ids.is_empty() {
+            true => HashMap::new(),
+            false => gio::AppInfo::all()
+                .into_iter()
+                .filter_map(|app| {
+                    let app_id = app.id()?.to_string();
+                    if !app_ids.contains(&app_id) {
+                        return None;
+                    }
+                    let path = index.icon_path(&app.icon()?, size);
+                    info!("Resolved the icon of application {app_id} to {path:?}");
+                    Some((app_id, path?))
+                })
+                .collect(),
+        };
+        Self {
+            icons,
+            applications,
+        }
+    }
+
+    pub fn icon(&self, name: &str) -> Option<PathBuf> {
+        self.icons.get(name).cloned()
+    }
+
+    pub fn application_icon(&self, app_id: &str) -> Option<PathBuf> {
+        self.applications.get(app_id).cloned()
+    }
+}
+
+// An application that shows up in the application menu
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstalledApplication {
+    pub id: String,
+    pub name: String,
+    pub icon: gio::Icon,
+}
+
+pub fn find_installed_applications() -> Vec<InstalledApplication> {
+    let mut applications: Vec<InstalledApplication> = gio::AppInfo::all()
+        .into_iter()
+        .filter(|app| app.should_show())
+        .filter_map(|app| {
+            Some(InstalledApplication {
+                id: app.id()?.to_string(),
+                name: app.display_name().to_string(),
+                icon: app.icon()?,
+            })
+        })
+        .collect();
+    applications.sort_by_key(|application| application.name.to_lowercase());
+    debug!("Found {} installed applications", applications.len());
+    applications
+}
+
+// Find the icon file of a single application by its id
+// Scalable icons are preferred, otherwise the smallest icon that is at least size pixels large
+pub fn resolve_application_icon(app_id: &str, size: u32) -> Option<PathBuf> {
+    ResolvedIcons::resolve(&HashSet::new(), &HashSet::from([app_id.to_string()]), size)
+        .application_icon(app_id)
+}
+
+fn application_icon(app_id: &str) -> Option<gio::Icon> {
+    gio::AppInfo::all()
+        .into_iter()
+        .find(|app| app.id().is_some_and(|id| id.as_str() == app_id))?
+        .icon()
+}
+
+// Icon themes store every size in a directory like 48x48 or 48x48@2
+fn icon_size_from_path(path: &Path) -> Option<u32> {
+    path.ancestors().find_map(|ancestor| {
+        let name = ancestor.file_name()?.to_str()?;
+        let (size, scale) = name.split_once('@').unwrap_or((name, "1"));
+        let (width, height) = size.split_once('x')?;
+        (width == height).then_some(width.parse::<u32>().ok()? * scale.parse::<u32>().ok()?)
+    })
+}
+
+fn icon_from_path(path: &Path) -> Option<InstalledIcon> {
+    if path.extension()? != "svg" {
+        return None;
+    }
+    let name = path.file_stem()?.to_str()?.to_string();
+    Some(InstalledIcon {
+        symbolic: name.ends_with("-symbolic"),
+        path: path.to_path_buf(),
+        name,
+    })
+}
+
+// Recursively walk an icon directory, sorted so the same icon is always picked first.
+// The callback returns true to stop walking
+fn walk_icon_directory(directory: &Path, callback: &mut dyn FnMut(&Path) -> bool) -> bool {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return false;
+    };
+    let mut paths: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+    paths.sort();
+    for path in paths {
+        let stop = if path.is_dir() {
+            walk_icon_directory(&path, callback)
+        } else {
+            callback(&path)
+        };
+        if stop {
+            return true;
+        }
+    }
+    false
+}
@@ -0,0 +1,258 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use gettextrs::gettext;
+use gio::prelude::*;
+use gtk::{gio, glib};
+use log::*;
+
+use crate::GenResult;
+use crate::objects::errors::IntoResult;
+use crate::objects::properties::FileProperties;
+use crate::objects::regeneration_backup::{RegenerationBackup, replace_file_atomically};
+use crate::objects::regenerator::Regenerator;
+
+// The attribute file managers like Nautilus use to show a custom icon for a folder
+const CUSTOM_ICON_ATTRIBUTE: &str = "metadata::custom-icon";
+
+// How a folder that uses an Iconic icon can be repaired
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReferenceStatus {
+    Valid,
+    // The icon is missing, but an icon with the same name is in the data directory
+    Moved(PathBuf),
+    // The icon is removed, but a regeneration backup of it still exists
+    Backup(PathBuf),
+    // Nothing is left of the icon, the reference can only be cleared
+    Missing,
+}
+
+// A folder with a custom icon that was created by Iconic
+#[derive(Debug, Clone)]
+pub struct FolderReference {
+    pub folder: PathBuf,
+    pub icon_path: PathBuf,
+    pub status: ReferenceStatus,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IconUsage {
+    pub references: Vec<FolderReference>,
+    pub scanned_folders: usize,
+}
+
+impl IconUsage {
+    // Walks every root and reads the custom icon of every folder, without changing anything
+    // Hidden folders and symbolic links are skipped. This blocks, so run it on a worker thread
+    pub fn scan(roots: &[PathBuf], data_path: &Path) -> Self {
+        let mut usage = Self::default();
+        let mut visited = HashSet::new();
+        let mut folders: Vec<PathBuf> = roots.to_vec();
+        while let Some(folder) = folders.pop() {
+            // Roots can be inside each other, every folder is only checked once
+            if !visited.insert(folder.clone()) {
+                continue;
+            }
+            usage.scanned_folders += 1;
+            if let Some(icon_path) = Self::custom_icon(&folder)
+                && let Some(status) = Self::reference_status(&icon_path, data_path)
+            {
+                debug!("{folder:?} uses {icon_path:?}: {status:?}");
+                usage.references.push(FolderReference {
+                    folder: folder.clone(),
+                    icon_path,
+                    status,
+                });
+            }
+            let Ok(entries) = fs::read_dir(&folder) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let is_folder = entry
+                    .file_type()
+                    .is_ok_and(|file_type| file_type.is_dir() && !file_type.is_symlink());
+                if is_folder && !entry.file_name().to_string_lossy().starts_with('.') {
+                    folders.push(entry.path());
+                }
+            }
+        }
+        usage.references.sort_by(|a, b| a.folder.cmp(&b.folder));
+        info!(
+            "Scanned {} folders, {} use an Iconic icon",
+            usage.scanned_folders,
+            usage.references.len()
+        );
+        usage
+    }
+
+    // The icon a file manager shows for the folder, if it has a custom icon
+    pub fn custom_icon(folder: &Path) -> Option<PathBuf> {
+        let info = gio::File::for_path(folder)
+            .query_info(
+                CUSTOM_ICON_ATTRIBUTE,
+                gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS,
+                gio::Cancellable::NONE,
+            )
+            .ok()?;
+        let value = info.attribute_string(CUSTOM_ICON_ATTRIBUTE)?;
+        // The icon is normally stored as an URI, but some tools store a plain path
+        if value.starts_with('/') {
+            Some(PathBuf::from(value.as_str()))
+        } else {
+            gio::File::for_uri(&value).path()
+        }
+    }
+
+    // None if the icon was not created by Iconic
+    fn reference_status(icon_path: &Path, data_path: &Path) -> Option<ReferenceStatus> {
+        let file_name = icon_path.file_name()?;
+        let in_data_path = icon_path.parent() == Some(data_path);
+        let copy_in_data_path = data_path.join(file_name);
+        if icon_path.exists() {
+            return in_data_path.then_some(ReferenceStatus::Valid);
+        }
+        if !in_data_path && copy_in_data_path.exists() {
+            return Some(ReferenceStatus::Moved(copy_in_data_path));
+        }
+        match RegenerationBackup::find_icon(file_name).ok().flatten() {
+            Some(backup_path) => Some(ReferenceStatus::Backup(backup_path)),
+            None if in_data_path => Some(ReferenceStatus::Missing),
+            None => None,
+        }
+    }
+
+    pub fn broken(&self) -> impl Iterator<Item = &FolderReference> {
+        self.references
+            .iter()
+            .filter(|reference| reference.status != ReferenceStatus::Valid)
+    }
+
+    // The icons in the data directory that are still used by a folder
+    pub fn used_icons(&self) -> HashSet<PathBuf> {
+        self.references
+            .iter()
+            .filter(|reference| reference.status == ReferenceStatus::Valid)
+            .map(|reference| reference.icon_path.clone())
+            .collect()
+    }
+}
+
+impl FolderReference {
+    pub fn status_text(&self) -> String {
+        let file_name = self
+            .icon_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        match &self.status {
+            ReferenceStatus::Valid => format!("{} {file_name}", gettext("Uses")),
+            ReferenceStatus::Moved(_) => format!(
+                "{} {file_name}, {}",
+                gettext("Points to a missing copy of"),
+                gettext("it can be pointed to the icon in the data directory")
+            ),
+            ReferenceStatus::Backup(_) => format!(
+                "{file_name} {}",
+                gettext("was removed, it can be regenerated from a backup")
+            ),
+            ReferenceStatus::Missing => format!(
+                "{file_name} {}",
+                gettext("was removed, the custom icon can only be cleared")
+            ),
+        }
+    }
+
+    pub fn can_repair(&self) -> bool {
+        matches!(
+            self.status,
+            ReferenceStatus::Moved(_) | ReferenceStatus::Backup(_)
+        )
+    }
+
+    // Points the folder to the icon in the data directory
+    // Icons that are restored from a backup are regenerated first, as the accent color may have changed since
+    pub async fn repair(&self, regenerator: &Regenerator, data_path: &Path) -> GenResult<()> {
+        let icon_path = match &self.status {
+            ReferenceStatus::Valid => return Ok(()),
+            ReferenceStatus::Moved(icon_path) => icon_path.clone(),
+            ReferenceStatus::Backup(backup_path) => {
+                let file_name = backup_path
+                    .file_name()
+                    .into_reason_result("Backup has no file name")?;
+                let icon_path = data_path.join(file_name);
+                let restore_path = icon_path.clone();
+                let backup_path = backup_path.clone();
+                gio::spawn_blocking(move || {
+                    replace_file_atomically(&restore_path, "restore", |temporary_path| {
+                        fs::copy(&backup_path, temporary_path)?;
+                        Ok(())
+                    })
+                    .map_err(|error| error.to_string())
+                })
+                .await
+                .unwrap()?;
+                // Icons that can not be regenerated are used as they were
+                match FileProperties::from_path(&icon_path) {
+                    Ok((properties, source)) => {
+                        regenerator
+                            .regenerate_icon(properties, icon_path.clone(), source)
+                            .await?
+                    }
+                    Err(error) => warn!("Restored {icon_path:?} without regenerating: {error}"),
+                }
+                icon_path
+            }
+            ReferenceStatus::Missing => return Err("The icon can not be repaired".into()),
+        };
+        set_custom_icon(&self.folder, &icon_path)
+    }
+
+    // Removes the custom icon, so the folder shows the normal folder icon
+    pub fn clear(&self) -> GenResult<()> {
+        gio::File::for_path(&self.folder).unset_attribute(CUSTOM_ICON_ATTRIBUTE)?;
+        info!("Cleared the custom icon of {:?}", self.folder);
+        Ok(())
+    }
+}
+
+// Points a folder to an icon, in the way file managers store it
+pub fn set_custom_icon(folder: &Path, icon_path: &Path) -> GenResult<()> {
+    let uri = gio::File::for_path(icon_path).uri();
+    gio::File::for_path(folder).set_attribute(
+        CUSTOM_ICON_ATTRIBUTE,
+        uri.as_str(),
+        gio::FileQueryInfoFlags::NONE,
+        gio::Cancellable::NONE,
+    )?;
+    info!("Pointed {folder:?} to {icon_path:?}");
+    Ok(())
+}
+
+// gio-rs can only set attributes to a value, unsetting needs the invalid attribute type
+trait UnsetAttribute {
+    fn unset_attribute(&self, attribute: &str) -> Result<(), glib::Error>;
+}
+
+impl UnsetAttribute for gio::File {
+    fn unset_attribute(&self, attribute: &str) -> Result<(), glib::Error> {
+        use glib::translate::{ToGlibPtr, from_glib_full};
+        let mut error = std::ptr::null_mut();
+        unsafe {
+            gio::ffi::g_file_set_attribute(
+                self.to_glib_none().0,
+                attribute.to_glib_none().0,
+                gio::ffi::G_FILE_ATTRIBUTE_TYPE_INVALID,
+                std::ptr::null_mut(),
+                gio::ffi::G_FILE_QUERY_INFO_NONE,
+                std::ptr::null_mut(),
+                &mut error,
+            );
+            if error.is_null() {
+                Ok(())
+            } else {
+                Err(from_glib_full(error))
+            }
+        }
+    }
+}
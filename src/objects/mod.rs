@@ -1,3 +1,12 @@
+pub mod animation;
+pub mod drag_index;
 pub mod errors;
 pub mod file;
+pub mod icon_container;
+pub mod icon_library;
+pub mod icon_usage;
+pub mod output_format;
 pub mod properties;
+pub mod regeneration_backup;
+pub mod regeneration_report;
+pub mod regenerator;
@@ -0,0 +1,127 @@
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use gettextrs::gettext;
+use image::{DynamicImage, ImageFormat};
+use thiserror::Error;
+
+use crate::GenResult;
+use crate::IconicWindow;
+use crate::objects::icon_container::{ContainerKind, IconContainer};
+use crate::objects::properties::FileProperties;
+
+#[derive(Debug, Error)]
+pub enum OutputError {
+    #[error(
+        "The icon was saved, but its properties could not be added to the {0}, so it can not be regenerated: {1}"
+    )]
+    MetadataNotWritten(String, String),
+}
+
+// The formats an icon can be saved in
+// Icon containers hold the same render at several sizes, for Windows and macOS
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    WebP,
+    Ico,
+    Icns,
+}
+
+impl OutputFormat {
+    pub const ALL: [OutputFormat; 4] = [Self::Png, Self::WebP, Self::Ico, Self::Icns];
+
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_string_lossy().to_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|format| format.extension() == extension)
+    }
+
+    // The format of an existing file, from its first bytes so a wrong extension does not matter
+    pub fn from_path(path: &Path) -> Self {
+        let mut header = [0; 12];
+        let read = fs::File::open(path).and_then(|mut file| file.read_exact(&mut header));
+        match header {
+            _ if read.is_err() => Self::from_extension(path).unwrap_or(Self::Png),
+            [0x89, b'P', b'N', b'G', ..] => Self::Png,
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P'] => Self::WebP,
+            [0, 0, 1, 0, ..] => Self::Ico,
+            [b'i', b'c', b'n', b's', ..] => Self::Icns,
+            _ => Self::from_extension(path).unwrap_or(Self::Png),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::WebP => "webp",
+            Self::Ico => "ico",
+            Self::Icns => "icns",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::WebP => "image/webp",
+            Self::Ico => "image/vnd.microsoft.icon",
+            Self::Icns => "image/x-icns",
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Self::Png => gettext("PNG Image"),
+            Self::WebP => gettext("WebP Image"),
+            Self::Ico => gettext("Windows Icon"),
+            Self::Icns => gettext("macOS Icon"),
+        }
+    }
+
+    pub fn container_kind(&self) -> Option<ContainerKind> {
+        match self {
+            Self::Ico => Some(ContainerKind::Ico),
+            Self::Icns => Some(ContainerKind::Icns),
+            _ => None,
+        }
+    }
+
+    // Writes the image in this format, with the properties as metadata if there are any
+    pub fn save(
+        &self,
+        image: &DynamicImage,
+        path: &Path,
+        properties: Option<&FileProperties>,
+    ) -> GenResult<()> {
+        if let Some(kind) = self.container_kind() {
+            let mut container = IconContainer::from_image(kind, image)?;
+            if let Some(properties) = properties {
+                container.set_xmp_packet(&properties.to_xmp()?.to_string())?;
+            }
+            fs::write(path, container.to_bytes(kind)?)?;
+            return Ok(());
+        }
+        match self {
+            // The WebP encoder only takes 8 bit images
+            Self::WebP => DynamicImage::ImageRgba8(image.to_rgba8())
+                .save_with_format(path, ImageFormat::WebP)?,
+            _ => image.save_with_format(path, ImageFormat::Png)?,
+        }
+        let Some(properties) = properties else {
+            return Ok(());
+        };
+        match (
+            self,
+            IconicWindow::write_image_metadata(path.to_path_buf(), properties),
+        ) {
+            // Not every version of the XMP toolkit can write WebP files, the icon is usable but can't be regenerated
+            (Self::WebP, Err(error)) => Err(Box::new(OutputError::MetadataNotWritten(
+                self.name(),
+                error.to_string(),
+            ))),
+            (_, result) => result,
+        }
+    }
+}
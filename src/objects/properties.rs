@@ -1,27 +1,47 @@
-use std::{fs::DirEntry, path::PathBuf};
+use std::fs::{self, DirEntry};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+use adw::prelude::ComboRowExt;
 use adw::subclass::prelude::ObjectSubclassIsExt;
+use gettextrs::gettext;
 use gio::prelude::SettingsExt;
 use gtk::gdk;
-use gtk::prelude::RangeExt;
+use gtk::prelude::{RangeExt, ToggleButtonExt};
 use hex::FromHex;
 use log::{debug, info};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use xmp_toolkit::{XmpMeta, XmpValue, xmp_ns};
 
+use crate::objects::icon_container::IconContainer;
+use crate::objects::output_format::OutputFormat;
 use crate::{GenResult, objects::errors::IntoResult, window::IconicWindow};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum PropertiesSource {
     XMP,
+    LegacyXMP, // Stored in the core XMP namespace, rewritten in the Iconic namespace when regenerated
     Filename,
 }
 
-#[derive(Debug, Clone, Default)]
+// All properties are stored in their own namespace, so other tools leave them alone
+// Increase the schema version when a property is added or the meaning of a stored property changes
+pub const ICONIC_XMP_NAMESPACE: &str = "https://github.com/youpie/Iconic/xmp/1.0/";
+const ICONIC_XMP_PREFIX: &str = "iconic";
+// 1: The properties are stored in the Iconic namespace
+// 2: Adds the SHA-256 top image hashes, the crop, the color adjustment and the regeneration policy
+pub const XMP_SCHEMA_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct FileProperties {
     pub bottom_image_type: BottomImageType,
-    pub top_image_hash: Option<u64>,
+    pub top_image_hash: Option<String>,
+    pub legacy_top_image_hash: Option<u64>, // Hash created by the std hasher, only read from older files
+    // SHA-256 of the cached top image instead of the original image, only set when older files are migrated,
+    // as the original image is gone by then. The cached image can be resized, so this never matches top_image_hash
+    pub top_image_cache_hash: Option<String>,
+    pub top_image_source: TopImageSource,
     pub x_val: f64,
     pub y_val: f64,
     pub zoom_val: f64,
@@ -30,16 +50,24 @@ pub struct FileProperties {
     pub monochrome_default: bool,
     pub monochrome_color: Option<(u8, u8, u8)>,
     pub monochrome_threshold_val: u8,
+    pub transform: TopImageTransform,
+    pub crop: TopImageCrop,
+    pub color_adjustment: ColorAdjustment,
+    pub blend_mode: BlendMode,
+    pub regeneration_policy: RegenerationPolicy,
     pub default: bool, // If the values above are still equal with the generated image. False if for example, the image was regenerated
 }
 
 impl FileProperties {
     pub fn new(
-        imp: &IconicWindow,
-        top_image_hash: Option<u64>,
+        window: &IconicWindow,
+        top_image_hash: Option<String>,
         default_monochrome_color: gdk::RGBA,
     ) -> Self {
-        let imp = imp.imp();
+        let transform = TopImageTransform::from_window(window);
+        let color_adjustment = ColorAdjustment::from_window(window);
+        let blend_mode = BlendMode::from_window(window);
+        let imp = window.imp();
         let x_val = imp.x_scale.value();
         let y_val = imp.y_scale.value();
         let zoom_val = imp.size.value();
@@ -55,10 +83,27 @@ impl FileProperties {
         let monochrome_default = default_monochrome_color == imp.monochrome_color.rgba();
         let monochrome_threshold_val = imp.threshold_scale.value() as u8;
         let monochrome_invert = imp.monochrome_invert.is_active();
-        let bottom_image_type = imp.file_properties.borrow().bottom_image_type.clone();
+        let regeneration_policy = RegenerationPolicy::from_window(window);
+        let mut bottom_image_type = imp.file_properties.borrow().bottom_image_type.clone();
+        // A folder that follows the accent color can only be pinned to the color it has now
+        if regeneration_policy == RegenerationPolicy::Pinned
+            && bottom_image_type == BottomImageType::FolderSystem
+        {
+            bottom_image_type = BottomImageType::Folder(window.get_accent_color());
+        }
+        let crop = imp.top_image_crop.get();
+        let top_image_source = imp
+            .top_image_file
+            .lock()
+            .ok()
+            .and_then(|file| file.as_ref().map(|file| file.source.clone()))
+            .unwrap_or_default();
         Self {
             bottom_image_type,
             top_image_hash,
+            legacy_top_image_hash: None,
+            top_image_cache_hash: None,
+            top_image_source,
             x_val,
             y_val,
             zoom_val,
@@ -67,17 +112,86 @@ impl FileProperties {
             monochrome_invert,
             monochrome_threshold_val,
             monochrome_toggle,
+            transform,
+            crop,
+            color_adjustment,
+            blend_mode,
+            regeneration_policy,
             default: true,
         }
     }
 
+    // Moves the icon to another folder on purpose
+    // The policy is set to match, so the next regeneration does not move it back to the accent folder
+    pub fn retarget(&mut self, bottom_image_type: BottomImageType) {
+        self.regeneration_policy = match bottom_image_type {
+            BottomImageType::FolderSystem => RegenerationPolicy::FollowAccent,
+            _ => RegenerationPolicy::Pinned,
+        };
+        self.bottom_image_type = bottom_image_type;
+        self.default = false;
+    }
+
+    // Whether regeneration changes this icon
+    pub fn will_regenerate(&self, strict: bool, ignore_custom: bool) -> bool {
+        self.resolved_regeneration_policy(strict, ignore_custom) != RegenerationPolicy::Never
+    }
+
+    // The policy this icon is regenerated with, never Automatic
+    // Icons that follow the preferences get the policy that the strict and ignore custom settings
+    // used to decide for their bottom image
+    pub fn resolved_regeneration_policy(
+        &self,
+        strict: bool,
+        ignore_custom: bool,
+    ) -> RegenerationPolicy {
+        if self.bottom_image_type.is_strict_compatible().is_none() {
+            return RegenerationPolicy::Never;
+        }
+        if self.regeneration_policy != RegenerationPolicy::Automatic {
+            return self.regeneration_policy;
+        }
+        match self.bottom_image_type {
+            BottomImageType::FolderSystem => RegenerationPolicy::FollowAccent,
+            BottomImageType::Folder(_) | BottomImageType::CustomCached(_)
+                if self.default && strict =>
+            {
+                RegenerationPolicy::Never
+            }
+            BottomImageType::Folder(_) if !strict => RegenerationPolicy::FollowAccent,
+            BottomImageType::FolderCustom(_, _) if self.default && strict && ignore_custom => {
+                RegenerationPolicy::Never
+            }
+            BottomImageType::FolderCustom(_, _) if !strict && !ignore_custom => {
+                RegenerationPolicy::FollowAccent
+            }
+            _ => RegenerationPolicy::Pinned,
+        }
+    }
+
     pub fn get_file_properties(file: &DirEntry) -> GenResult<(Self, PropertiesSource)> {
-        if let Ok(xmp_data) = XmpMeta::from_file(file.path()) {
+        Self::from_path(&file.path())
+    }
+
+    pub fn from_path(path: &Path) -> GenResult<(Self, PropertiesSource)> {
+        // The XMP toolkit can not read icon containers, their metadata is stored in the largest image
+        if let Some(kind) = OutputFormat::from_path(path).container_kind() {
+            info!("loading image from icon container XMP");
+            let container = IconContainer::parse(kind, &fs::read(path)?)?;
+            let packet = container
+                .xmp_packet()
+                .into_reason_result("Icon file has no metadata")?;
+            let xmp_data = XmpMeta::from_str(&packet)?;
+            let (namespace, source) = Self::xmp_namespace(&xmp_data)?;
+            return Ok((Self::from_xmp_data(xmp_data, namespace)?, source));
+        }
+        if let Ok(xmp_data) = XmpMeta::from_file(path) {
             info!("loading image from XMP");
-            Ok((Self::from_xmp_data(xmp_data)?, PropertiesSource::XMP))
+            let (namespace, source) = Self::xmp_namespace(&xmp_data)?;
+            Ok((Self::from_xmp_data(xmp_data, namespace)?, source))
         } else {
             info!("loading image from Filename");
-            let file_name = file.file_name();
+            let file_name = path.file_name().into_reason_result("File has no name")?;
             Ok((
                 Self::from_filename(file_name.to_string_lossy().to_string())?,
                 PropertiesSource::Filename,
@@ -125,7 +239,7 @@ impl FileProperties {
         } else {
             None
         };
-        let top_image_hash: Option<u64> =
+        let legacy_top_image_hash: Option<u64> =
             Some(properties_list[FilenameProperty::Hash as usize].parse()?);
         let bottom_image_type = match properties_list[FilenameProperty::DefaultBottomImage as usize]
             .parse::<u8>()?
@@ -144,45 +258,73 @@ impl FileProperties {
             monochrome_invert,
             monochrome_threshold_val,
             monochrome_toggle,
-            top_image_hash,
+            top_image_hash: None,
+            legacy_top_image_hash,
+            top_image_cache_hash: None,
+            top_image_source: TopImageSource::File,
+            transform: TopImageTransform::default(),
+            crop: TopImageCrop::default(),
+            color_adjustment: ColorAdjustment::default(),
+            blend_mode: BlendMode::Normal,
+            regeneration_policy: RegenerationPolicy::Automatic,
             bottom_image_type,
             default: true,
         })
     }
-    fn from_xmp_data(xmp_data: XmpMeta) -> GenResult<Self> {
+    // Files saved before the Iconic namespace existed store their properties in the core XMP namespace
+    fn xmp_namespace(xmp_data: &XmpMeta) -> GenResult<(&'static str, PropertiesSource)> {
+        Self::register_xmp_namespace()?;
+        match xmp_data.property(ICONIC_XMP_NAMESPACE, "schema_version") {
+            Some(version) => {
+                let version: u32 = version.value.parse()?;
+                if version > XMP_SCHEMA_VERSION {
+                    return Err(Box::new(PropertiesError::UnsupportedVersion(version)));
+                }
+                Ok((ICONIC_XMP_NAMESPACE, PropertiesSource::XMP))
+            }
+            None => Ok((xmp_ns::XMP, PropertiesSource::LegacyXMP)),
+        }
+    }
+
+    fn register_xmp_namespace() -> GenResult<()> {
+        XmpMeta::register_namespace(ICONIC_XMP_NAMESPACE, ICONIC_XMP_PREFIX)?;
+        Ok(())
+    }
+
+    fn from_xmp_data(xmp_data: XmpMeta, namespace: &str) -> GenResult<Self> {
         let x_val: f64 = xmp_data
-            .property(xmp_ns::XMP, "x_val")
+            .property(namespace, "x_val")
             .into_reason_result("XMP X-val")?
             .value
             .parse()?;
         let y_val: f64 = xmp_data
-            .property(xmp_ns::XMP, "y_val")
+            .property(namespace, "y_val")
             .into_reason_result("XMP Y-val")?
             .value
             .parse()?;
         let zoom_val: f64 = xmp_data
-            .property(xmp_ns::XMP, "zoom_val")
+            .property(namespace, "zoom_val")
             .into_reason_result("XMP Zoom-val")?
             .value
             .parse()?;
         let monochrome_toggle: bool = xmp_data
-            .property(xmp_ns::XMP, "monochrome_toggle")
+            .property(namespace, "monochrome_toggle")
             .into_reason_result("XMP monochrome_toggle")?
             .value
             .parse()?;
         let monochrome_color: Option<(u8, u8, u8)> = if monochrome_toggle {
             let red: u8 = xmp_data
-                .property(xmp_ns::XMP, "monochrome_red")
+                .property(namespace, "monochrome_red")
                 .into_reason_result("XMP monochrome_red")?
                 .value
                 .parse()?;
             let green: u8 = xmp_data
-                .property(xmp_ns::XMP, "monochrome_green")
+                .property(namespace, "monochrome_green")
                 .into_reason_result("XMP monochrome_green")?
                 .value
                 .parse()?;
             let blue: u8 = xmp_data
-                .property(xmp_ns::XMP, "monochrome_blue")
+                .property(namespace, "monochrome_blue")
                 .into_reason_result("XMP monochrome_blue")?
                 .value
                 .parse()?;
@@ -191,31 +333,121 @@ impl FileProperties {
             None
         };
         let monochrome_default: bool = xmp_data
-            .property(xmp_ns::XMP, "monochrome_default")
+            .property(namespace, "monochrome_default")
             .into_reason_result("XMP monochrome_default")?
             .value
             .parse()?;
         let monochrome_invert: bool = xmp_data
-            .property(xmp_ns::XMP, "monochrome_invert")
+            .property(namespace, "monochrome_invert")
             .into_reason_result("XMP monochrome_invert")?
             .value
             .parse()?;
         let monochrome_threshold_val: u8 = xmp_data
-            .property(xmp_ns::XMP, "monochrome_threshold")
+            .property(namespace, "monochrome_threshold")
             .into_reason_result("XMP monochrome_threshold")?
             .value
             .parse()?;
-        let top_image_hash: Option<u64> = xmp_data
-            .property(xmp_ns::XMP, "top_image_hash")
+        let top_image_hash: Option<String> = xmp_data
+            .property(namespace, "top_image_content_hash")
+            .map(|value| value.value);
+        let legacy_top_image_hash: Option<u64> = xmp_data
+            .property(namespace, "top_image_hash")
             .and_then(|value| Some(value.value.parse().unwrap_or_default()));
+        let top_image_cache_hash: Option<String> = xmp_data
+            .property(namespace, "top_image_cache_hash")
+            .map(|value| value.value);
+        // Older files do not store where the top image came from
+        let top_image_source: TopImageSource =
+            match xmp_data.property(namespace, "top_image_source") {
+                Some(value) => serde_json::from_str(&value.value)?,
+                None => TopImageSource::File,
+            };
         let bottom_image_type: BottomImageType = serde_json::from_str(
             &xmp_data
-                .property(xmp_ns::XMP, "bottom_image_type")
+                .property(namespace, "bottom_image_type")
                 .into_reason_result("XMP bottom_image_type")?
                 .value,
         )?;
+        // Older files do not store any transformation of the top image
+        let default_transform = TopImageTransform::default();
+        let transform = TopImageTransform {
+            rotation: match xmp_data.property(namespace, "rotation") {
+                Some(value) => value.value.parse()?,
+                None => default_transform.rotation,
+            },
+            flip_horizontal: match xmp_data.property(namespace, "flip_horizontal") {
+                Some(value) => value.value.parse()?,
+                None => default_transform.flip_horizontal,
+            },
+            flip_vertical: match xmp_data.property(namespace, "flip_vertical") {
+                Some(value) => value.value.parse()?,
+                None => default_transform.flip_vertical,
+            },
+            opacity: match xmp_data.property(namespace, "opacity") {
+                Some(value) => value.value.parse()?,
+                None => default_transform.opacity,
+            },
+        };
+        // Older files use the whole top image
+        let default_crop = TopImageCrop::default();
+        let crop = TopImageCrop {
+            x: match xmp_data.property(namespace, "crop_x") {
+                Some(value) => value.value.parse()?,
+                None => default_crop.x,
+            },
+            y: match xmp_data.property(namespace, "crop_y") {
+                Some(value) => value.value.parse()?,
+                None => default_crop.y,
+            },
+            width: match xmp_data.property(namespace, "crop_width") {
+                Some(value) => value.value.parse()?,
+                None => default_crop.width,
+            },
+            height: match xmp_data.property(namespace, "crop_height") {
+                Some(value) => value.value.parse()?,
+                None => default_crop.height,
+            },
+            pad_to_square: match xmp_data.property(namespace, "crop_pad_to_square") {
+                Some(value) => value.value.parse()?,
+                None => default_crop.pad_to_square,
+            },
+        };
+        // Older files keep the original colors of the top image
+        let default_adjustment = ColorAdjustment::default();
+        let color_adjustment = ColorAdjustment {
+            brightness: match xmp_data.property(namespace, "brightness") {
+                Some(value) => value.value.parse()?,
+                None => default_adjustment.brightness,
+            },
+            contrast: match xmp_data.property(namespace, "contrast") {
+                Some(value) => value.value.parse()?,
+                None => default_adjustment.contrast,
+            },
+            saturation: match xmp_data.property(namespace, "saturation") {
+                Some(value) => value.value.parse()?,
+                None => default_adjustment.saturation,
+            },
+            hue_shift: match xmp_data.property(namespace, "hue_shift") {
+                Some(value) => value.value.parse()?,
+                None => default_adjustment.hue_shift,
+            },
+            match_accent: match xmp_data.property(namespace, "match_accent_hue") {
+                Some(value) => value.value.parse()?,
+                None => default_adjustment.match_accent,
+            },
+        };
+        let blend_mode: BlendMode = match xmp_data.property(namespace, "blend_mode") {
+            Some(value) => serde_json::from_str(&value.value)?,
+            None => BlendMode::Normal,
+        };
+        // Older files leave regeneration to the preferences
+        let regeneration_policy: RegenerationPolicy =
+            match xmp_data.property(namespace, "regeneration_policy") {
+                Some(value) => serde_json::from_str(&value.value)?,
+                None => RegenerationPolicy::Automatic,
+            };
         let default: bool = xmp_data
-            .property(xmp_ns::XMP, "default")
+            .property(namespace, "default")
             .unwrap_or(XmpValue::new("true".to_owned()))
             .value
             .parse()?;
@@ -229,16 +461,405 @@ impl FileProperties {
             monochrome_threshold_val,
             monochrome_toggle,
             top_image_hash,
+            legacy_top_image_hash,
+            top_image_cache_hash,
+            top_image_source,
+            transform,
+            crop,
+            color_adjustment,
+            blend_mode,
+            regeneration_policy,
             bottom_image_type,
             default,
         })
     }
 }
 
+// Rotation in degrees, and opacity in percent, of the top image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TopImageTransform {
+    pub rotation: f64,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    pub opacity: f64,
+}
+
+impl Default for TopImageTransform {
+    fn default() -> Self {
+        Self {
+            rotation: 0.0,
+            flip_horizontal: false,
+            flip_vertical: false,
+            opacity: 100.0,
+        }
+    }
+}
+
+impl TopImageTransform {
+    pub fn from_window(window: &IconicWindow) -> Self {
+        let imp = window.imp();
+        Self {
+            rotation: imp.rotation_scale.value(),
+            flip_horizontal: imp.flip_horizontal.is_active(),
+            flip_vertical: imp.flip_vertical.is_active(),
+            opacity: imp.opacity_scale.value(),
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+// The part of the top image that is used, as fractions of the original image,
+// so the same crop fits the cached image and the image in the editor, whatever their size
+// Padding adds transparent borders to make the cropped image square
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TopImageCrop {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    pub pad_to_square: bool,
+}
+
+impl Default for TopImageCrop {
+    fn default() -> Self {
+        Self {
+            x: 0.0,
+            y: 0.0,
+            width: 1.0,
+            height: 1.0,
+            pad_to_square: false,
+        }
+    }
+}
+
+impl TopImageCrop {
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // The crop in pixels of an image with these dimensions, as x, y, width and height
+    // Always lies within the image and is at least one pixel large
+    pub fn pixel_rect(&self, dimensions: (u32, u32)) -> (u32, u32, u32, u32) {
+        let to_pixels =
+            |fraction: f64, size: u32| (fraction.clamp(0.0, 1.0) * size as f64).round() as u32;
+        let x = to_pixels(self.x, dimensions.0).min(dimensions.0.saturating_sub(1));
+        let y = to_pixels(self.y, dimensions.1).min(dimensions.1.saturating_sub(1));
+        let width = to_pixels(self.width, dimensions.0).clamp(1, (dimensions.0 - x).max(1));
+        let height = to_pixels(self.height, dimensions.1).clamp(1, (dimensions.1 - y).max(1));
+        (x, y, width, height)
+    }
+
+    // The dimensions of an image after it is cropped and padded
+    pub fn cropped_size(&self, dimensions: (u32, u32)) -> (u32, u32) {
+        let (_, _, width, height) = self.pixel_rect(dimensions);
+        match self.pad_to_square {
+            true => (width.max(height), width.max(height)),
+            false => (width, height),
+        }
+    }
+}
+
+// Changes to the colors of the top image, which are only made when monochrome is off
+// Brightness, contrast and saturation are percentages from -100 to 100, the hue shift is in degrees
+// Matching the accent rotates the hues of the image toward the color of the folder,
+// so the top image follows the folder when it is regenerated with another color
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ColorAdjustment {
+    pub brightness: f64,
+    pub contrast: f64,
+    pub saturation: f64,
+    pub hue_shift: f64,
+    pub match_accent: bool,
+}
+
+impl ColorAdjustment {
+    pub fn from_window(window: &IconicWindow) -> Self {
+        let imp = window.imp();
+        Self {
+            brightness: imp.brightness_scale.value(),
+            contrast: imp.contrast_scale.value(),
+            saturation: imp.saturation_scale.value(),
+            hue_shift: imp.hue_scale.value(),
+            match_accent: imp.match_accent_switch.is_active(),
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+// How the top image is composited onto the bottom image
+// The order matches the blend mode dropdown in the window
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    SoftLight,
+}
+
+impl BlendMode {
+    const ALL: [BlendMode; 5] = [
+        Self::Normal,
+        Self::Multiply,
+        Self::Screen,
+        Self::Overlay,
+        Self::SoftLight,
+    ];
+
+    pub fn from_index(index: u32) -> Self {
+        Self::ALL.get(index as usize).copied().unwrap_or_default()
+    }
+
+    pub fn index(&self) -> u32 {
+        Self::ALL
+            .iter()
+            .position(|mode| mode == self)
+            .unwrap_or_default() as u32
+    }
+
+    pub fn from_window(window: &IconicWindow) -> Self {
+        Self::from_index(window.imp().blend_mode_row.selected())
+    }
+}
+
+impl FileProperties {
+    pub fn to_xmp(&self) -> GenResult<XmpMeta> {
+        Self::register_xmp_namespace()?;
+        let mut metadata = XmpMeta::new()?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "schema_version",
+            &XmpValue::new(XMP_SCHEMA_VERSION.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "x_val",
+            &XmpValue::new(self.x_val.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "y_val",
+            &XmpValue::new(self.y_val.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "zoom_val",
+            &XmpValue::new(self.zoom_val.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "monochrome_toggle",
+            &XmpValue::new(self.monochrome_toggle.to_string()),
+        )?;
+        if let Some(colors) = self.monochrome_color {
+            metadata.set_property(
+                ICONIC_XMP_NAMESPACE,
+                "monochrome_red",
+                &XmpValue::new(colors.0.to_string()),
+            )?;
+            metadata.set_property(
+                ICONIC_XMP_NAMESPACE,
+                "monochrome_green",
+                &XmpValue::new(colors.1.to_string()),
+            )?;
+            metadata.set_property(
+                ICONIC_XMP_NAMESPACE,
+                "monochrome_blue",
+                &XmpValue::new(colors.2.to_string()),
+            )?;
+        }
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "monochrome_default",
+            &XmpValue::new(self.monochrome_default.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "monochrome_invert",
+            &XmpValue::new(self.monochrome_invert.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "monochrome_threshold",
+            &XmpValue::new(self.monochrome_threshold_val.to_string()),
+        )?;
+        if let Some(hash) = &self.top_image_hash {
+            metadata.set_property(
+                ICONIC_XMP_NAMESPACE,
+                "top_image_content_hash",
+                &XmpValue::new(hash.clone()),
+            )?;
+        }
+        if let Some(hash) = self.legacy_top_image_hash {
+            metadata.set_property(
+                ICONIC_XMP_NAMESPACE,
+                "top_image_hash",
+                &XmpValue::new(hash.to_string()),
+            )?;
+        }
+        if let Some(hash) = &self.top_image_cache_hash {
+            metadata.set_property(
+                ICONIC_XMP_NAMESPACE,
+                "top_image_cache_hash",
+                &XmpValue::new(hash.clone()),
+            )?;
+        }
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "rotation",
+            &XmpValue::new(self.transform.rotation.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "flip_horizontal",
+            &XmpValue::new(self.transform.flip_horizontal.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "flip_vertical",
+            &XmpValue::new(self.transform.flip_vertical.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "opacity",
+            &XmpValue::new(self.transform.opacity.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "crop_x",
+            &XmpValue::new(self.crop.x.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "crop_y",
+            &XmpValue::new(self.crop.y.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "crop_width",
+            &XmpValue::new(self.crop.width.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "crop_height",
+            &XmpValue::new(self.crop.height.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "crop_pad_to_square",
+            &XmpValue::new(self.crop.pad_to_square.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "brightness",
+            &XmpValue::new(self.color_adjustment.brightness.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "contrast",
+            &XmpValue::new(self.color_adjustment.contrast.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "saturation",
+            &XmpValue::new(self.color_adjustment.saturation.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "hue_shift",
+            &XmpValue::new(self.color_adjustment.hue_shift.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "match_accent_hue",
+            &XmpValue::new(self.color_adjustment.match_accent.to_string()),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "blend_mode",
+            &XmpValue::new(serde_json::to_string(&self.blend_mode)?),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "top_image_source",
+            &XmpValue::new(serde_json::to_string(&self.top_image_source)?),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "bottom_image_type",
+            &XmpValue::new(serde_json::to_string(&self.bottom_image_type)?),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "regeneration_policy",
+            &XmpValue::new(serde_json::to_string(&self.regeneration_policy)?),
+        )?;
+        metadata.set_property(
+            ICONIC_XMP_NAMESPACE,
+            "default",
+            &XmpValue::new(self.default.to_string()),
+        )?;
+        Ok(metadata)
+    }
+}
+
+// What happens to an icon when icons are regenerated
+// The order matches the regeneration policy dropdowns
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum RegenerationPolicy {
+    #[default]
+    Automatic, // Decided by the strict regeneration and ignore custom folder preferences
+    FollowAccent, // Always moved onto the folder of the accent color
+    Pinned,       // Regenerated on the folder and with the color it has now
+    Never,
+}
+
+impl RegenerationPolicy {
+    pub const ALL: [RegenerationPolicy; 4] = [
+        Self::Automatic,
+        Self::FollowAccent,
+        Self::Pinned,
+        Self::Never,
+    ];
+
+    pub fn from_index(index: u32) -> Self {
+        Self::ALL.get(index as usize).copied().unwrap_or_default()
+    }
+
+    pub fn index(&self) -> u32 {
+        Self::ALL
+            .iter()
+            .position(|policy| policy == self)
+            .unwrap_or_default() as u32
+    }
+
+    pub fn from_window(window: &IconicWindow) -> Self {
+        Self::from_index(window.imp().regeneration_policy_row.selected())
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Self::Automatic => gettext("Follow Preferences"),
+            Self::FollowAccent => gettext("Follow Accent Color"),
+            Self::Pinned => gettext("Keep Folder Color"),
+            Self::Never => gettext("Never Regenerate"),
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum PropertiesError {
     #[error("The provided filename is not compatible")]
     Incompatible,
+    #[error("The metadata was written by a newer version of Iconic (schema version {0})")]
+    UnsupportedVersion(u32),
 }
 
 type Background = String;
@@ -251,7 +872,20 @@ pub enum BottomImageType {
     FolderSystem,
     Folder(String),
     FolderCustom(Foreground, Background),
-    Custom(PathBuf),
+    Custom(PathBuf), // Only read from older files, the image at this path may have changed since
+    CustomCached(String), // Hash of the image in the bottom image cache
+}
+
+// Where the top image originally came from.
+// Icons picked from the installed icon themes can be looked up again by name
+// if the cached top image is missing
+// Application icons store the id of the application, they are looked up again on every regeneration
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub enum TopImageSource {
+    #[default]
+    File,
+    Icon(String),
+    Application(String),
 }
 
 impl BottomImageType {
@@ -262,6 +896,7 @@ impl BottomImageType {
             Self::FolderSystem => Some(true),
             Self::FolderCustom(_, _) => Some(false),
             Self::Folder(value) if value != "Unknown" => Some(false),
+            Self::CustomCached(_) => Some(false),
             _ => None,
         }
     }
@@ -270,20 +905,28 @@ impl BottomImageType {
     pub fn get_base(window: &IconicWindow) -> Self {
         let imp = window.imp();
         if imp.settings.boolean("manual-bottom-image-selection") {
-            let cache_file_name: String = imp.settings.string("folder-cache-name").into();
-            let path = window.check_chache_icon(&cache_file_name);
-            BottomImageType::Custom(path)
-        } else if imp.settings.string("selected-accent-color") == "Custom" {
-            let custom_primary_color: String = imp.settings.string("primary-folder-color").into();
-            let custom_secondary_color: String =
-                imp.settings.string("secondary-folder-color").into();
-            BottomImageType::FolderCustom(custom_primary_color, custom_secondary_color)
+            let hash: String = imp.settings.string("folder-cache-name").into();
+            match window.check_chache_icon(&hash) {
+                Some(hash) => BottomImageType::CustomCached(hash),
+                None => BottomImageType::FolderSystem,
+            }
         } else {
-            let set_folder_color: String = imp.settings.string("selected-accent-color").into();
-            match set_folder_color.as_str() {
-                "None" => BottomImageType::FolderSystem,
-                _ => BottomImageType::Folder(set_folder_color),
+            Self::from_folder_color(&imp.settings.string("selected-accent-color"), &imp.settings)
+        }
+    }
+
+    // The folder with a color named like the selected-accent-color setting
+    // "None" follows the accent color, "Custom" uses the custom folder colors from the settings
+    pub fn from_folder_color(color: &str, settings: &gio::Settings) -> Self {
+        match color {
+            "None" => BottomImageType::FolderSystem,
+            "Custom" => {
+                let custom_primary_color: String = settings.string("primary-folder-color").into();
+                let custom_secondary_color: String =
+                    settings.string("secondary-folder-color").into();
+                BottomImageType::FolderCustom(custom_primary_color, custom_secondary_color)
             }
+            _ => BottomImageType::Folder(color.to_string()),
         }
     }
 }
@@ -347,3 +990,297 @@ impl CustomRGB for gdk::RGBA {
         hex
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::file::File;
+    use crate::objects::icon_container::ContainerKind;
+
+    fn round_trip(properties: &FileProperties) -> FileProperties {
+        let xmp_data = properties.to_xmp().unwrap();
+        let (namespace, source) = FileProperties::xmp_namespace(&xmp_data).unwrap();
+        assert_eq!(source, PropertiesSource::XMP);
+        FileProperties::from_xmp_data(xmp_data, namespace).unwrap()
+    }
+
+    #[test]
+    fn round_trip_every_field() {
+        let properties = FileProperties {
+            bottom_image_type: BottomImageType::FolderCustom("A4CAEE".into(), "438DE6".into()),
+            top_image_hash: Some(File::create_hash(b"top image").unwrap()),
+            legacy_top_image_hash: Some(1234567890),
+            top_image_cache_hash: Some(File::create_hash(b"cached top image").unwrap()),
+            top_image_source: TopImageSource::Icon("folder-music-symbolic".into()),
+            x_val: -12.5,
+            y_val: 9.447,
+            zoom_val: 24.1,
+            monochrome_toggle: true,
+            monochrome_invert: true,
+            monochrome_default: false,
+            monochrome_color: Some((67, 141, 230)),
+            monochrome_threshold_val: 123,
+            transform: TopImageTransform {
+                rotation: -37.5,
+                flip_horizontal: true,
+                flip_vertical: true,
+                opacity: 42.0,
+            },
+            crop: TopImageCrop {
+                x: 0.125,
+                y: 0.3,
+                width: 0.5,
+                height: 0.41,
+                pad_to_square: true,
+            },
+            color_adjustment: ColorAdjustment {
+                brightness: 12.0,
+                contrast: -30.5,
+                saturation: 45.0,
+                hue_shift: -90.0,
+                match_accent: true,
+            },
+            blend_mode: BlendMode::SoftLight,
+            regeneration_policy: RegenerationPolicy::Never,
+            default: false,
+        };
+        assert_eq!(round_trip(&properties), properties);
+    }
+
+    #[test]
+    fn round_trip_minimal() {
+        let properties = FileProperties {
+            bottom_image_type: BottomImageType::FolderSystem,
+            default: true,
+            ..Default::default()
+        };
+        assert_eq!(round_trip(&properties), properties);
+    }
+
+    #[test]
+    fn round_trip_application_source() {
+        let properties = FileProperties {
+            bottom_image_type: BottomImageType::Folder("Green".into()),
+            top_image_hash: Some(File::create_hash(b"application icon").unwrap()),
+            top_image_source: TopImageSource::Application("org.gnome.Nautilus.desktop".into()),
+            ..Default::default()
+        };
+        assert_eq!(round_trip(&properties), properties);
+    }
+
+    #[test]
+    fn round_trip_icon_container() {
+        let properties = FileProperties {
+            bottom_image_type: BottomImageType::Folder("Blue".into()),
+            top_image_hash: Some(File::create_hash(b"container icon").unwrap()),
+            x_val: 4.0,
+            ..Default::default()
+        };
+        let image = image::DynamicImage::new_rgba8(300, 200);
+        let packet = properties.to_xmp().unwrap().to_string();
+        for kind in [ContainerKind::Ico, ContainerKind::Icns] {
+            let mut container = IconContainer::from_image(kind, &image).unwrap();
+            container.set_xmp_packet(&packet).unwrap();
+            let container = IconContainer::parse(kind, &container.to_bytes(kind).unwrap()).unwrap();
+            // Only the sizes up to the size of the render are written
+            assert_eq!(container.entries[0].width, 256);
+            let xmp_data = XmpMeta::from_str(&container.xmp_packet().unwrap()).unwrap();
+            let (namespace, _) = FileProperties::xmp_namespace(&xmp_data).unwrap();
+            assert_eq!(
+                FileProperties::from_xmp_data(xmp_data, namespace).unwrap(),
+                properties
+            );
+        }
+    }
+
+    #[test]
+    fn crop_stays_within_image() {
+        let crop = TopImageCrop {
+            x: 0.25,
+            y: 0.5,
+            width: 0.5,
+            height: 0.25,
+            pad_to_square: true,
+        };
+        assert_eq!(crop.pixel_rect((200, 100)), (50, 50, 100, 25));
+        assert_eq!(crop.cropped_size((200, 100)), (100, 100));
+        // A crop that reaches past the edge of the image is cut off there
+        let crop = TopImageCrop {
+            x: 0.9,
+            y: 0.9,
+            width: 0.5,
+            height: 0.5,
+            pad_to_square: false,
+        };
+        assert_eq!(crop.pixel_rect((10, 10)), (9, 9, 1, 1));
+        assert_eq!(TopImageCrop::default().pixel_rect((0, 0)), (0, 0, 1, 1));
+    }
+
+    #[test]
+    fn read_legacy_namespace() {
+        let mut xmp_data = XmpMeta::new().unwrap();
+        for (key, value) in [
+            ("x_val", "1"),
+            ("y_val", "9.447"),
+            ("zoom_val", "24"),
+            ("monochrome_toggle", "false"),
+            ("monochrome_default", "true"),
+            ("monochrome_invert", "false"),
+            ("monochrome_threshold", "100"),
+            ("top_image_hash", "42"),
+            ("bottom_image_type", "\"FolderSystem\""),
+        ] {
+            xmp_data
+                .set_property(xmp_ns::XMP, key, &XmpValue::new(value.to_owned()))
+                .unwrap();
+        }
+        let (namespace, source) = FileProperties::xmp_namespace(&xmp_data).unwrap();
+        assert_eq!(source, PropertiesSource::LegacyXMP);
+        let properties = FileProperties::from_xmp_data(xmp_data, namespace).unwrap();
+        assert_eq!(properties.x_val, 1.0);
+        assert_eq!(properties.top_image_hash, None);
+        assert_eq!(properties.legacy_top_image_hash, Some(42));
+        assert_eq!(properties.bottom_image_type, BottomImageType::FolderSystem);
+        assert_eq!(properties.transform, TopImageTransform::default());
+        assert_eq!(properties.blend_mode, BlendMode::Normal);
+        assert_eq!(
+            properties.regeneration_policy,
+            RegenerationPolicy::Automatic
+        );
+    }
+
+    #[test]
+    fn resolve_regeneration_policy() {
+        let properties = |bottom_image_type, regeneration_policy, default| FileProperties {
+            bottom_image_type,
+            regeneration_policy,
+            default,
+            ..Default::default()
+        };
+        let green = || BottomImageType::Folder("Green".into());
+        let custom = || BottomImageType::FolderCustom("A4CAEE".into(), "438DE6".into());
+        let automatic = RegenerationPolicy::Automatic;
+        for (properties, strict, ignore_custom, expected) in [
+            (
+                properties(BottomImageType::FolderSystem, automatic, true),
+                true,
+                true,
+                RegenerationPolicy::FollowAccent,
+            ),
+            (
+                properties(green(), automatic, true),
+                true,
+                false,
+                RegenerationPolicy::Never,
+            ),
+            (
+                properties(green(), automatic, false),
+                true,
+                false,
+                RegenerationPolicy::Pinned,
+            ),
+            (
+                properties(green(), automatic, true),
+                false,
+                false,
+                RegenerationPolicy::FollowAccent,
+            ),
+            (
+                properties(custom(), automatic, true),
+                true,
+                true,
+                RegenerationPolicy::Never,
+            ),
+            (
+                properties(custom(), automatic, true),
+                false,
+                false,
+                RegenerationPolicy::FollowAccent,
+            ),
+            (
+                properties(custom(), automatic, true),
+                false,
+                true,
+                RegenerationPolicy::Pinned,
+            ),
+            (
+                properties(green(), RegenerationPolicy::FollowAccent, true),
+                true,
+                true,
+                RegenerationPolicy::FollowAccent,
+            ),
+            (
+                properties(
+                    BottomImageType::FolderSystem,
+                    RegenerationPolicy::Never,
+                    true,
+                ),
+                false,
+                false,
+                RegenerationPolicy::Never,
+            ),
+            (
+                properties(BottomImageType::Unknown, RegenerationPolicy::Pinned, true),
+                false,
+                false,
+                RegenerationPolicy::Never,
+            ),
+        ] {
+            assert_eq!(
+                properties.resolved_regeneration_policy(strict, ignore_custom),
+                expected,
+                "{properties:?} strict: {strict} ignore custom: {ignore_custom}"
+            );
+        }
+    }
+
+    fn with_schema_version(properties: &FileProperties, version: u32) -> XmpMeta {
+        let mut xmp_data = properties.to_xmp().unwrap();
+        xmp_data
+            .set_property(
+                ICONIC_XMP_NAMESPACE,
+                "schema_version",
+                &XmpValue::new(version.to_string()),
+            )
+            .unwrap();
+        xmp_data
+    }
+
+    #[test]
+    fn write_current_schema() {
+        let xmp_data = FileProperties::default().to_xmp().unwrap();
+        let version = xmp_data
+            .property(ICONIC_XMP_NAMESPACE, "schema_version")
+            .unwrap();
+        assert_eq!(version.value, XMP_SCHEMA_VERSION.to_string());
+    }
+
+    #[test]
+    fn read_older_schema() {
+        let properties = FileProperties {
+            bottom_image_type: BottomImageType::FolderSystem,
+            x_val: 3.0,
+            default: true,
+            ..Default::default()
+        };
+        for version in 1..XMP_SCHEMA_VERSION {
+            let xmp_data = with_schema_version(&properties, version);
+            let (namespace, source) = FileProperties::xmp_namespace(&xmp_data).unwrap();
+            assert_eq!(source, PropertiesSource::XMP);
+            assert_eq!(
+                FileProperties::from_xmp_data(xmp_data, namespace).unwrap(),
+                properties
+            );
+        }
+    }
+
+    #[test]
+    fn reject_newer_schema() {
+        let xmp_data = with_schema_version(&FileProperties::default(), XMP_SCHEMA_VERSION + 1);
+        let error = FileProperties::xmp_namespace(&xmp_data).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<PropertiesError>(),
+            Some(PropertiesError::UnsupportedVersion(version)) if *version == XMP_SCHEMA_VERSION + 1
+        ));
+    }
+}
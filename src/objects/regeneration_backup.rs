@@ -0,0 +1,241 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use gtk::glib;
+use log::*;
+
+use crate::GenResult;
+use crate::IconicWindow;
+use crate::objects::errors::IntoResult;
+
+// How many regenerations can be reverted, older backups are removed
+const MAX_BACKUP_GENERATIONS: usize = 5;
+
+// Icons are first written to a hidden file with this marker in its name, and then renamed over the original
+const TEMPORARY_MARKER: &str = ".iconic-tmp";
+
+// How often a regeneration checks if the regeneration before it is done
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Only one regeneration can write icons at a time, also across processes,
+// so a headless regeneration and the window never write the same icons
+// The lock belongs to the open file, so it is also released if Iconic crashes
+#[derive(Debug)]
+pub struct RegenerationLock {
+    _file: fs::File,
+}
+
+impl RegenerationLock {
+    // None if another regeneration holds the lock
+    pub fn try_acquire() -> GenResult<Option<Self>> {
+        let cache_path = IconicWindow::get_cache_path();
+        fs::create_dir_all(&cache_path)?;
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(cache_path.join("regeneration.lock"))?;
+        match file.try_lock() {
+            Ok(_) => Ok(Some(Self { _file: file })),
+            Err(fs::TryLockError::WouldBlock) => Ok(None),
+            Err(fs::TryLockError::Error(error)) => Err(error.into()),
+        }
+    }
+
+    // Waits until the regeneration that holds the lock is done
+    // None if should_stop returns true before that
+    pub async fn acquire(should_stop: impl Fn() -> bool) -> GenResult<Option<Self>> {
+        loop {
+            if should_stop() {
+                return Ok(None);
+            }
+            if let Some(lock) = Self::try_acquire()? {
+                return Ok(Some(lock));
+            }
+            glib::timeout_future(LOCK_POLL_INTERVAL).await;
+        }
+    }
+}
+
+// The versions of all icons from before a single regeneration
+// Every regeneration gets its own generation, named after the time it started
+#[derive(Debug, Clone)]
+pub struct RegenerationBackup {
+    pub id: String,
+    pub path: PathBuf,
+}
+
+impl RegenerationBackup {
+    fn backups_path() -> PathBuf {
+        IconicWindow::get_cache_path().join("regeneration_backups")
+    }
+
+    pub fn new() -> GenResult<Self> {
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_millis()
+            .to_string();
+        let path = Self::backups_path().join(&id);
+        fs::create_dir_all(&path)?;
+        debug!("Created backup generation {id}");
+        Ok(Self { id, path })
+    }
+
+    // All backup generations, the oldest first
+    fn generations() -> GenResult<Vec<Self>> {
+        let backups_path = Self::backups_path();
+        if !backups_path.exists() {
+            return Ok(vec![]);
+        }
+        let mut generations: Vec<(u128, Self)> = fs::read_dir(backups_path)?
+            .flatten()
+            .filter_map(|entry| {
+                let id = entry.file_name().to_str()?.to_string();
+                let time = id.parse().ok()?;
+                Some((
+                    time,
+                    Self {
+                        id,
+                        path: entry.path(),
+                    },
+                ))
+            })
+            .collect();
+        generations.sort_by_key(|(time, _)| *time);
+        Ok(generations
+            .into_iter()
+            .map(|(_, generation)| generation)
+            .collect())
+    }
+
+    // The generation of the last regeneration that changed any icons
+    pub fn latest() -> GenResult<Option<Self>> {
+        Ok(Self::generations()?
+            .into_iter()
+            .rev()
+            .find(|generation| generation.icon_count() > 0))
+    }
+
+    // The newest backed up version of an icon, also if it has since been removed from the data directory
+    pub fn find_icon(file_name: &OsStr) -> GenResult<Option<PathBuf>> {
+        Ok(Self::generations()?
+            .into_iter()
+            .rev()
+            .map(|generation| generation.path.join(file_name))
+            .find(|path| path.exists()))
+    }
+
+    pub fn icon_count(&self) -> usize {
+        fs::read_dir(&self.path)
+            .map(|entries| entries.count())
+            .unwrap_or(0)
+    }
+
+    // Keeps a copy of the icon, and replaces it with the file created by write
+    pub fn replace_icon(
+        &self,
+        icon_path: &Path,
+        write: impl FnOnce(&Path) -> GenResult<()>,
+    ) -> GenResult<()> {
+        let file_name = icon_path
+            .file_name()
+            .into_reason_result("Icon has no file name")?;
+        let backup_path = self.path.join(file_name);
+        // If an icon is changed twice in one regeneration, the version from before the regeneration is kept
+        if icon_path.exists() && !backup_path.exists() {
+            fs::copy(icon_path, &backup_path)?;
+        }
+        replace_file_atomically(icon_path, &self.id, write)
+    }
+
+    // Removes this generation if no icon was changed, and the generations that are too old to keep
+    pub fn finish(&self) -> GenResult<()> {
+        if self.icon_count() == 0 {
+            fs::remove_dir(&self.path)?;
+        }
+        let generations = Self::generations()?;
+        let too_old = generations.len().saturating_sub(MAX_BACKUP_GENERATIONS);
+        for generation in &generations[..too_old] {
+            debug!("Removing backup generation {}", generation.id);
+            fs::remove_dir_all(&generation.path)?;
+        }
+        Ok(())
+    }
+
+    // Puts every icon of this generation back into the data directory, and removes the generation
+    // Returns the names of the restored icons
+    pub fn restore(self, data_path: &Path) -> GenResult<Vec<String>> {
+        let mut restored = vec![];
+        for entry in fs::read_dir(&self.path)?.flatten() {
+            let icon_path = data_path.join(entry.file_name());
+            replace_file_atomically(&icon_path, "restore", |temporary_path| {
+                fs::copy(entry.path(), temporary_path)?;
+                Ok(())
+            })?;
+            restored.push(entry.file_name().to_string_lossy().to_string());
+        }
+        fs::remove_dir_all(&self.path)?;
+        info!(
+            "Restored {} icons from generation {}",
+            restored.len(),
+            self.id
+        );
+        Ok(restored)
+    }
+}
+
+// Lets write create the new version next to the file, and then renames it over the file
+// A rename is atomic, so the file is never left half written, even if Iconic is stopped during the write
+// The extension is kept, as the XMP toolkit uses it to find out the file type
+pub fn replace_file_atomically(
+    path: &Path,
+    tag: &str,
+    write: impl FnOnce(&Path) -> GenResult<()>,
+) -> GenResult<()> {
+    let temporary_path = temporary_path(path, tag)?;
+    let result = write(&temporary_path).and_then(|_| {
+        fs::File::open(&temporary_path)?.sync_all()?;
+        fs::rename(&temporary_path, path)?;
+        Ok(())
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary_path);
+    }
+    result
+}
+
+// The hidden file the new version of path is written to, it is removed by remove_temporary_files if it is left behind
+pub fn temporary_path(path: &Path, tag: &str) -> GenResult<PathBuf> {
+    let file_stem = path
+        .file_stem()
+        .into_reason_result("File has no name")?
+        .to_string_lossy();
+    let temporary_name = match path.extension() {
+        Some(extension) => format!(
+            ".{file_stem}.{tag}{TEMPORARY_MARKER}.{}",
+            extension.to_string_lossy()
+        ),
+        None => format!(".{file_stem}.{tag}{TEMPORARY_MARKER}"),
+    };
+    Ok(path.with_file_name(temporary_name))
+}
+
+pub fn is_temporary_file(file_name: &str) -> bool {
+    file_name.starts_with('.') && file_name.contains(TEMPORARY_MARKER)
+}
+
+// Removes the temporary files that are left behind if Iconic crashed while writing an icon
+// Only call this while holding the RegenerationLock
+pub fn remove_temporary_files(data_path: &Path) {
+    let Ok(entries) = fs::read_dir(data_path) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if is_temporary_file(&entry.file_name().to_string_lossy()) {
+            warn!("Removing left over temporary file {:?}", entry.file_name());
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use std::cell::OnceCell;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use gettextrs::gettext;
+use log::*;
+
+use crate::GenResult;
+use crate::IconicWindow;
+use crate::objects::icon_library::IconNameIndex;
+use crate::objects::properties::{
+    BottomImageType, FileProperties, PropertiesSource, RegenerationPolicy, TopImageSource,
+};
+use crate::objects::regeneration_backup::is_temporary_file;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReportStatus {
+    Regenerate,
+    Skipped(String),
+    Failed(String),
+}
+
+// Everything that decides whether a single icon in the data directory is regenerated
+#[derive(Debug, Clone)]
+pub struct ReportEntry {
+    pub file_name: String,
+    pub path: PathBuf,
+    pub properties: Option<FileProperties>,
+    pub properties_source: Option<PropertiesSource>,
+    pub strict_regenerates: bool,
+    pub top_image_available: bool,
+    pub status: ReportStatus,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RegenerationReport {
+    pub entries: Vec<ReportEntry>,
+}
+
+impl RegenerationReport {
+    // Goes over every icon in the data directory, without changing any files
+    // This reads every icon, so the window runs it on a worker thread
+    pub fn scan(data_path: &Path, strict: bool, ignore_custom: bool) -> GenResult<Self> {
+        let mut entries = vec![];
+        // Only built if an icon is missing from the cache and has to be looked up
+        let icon_index = OnceCell::new();
+        let mut files: Vec<fs::DirEntry> = fs::read_dir(data_path)?
+            .flatten()
+            .filter(|file| !is_temporary_file(&file.file_name().to_string_lossy()))
+            .collect();
+        files.sort_by_key(|file| file.file_name());
+        for file in files {
+            let entry = Self::scan_file(&file, strict, ignore_custom, &icon_index);
+            match &entry.status {
+                ReportStatus::Regenerate => debug!("{} will be regenerated", entry.file_name),
+                ReportStatus::Skipped(reason) | ReportStatus::Failed(reason) => {
+                    warn!("{} will be skipped: {reason}", entry.file_name)
+                }
+            }
+            entries.push(entry);
+        }
+        Ok(Self { entries })
+    }
+
+    fn scan_file(
+        file: &fs::DirEntry,
+        strict: bool,
+        ignore_custom: bool,
+        icon_index: &OnceCell<IconNameIndex>,
+    ) -> ReportEntry {
+        let mut entry = ReportEntry {
+            file_name: file.file_name().to_string_lossy().to_string(),
+            path: file.path(),
+            properties: None,
+            properties_source: None,
+            strict_regenerates: false,
+            top_image_available: false,
+            status: ReportStatus::Regenerate,
+        };
+        let (properties, source) = match FileProperties::get_file_properties(file) {
+            Ok(properties) => properties,
+            Err(error) => {
+                entry.status = ReportStatus::Skipped(format!(
+                    "{}: {error}",
+                    gettext("The properties could not be read")
+                ));
+                return entry;
+            }
+        };
+        entry.properties_source = Some(source);
+        entry.strict_regenerates = properties.will_regenerate(true, ignore_custom);
+        let top_image_path = IconicWindow::top_image_cache_path(&properties);
+        let cached = top_image_path.as_ref().is_some_and(|path| path.exists());
+        // Installed icons are only looked up if their copy in the cache is gone
+        let icon_installed = !cached
+            && match &properties.top_image_source {
+                TopImageSource::Icon(name) => {
+                    icon_index.get_or_init(IconNameIndex::build).has_icon(name)
+                }
+                TopImageSource::Application(app_id) => icon_index
+                    .get_or_init(IconNameIndex::build)
+                    .has_application_icon(app_id),
+                TopImageSource::File => false,
+            };
+        entry.top_image_available = cached || icon_installed;
+
+        entry.status = if properties
+            .bottom_image_type
+            .is_strict_compatible()
+            .is_none()
+        {
+            ReportStatus::Skipped(gettext("The bottom image is not a built-in folder"))
+        } else if !properties.will_regenerate(strict, ignore_custom) {
+            match properties.bottom_image_type {
+                _ if properties.regeneration_policy == RegenerationPolicy::Never => {
+                    ReportStatus::Skipped(gettext("Regeneration is turned off for this icon"))
+                }
+                BottomImageType::FolderCustom(_, _) => {
+                    ReportStatus::Skipped(gettext("Custom folders are ignored in strict mode"))
+                }
+                _ => ReportStatus::Skipped(gettext(
+                    "The folder does not follow the accent color and loose regeneration is disabled",
+                )),
+            }
+        } else if let BottomImageType::CustomCached(hash) = &properties.bottom_image_type
+            && !IconicWindow::bottom_image_cache_path(hash).exists()
+        {
+            ReportStatus::Skipped(gettext("The bottom image is missing from the cache"))
+        } else if top_image_path.is_none() && !icon_installed {
+            ReportStatus::Skipped(gettext("No top image is stored for this icon"))
+        } else if !entry.top_image_available {
+            ReportStatus::Skipped(gettext("The top image is missing from the cache"))
+        } else {
+            ReportStatus::Regenerate
+        };
+        entry.properties = Some(properties);
+        entry
+    }
+
+    pub fn regeneratable(&self) -> impl Iterator<Item = &ReportEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.status == ReportStatus::Regenerate)
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &ReportEntry> {
+        self.entries
+            .iter()
+            .filter(|entry| matches!(entry.status, ReportStatus::Failed(_)))
+    }
+
+    // Failed icons are counted separately
+    pub fn skipped_count(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| matches!(entry.status, ReportStatus::Skipped(_)))
+            .count()
+    }
+
+    pub fn mark_failed(&mut self, path: &Path, reason: String) {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.path == path) {
+            entry.status = ReportStatus::Failed(reason);
+        }
+    }
+
+    // A plain text version of the report, used for the command line
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for entry in &self.entries {
+            let _ = writeln!(text, "{}", entry.file_name);
+            let _ = writeln!(text, "  {}: {}", gettext("Status"), entry.status_text());
+            let _ = writeln!(
+                text,
+                "  {}: {}",
+                gettext("Properties source"),
+                entry.source_text()
+            );
+            let _ = writeln!(
+                text,
+                "  {}: {}",
+                gettext("Bottom image"),
+                entry.bottom_image_text()
+            );
+            let _ = writeln!(
+                text,
+                "  {}: {}",
+                gettext("Regeneration policy"),
+                entry.policy_text()
+            );
+            let _ = writeln!(
+                text,
+                "  {}: {}",
+                gettext("Regenerated in strict mode"),
+                yes_no(entry.strict_regenerates)
+            );
+            let _ = writeln!(
+                text,
+                "  {}: {}",
+                gettext("Top image available"),
+                yes_no(entry.top_image_available)
+            );
+        }
+        let _ = writeln!(
+            text,
+            "{}: {}, {}: {}",
+            gettext("Regenerate"),
+            self.regeneratable().count(),
+            gettext("Skipped"),
+            self.skipped_count()
+        );
+        text
+    }
+}
+
+impl ReportEntry {
+    pub fn status_text(&self) -> String {
+        match &self.status {
+            ReportStatus::Regenerate => gettext("Will be regenerated"),
+            ReportStatus::Skipped(reason) => format!("{}: {reason}", gettext("Skipped")),
+            ReportStatus::Failed(reason) => format!("{}: {reason}", gettext("Failed")),
+        }
+    }
+
+    pub fn source_text(&self) -> String {
+        match self.properties_source {
+            Some(PropertiesSource::XMP) => gettext("Metadata"),
+            Some(PropertiesSource::LegacyXMP) => gettext("Legacy metadata"),
+            Some(PropertiesSource::Filename) => gettext("Filename"),
+            None => gettext("Unknown"),
+        }
+    }
+
+    pub fn policy_text(&self) -> String {
+        match &self.properties {
+            Some(properties) => properties.regeneration_policy.name(),
+            None => gettext("Unknown"),
+        }
+    }
+
+    pub fn bottom_image_text(&self) -> String {
+        match self
+            .properties
+            .as_ref()
+            .map(|properties| &properties.bottom_image_type)
+        {
+            Some(BottomImageType::FolderSystem) => gettext("Folder following the accent color"),
+            Some(BottomImageType::Folder(color)) => format!("{} ({color})", gettext("Folder")),
+            Some(BottomImageType::FolderCustom(primary, secondary)) => {
+                format!("{} (#{primary}, #{secondary})", gettext("Custom folder"))
+            }
+            Some(BottomImageType::Custom(path)) => {
+                format!("{} ({})", gettext("Custom image"), path.display())
+            }
+            Some(BottomImageType::CustomCached(hash)) => {
+                format!(
+                    "{} ({})",
+                    gettext("Custom image"),
+                    &hash[..hash.len().min(12)]
+                )
+            }
+            Some(BottomImageType::Unknown) | None => gettext("Unknown"),
+        }
+    }
+}
+
+fn yes_no(value: bool) -> String {
+    if value { gettext("Yes") } else { gettext("No") }
+}
@@ -0,0 +1,495 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use gio::prelude::*;
+use gtk::gdk::RGBA;
+use gtk::{gio, glib};
+use image::*;
+use log::*;
+
+use crate::GenResult;
+use crate::IconicWindow;
+use crate::objects::errors::IntoResult;
+use crate::objects::file::File;
+use crate::objects::icon_library::ResolvedIcons;
+use crate::objects::output_format::OutputFormat;
+use crate::objects::properties::{
+    BottomImageType, CustomRGB, FileProperties, PropertiesSource, RegenerationPolicy,
+};
+use crate::objects::regeneration_backup::RegenerationBackup;
+use crate::objects::regeneration_report::ReportEntry;
+use crate::windows::generation::CompositeParams;
+
+// The default monochrome color of every built-in folder
+pub fn default_accent_colors() -> HashMap<String, RGBA> {
+    HashMap::from([
+        ("Blue".to_string(), RGBA::from_rgb(67, 141, 230)),
+        ("Teal".to_string(), RGBA::from_rgb(18, 158, 176)),
+        ("Green".to_string(), RGBA::from_rgb(61, 158, 79)),
+        ("Yellow".to_string(), RGBA::from_rgb(203, 147, 26)),
+        ("Orange".to_string(), RGBA::from_rgb(241, 119, 56)),
+        ("Red".to_string(), RGBA::from_rgb(232, 64, 83)),
+        ("Pink".to_string(), RGBA::from_rgb(230, 67, 146)),
+        ("Purple".to_string(), RGBA::from_rgb(149, 74, 181)),
+        ("Slate".to_string(), RGBA::from_rgb(99, 118, 146)),
+    ])
+}
+
+// Get the accent color without a window, in the same format as IconicWindow::get_accent_color
+// Libadwaita also reads the settings portal, so it is used if there is a display to connect to
+// Otherwise the accent color is read from the GNOME interface settings directly
+pub fn system_accent_color() -> String {
+    if gtk::init().is_ok() && adw::init().is_ok() {
+        return format!("{:?}", adw::StyleManager::default().accent_color());
+    }
+    const INTERFACE_SCHEMA: &str = "org.gnome.desktop.interface";
+    let accent_color = gio::SettingsSchemaSource::default()
+        .and_then(|source| source.lookup(INTERFACE_SCHEMA, true))
+        .filter(|schema| schema.has_key("accent-color"))
+        .map(|_| {
+            gio::Settings::new(INTERFACE_SCHEMA)
+                .string("accent-color")
+                .to_string()
+        })
+        .unwrap_or_else(|| {
+            warn!("No accent color found, falling back to blue");
+            "blue".to_string()
+        });
+    // GSettings stores the color in lowercase, libadwaita names it with a capital letter
+    let mut chars = accent_color.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => "Blue".to_string(),
+    }
+}
+
+// The most icons that are regenerated at the same time
+const MAX_WORKERS: usize = 8;
+
+// The folder an icon is regenerated on
+#[derive(Debug, PartialEq)]
+enum BottomImage {
+    Folder(String),
+    CustomFolder(String, String),
+    Cached(String),
+}
+
+impl BottomImage {
+    fn cache_key(&self) -> String {
+        match self {
+            BottomImage::Folder(color) => color.clone(),
+            BottomImage::CustomFolder(foreground, background) => {
+                format!("custom_{foreground}_{background}")
+            }
+            BottomImage::Cached(hash) => format!("cached_{hash}"),
+        }
+    }
+}
+
+// Every distinct bottom image is only decoded once per regeneration
+// If two workers need the same image at the same time, the second waits for the first to decode it
+#[derive(Debug, Clone, Default)]
+struct BottomImageCache {
+    images: Arc<Mutex<HashMap<String, Arc<OnceLock<Result<DynamicImage, String>>>>>>,
+}
+
+impl BottomImageCache {
+    // This blocks, so only call it from a worker thread
+    fn get_or_decode(
+        &self,
+        key: &str,
+        decode: impl FnOnce() -> GenResult<DynamicImage>,
+    ) -> GenResult<DynamicImage> {
+        let image = self
+            .images
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        image
+            .get_or_init(|| {
+                debug!("Decoding bottom image {key}");
+                decode().map_err(|error| error.to_string())
+            })
+            .clone()
+            .map_err(Into::into)
+    }
+}
+
+// Recreates stored icons from their properties. It does not use any widgets,
+// so it is shared by the window and the headless --regenerate mode
+#[derive(Debug, Clone)]
+pub struct Regenerator {
+    pub accent_color: String,
+    pub strict: bool,
+    pub ignore_custom: bool,
+    exact: bool,
+    default_colors: HashMap<String, RGBA>,
+    backup: RegenerationBackup,
+    bottom_images: BottomImageCache,
+}
+
+impl Regenerator {
+    pub fn new(settings: &gio::Settings, accent_color: String) -> GenResult<Self> {
+        Ok(Self {
+            accent_color,
+            strict: settings.boolean("strict-regeneration"),
+            ignore_custom: settings.boolean("ignore-custom"),
+            exact: false,
+            default_colors: default_accent_colors(),
+            backup: RegenerationBackup::new()?,
+            bottom_images: BottomImageCache::default(),
+        })
+    }
+
+    // Regenerates icons on exactly the folder that is stored in them, regardless of their policy and the settings
+    // Used when the folder of icons is changed on purpose, so the new color is not replaced by the accent color
+    pub fn exact(accent_color: String) -> GenResult<Self> {
+        Ok(Self {
+            accent_color,
+            strict: true,
+            ignore_custom: true,
+            exact: true,
+            default_colors: default_accent_colors(),
+            backup: RegenerationBackup::new()?,
+            bottom_images: BottomImageCache::default(),
+        })
+    }
+
+    // Regenerates the icons on a bounded number of workers
+    // should_stop is checked before an icon is started, icons that are already started are finished,
+    // so a stopped regeneration never leaves an icon half done
+    // on_result is called on the main thread after every icon, in the order they finish
+    pub async fn regenerate_all(
+        &self,
+        entries: Vec<ReportEntry>,
+        should_stop: impl Fn() -> bool + Clone + 'static,
+        mut on_result: impl FnMut(ReportEntry, GenResult<()>),
+    ) {
+        let worker_count = std::thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(1)
+            .clamp(1, MAX_WORKERS)
+            .min(entries.len());
+        // Installed top images are looked up once for all icons, instead of walking the icon directories for every icon
+        let properties: Vec<FileProperties> = entries
+            .iter()
+            .filter_map(|entry| entry.properties.clone())
+            .collect();
+        let installed_icons = Arc::new(
+            gio::spawn_blocking(move || IconicWindow::resolve_top_image_icons(&properties))
+                .await
+                .unwrap(),
+        );
+        let (job_sender, job_receiver) = async_channel::unbounded();
+        for entry in entries {
+            let _ = job_sender.try_send(entry);
+        }
+        job_sender.close();
+        let (result_sender, result_receiver) = async_channel::unbounded();
+        for _ in 0..worker_count {
+            let regenerator = self.clone();
+            let jobs = job_receiver.clone();
+            let results = result_sender.clone();
+            let should_stop = should_stop.clone();
+            let installed_icons = installed_icons.clone();
+            glib::spawn_future_local(async move {
+                while let Ok(entry) = jobs.recv().await {
+                    if should_stop() {
+                        break;
+                    }
+                    let result = match (entry.properties.clone(), entry.properties_source) {
+                        (Some(properties), Some(source)) => {
+                            regenerator
+                                .regenerate_icon_with(
+                                    properties,
+                                    entry.path.clone(),
+                                    source,
+                                    installed_icons.clone(),
+                                )
+                                .await
+                        }
+                        _ => Ok(()),
+                    };
+                    if results.send((entry, result)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // The loop below ends when every worker is done and has dropped its sender
+        drop(result_sender);
+        while let Ok((entry, result)) = result_receiver.recv().await {
+            on_result(entry, result);
+        }
+    }
+
+    // This function regenerates a single compatible icon
+    pub async fn regenerate_icon(
+        &self,
+        properties: FileProperties,
+        file_path: PathBuf,
+        property_source: PropertiesSource,
+    ) -> GenResult<()> {
+        let lookup_properties = properties.clone();
+        let installed_icons = gio::spawn_blocking(move || {
+            IconicWindow::resolve_top_image_icons([&lookup_properties])
+        })
+        .await
+        .unwrap();
+        self.regenerate_icon_with(
+            properties,
+            file_path,
+            property_source,
+            Arc::new(installed_icons),
+        )
+        .await
+    }
+
+    async fn regenerate_icon_with(
+        &self,
+        mut properties: FileProperties,
+        file_path: PathBuf,
+        property_source: PropertiesSource,
+        installed_icons: Arc<ResolvedIcons>,
+    ) -> GenResult<()> {
+        let Some((bottom_image, default_color)) = self.bottom_image_for(&properties)? else {
+            return Ok(());
+        };
+        let bottom_image_file = self.bottom_image(bottom_image).await?;
+        info!("Generating image");
+
+        // Icons without a policy of their own have their folder decided by the preferences
+        // If strict mode is disabled and the image is not regenerated during strict mode. And the image is regenerated, is has te be regenerated to mark it as no longer default
+        if properties.regeneration_policy == RegenerationPolicy::Automatic {
+            properties.default =
+                self.strict || properties.bottom_image_type.is_strict_compatible() != Some(false);
+        }
+
+        // Create the path where the top image of this file is located
+        // The top image has the same name as the hash of that image
+        let top_image_properties = properties.clone();
+        let top_image_file = gio::spawn_blocking(move || {
+            let top_image_path =
+                IconicWindow::find_top_image(&top_image_properties, &installed_icons)
+                    .ok_or_else(|| "Top image could not be found".to_string())?;
+            File::from_path(top_image_path, 1024, 0).map_err(|err| err.to_string())
+        })
+        .await
+        .unwrap()?
+        .dynamic_image;
+        // Create the top image and place it on the bottom image on a worker thread,
+        // so other icons can be regenerated at the same time
+        // Using the generic composite_image function. The icon can faithfully be recreated
+        let regenerator = self.clone();
+        let image_properties = properties.clone();
+        let generated_image = gio::spawn_blocking(move || -> GenResult<DynamicImage> {
+            let top_image =
+                regenerator.color_top_image(&image_properties, top_image_file, default_color);
+            Ok(IconicWindow::composite_image(
+                bottom_image_file,
+                top_image,
+                imageops::FilterType::Gaussian,
+                CompositeParams::from_properties(&image_properties, default_color),
+            ))
+        })
+        .await
+        .unwrap()?;
+        info!("Saving image");
+        // The new icon is written next to the old one and renamed over it, after the old one is backed up
+        // So an icon is never half written, even if the regeneration is stopped
+        // Icons are written back in the format they were saved in
+        let backup = self.backup.clone();
+        gio::spawn_blocking(move || {
+            let format = OutputFormat::from_path(&file_path);
+            let metadata = match property_source {
+                PropertiesSource::XMP | PropertiesSource::LegacyXMP => Some(&properties),
+                PropertiesSource::Filename => None,
+            };
+            backup.replace_icon(&file_path, |temporary_path| {
+                format.save(&generated_image, temporary_path, metadata)
+            })
+        })
+        .await
+        .unwrap()?;
+        info!("Saving Succesful");
+        Ok(())
+    }
+
+    // Cleans up the backup of this regeneration, call this when it is done or stopped
+    pub fn finish(&self) -> GenResult<()> {
+        self.backup.finish()
+    }
+
+    // The folder an icon is placed on, and the top color that belongs to it
+    // None if the icon is not regenerated
+    fn bottom_image_for(
+        &self,
+        properties: &FileProperties,
+    ) -> GenResult<Option<(BottomImage, RGBA)>> {
+        let policy = if self.exact {
+            RegenerationPolicy::Pinned
+        } else {
+            properties.resolved_regeneration_policy(self.strict, self.ignore_custom)
+        };
+
+        // Icons that follow the accent color are placed on the folder of the current accent color,
+        // pinned icons are placed on the folder that is stored in them
+        // The default top color is the color that belongs to that folder
+        Ok(Some(match (policy, properties.bottom_image_type.clone()) {
+            (RegenerationPolicy::Never | RegenerationPolicy::Automatic, _) => return Ok(None),
+            (RegenerationPolicy::FollowAccent, _) | (_, BottomImageType::FolderSystem) => (
+                BottomImage::Folder(self.accent_color.clone()),
+                self.accent_rgba(&self.accent_color)?,
+            ),
+            (RegenerationPolicy::Pinned, BottomImageType::Folder(color)) => {
+                let default_color = self.accent_rgba(&color)?;
+                (BottomImage::Folder(color), default_color)
+            }
+            (RegenerationPolicy::Pinned, BottomImageType::FolderCustom(foreground, background)) => {
+                (
+                    BottomImage::CustomFolder(foreground, background.clone()),
+                    RGBA::from_hex(background),
+                )
+            }
+            (RegenerationPolicy::Pinned, BottomImageType::CustomCached(hash)) => (
+                BottomImage::Cached(hash),
+                self.accent_rgba(&self.accent_color)?,
+            ),
+            _ => return Ok(None),
+        }))
+    }
+
+    async fn bottom_image(&self, bottom_image: BottomImage) -> GenResult<DynamicImage> {
+        let cache = self.bottom_images.clone();
+        gio::spawn_blocking(move || {
+            cache.get_or_decode(&bottom_image.cache_key(), || match &bottom_image {
+                // Icons that are compatible for regeneration are only allowed to use default folder images.
+                // So when regenerating icons, you need the folder which is the same color as the current accent color
+                BottomImage::Folder(color) => {
+                    let bottom_image_path =
+                        PathBuf::from(format!("/app/share/Iconic/folders/folder_{color}.svg"));
+                    Ok(File::from_path(bottom_image_path, 1024, 0)?.dynamic_image)
+                }
+                // Rendered from memory, so icons with different colors can be regenerated at the same time
+                BottomImage::CustomFolder(foreground, background) => File::load_svg_data(
+                    IconicWindow::custom_folder_svg(foreground, background).as_bytes(),
+                    1024,
+                ),
+                BottomImage::Cached(hash) => {
+                    Ok(
+                        File::from_path(IconicWindow::bottom_image_cache_path(hash), 1024, 0)?
+                            .dynamic_image,
+                    )
+                }
+            })
+        })
+        .await
+        .unwrap()
+    }
+
+    // Create the top image based on the properties of the to-be regenerated icon
+    // Icons that use the default top color get the color that belongs to their new folder
+    // Color adjustments are made while compositing, so icons that match the accent are matched to the new folder
+    fn color_top_image(
+        &self,
+        properties: &FileProperties,
+        top_image: DynamicImage,
+        default_color: RGBA,
+    ) -> DynamicImage {
+        let color = match properties.monochrome_default {
+            false => RGBA::new(
+                properties.monochrome_color.unwrap_or_default().0 as f32 / 255.0,
+                properties.monochrome_color.unwrap_or_default().1 as f32 / 255.0,
+                properties.monochrome_color.unwrap_or_default().2 as f32 / 255.0,
+                1.0,
+            ),
+            true => default_color,
+        };
+        match properties.monochrome_toggle {
+            true => IconicWindow::monochrome_image(
+                top_image,
+                properties.monochrome_threshold_val,
+                color,
+                properties.monochrome_invert,
+            ),
+            false => top_image,
+        }
+    }
+
+    fn accent_rgba(&self, accent_color: &str) -> GenResult<RGBA> {
+        Ok(self.default_colors.get(accent_color).into_result()?.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regenerator(accent_color: &str) -> Regenerator {
+        Regenerator {
+            accent_color: accent_color.to_string(),
+            strict: false,
+            ignore_custom: false,
+            exact: false,
+            default_colors: default_accent_colors(),
+            backup: RegenerationBackup {
+                id: String::new(),
+                path: PathBuf::new(),
+            },
+            bottom_images: BottomImageCache::default(),
+        }
+    }
+
+    fn folder_of(regenerator: &Regenerator, properties: &FileProperties) -> Option<BottomImage> {
+        regenerator
+            .bottom_image_for(properties)
+            .unwrap()
+            .map(|(bottom_image, _)| bottom_image)
+    }
+
+    #[test]
+    fn retargeted_icon_keeps_its_folder() {
+        let regenerator = regenerator("Green");
+        for policy in [
+            RegenerationPolicy::FollowAccent,
+            RegenerationPolicy::Automatic,
+        ] {
+            let mut properties = FileProperties {
+                bottom_image_type: BottomImageType::Folder("Blue".into()),
+                regeneration_policy: policy,
+                default: true,
+                ..Default::default()
+            };
+            assert_eq!(
+                folder_of(&regenerator, &properties),
+                Some(BottomImage::Folder("Green".into()))
+            );
+            properties.retarget(BottomImageType::Folder("Red".into()));
+            let (bottom_image, default_color) =
+                regenerator.bottom_image_for(&properties).unwrap().unwrap();
+            assert_eq!(bottom_image, BottomImage::Folder("Red".into()));
+            assert_eq!(default_color, default_accent_colors()["Red"]);
+        }
+    }
+
+    #[test]
+    fn retargeted_to_accent_follows_accent() {
+        let mut properties = FileProperties {
+            bottom_image_type: BottomImageType::Folder("Blue".into()),
+            regeneration_policy: RegenerationPolicy::Pinned,
+            ..Default::default()
+        };
+        properties.retarget(BottomImageType::FolderSystem);
+        assert_eq!(
+            properties.regeneration_policy,
+            RegenerationPolicy::FollowAccent
+        );
+        assert_eq!(
+            folder_of(&regenerator("Teal"), &properties),
+            Some(BottomImage::Folder("Teal".into()))
+        );
+    }
+}
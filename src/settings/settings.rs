@@ -15,8 +15,8 @@ use gtk::prelude::*;
 use gtk::subclass::prelude::*;
 use gtk::*;
 use log::*;
+use std::path;
 use std::path::PathBuf;
-use std::{env, fs, path};
 
 use crate::IconicWindow;
 
@@ -77,6 +77,12 @@ mod imp {
         pub preferences_page: TemplateChild<adw::PreferencesPage>,
         #[template_child]
         pub enable_advanced: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub show_guides: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub snap_to_guides: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub guide_grid_size: TemplateChild<adw::SpinRow>,
         pub settings: gio::Settings,
     }
 
@@ -112,6 +118,9 @@ mod imp {
                 select_default_bottom: TemplateChild::default(),
                 preferences_page: TemplateChild::default(),
                 enable_advanced: TemplateChild::default(),
+                show_guides: TemplateChild::default(),
+                snap_to_guides: TemplateChild::default(),
+                guide_grid_size: TemplateChild::default(),
             }
         }
 
@@ -257,6 +266,15 @@ impl PreferencesDialog {
         imp.settings
             .bind("ignore-custom", &*imp.ignore_custom, "active")
             .build();
+        imp.settings
+            .bind("show-guides", &*imp.show_guides, "active")
+            .build();
+        imp.settings
+            .bind("snap-to-guides", &*imp.snap_to_guides, "active")
+            .build();
+        imp.settings
+            .bind("guide-grid-size", &*imp.guide_grid_size, "value")
+            .build();
         imp.settings
             .bind(
                 "manual-bottom-image-selection",
@@ -399,16 +417,8 @@ impl PreferencesDialog {
     }
 
     fn copy_folder_image_to_cache(&self, original_path: path::PathBuf) -> GenResult<()> {
-        let cache_dir = self.get_cache_path();
-        let file_name = format!(
-            "folder.{}",
-            original_path.extension().unwrap().to_str().unwrap()
-        );
-        self.imp()
-            .settings
-            .set("folder-cache-name", file_name.clone())?;
-        let cache_path = cache_dir.join(file_name);
-        fs::copy(original_path, cache_path)?;
+        let hash = IconicWindow::store_bottom_image_in_cache(&original_path)?;
+        self.imp().settings.set("folder-cache-name", hash)?;
         Ok(())
     }
 
@@ -439,23 +449,6 @@ impl PreferencesDialog {
         }
     }
 
-    pub fn get_cache_path(&self) -> PathBuf {
-        let cache_path = match env::var("XDG_CACHE_HOME") {
-            Ok(value) => PathBuf::from(value),
-            Err(_) => {
-                let config_dir = PathBuf::from(env::var("HOME").unwrap())
-                    .join(".cache")
-                    .join(format!("nl.emphisia.icon"));
-                if !config_dir.exists() {
-                    fs::create_dir(&config_dir).unwrap();
-                }
-                config_dir
-            }
-        };
-        debug!("cache path {:?}", cache_path);
-        cache_path
-    }
-
     #[template_callback]
     pub async fn open_image_cache(&self, _button: adw::ButtonRow) {
         let file = gio::File::for_path(format!(
@@ -22,7 +22,12 @@ use crate::config::{APP_ICON, APP_ID, PROFILE};
 use crate::glib::clone;
 use crate::objects::errors::show_error_popup;
 use crate::objects::file::File;
-use crate::objects::properties::{BottomImageType, CustomRGB};
+use crate::objects::properties::{
+    BlendMode, BottomImageType, CustomRGB, FileProperties, RegenerationPolicy, TopImageCrop,
+};
+use crate::objects::regeneration_backup::{RegenerationLock, remove_temporary_files};
+use crate::objects::regenerator::default_accent_colors;
+use crate::windows::crop_dialog::CropDialog;
 use adw::{prelude::*, subclass::prelude::*};
 use gettextrs::gettext;
 use gio::prelude::SettingsExt;
@@ -67,6 +72,8 @@ pub mod imp {
         #[template_child]
         pub header_bar: TemplateChild<adw::HeaderBar>,
         #[template_child]
+        pub window_title: TemplateChild<adw::WindowTitle>,
+        #[template_child]
         pub toast_overlay: TemplateChild<adw::ToastOverlay>,
         #[template_child]
         pub open_top_icon: TemplateChild<gtk::Button>,
@@ -87,12 +94,42 @@ pub mod imp {
         #[template_child]
         pub image_loading_spinner: TemplateChild<adw::Spinner>,
         #[template_child]
+        pub image_loading_box: TemplateChild<gtk::Box>,
+        #[template_child]
+        pub image_loading_progress: TemplateChild<gtk::ProgressBar>,
+        #[template_child]
         pub monochrome_action_row: TemplateChild<adw::ExpanderRow>,
         #[template_child]
         pub monochrome_switch: TemplateChild<gtk::Switch>,
         #[template_child]
         pub threshold_scale: TemplateChild<gtk::Scale>,
         #[template_child]
+        pub transform_row: TemplateChild<adw::ExpanderRow>,
+        #[template_child]
+        pub rotation_scale: TemplateChild<gtk::Scale>,
+        #[template_child]
+        pub flip_horizontal: TemplateChild<gtk::ToggleButton>,
+        #[template_child]
+        pub flip_vertical: TemplateChild<gtk::ToggleButton>,
+        #[template_child]
+        pub opacity_scale: TemplateChild<gtk::Scale>,
+        #[template_child]
+        pub blend_mode_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub crop_row: TemplateChild<adw::ActionRow>,
+        #[template_child]
+        pub brightness_scale: TemplateChild<gtk::Scale>,
+        #[template_child]
+        pub contrast_scale: TemplateChild<gtk::Scale>,
+        #[template_child]
+        pub saturation_scale: TemplateChild<gtk::Scale>,
+        #[template_child]
+        pub hue_scale: TemplateChild<gtk::Scale>,
+        #[template_child]
+        pub match_accent_switch: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub regeneration_policy_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
         pub monochrome_color: TemplateChild<gtk::ColorDialogButton>,
         #[template_child]
         pub reset_color: TemplateChild<gtk::Button>,
@@ -131,6 +168,14 @@ pub mod imp {
         pub drag_active: Rc<Cell<bool>>,
         pub file_properties: RefCell<FileProperties>,
         pub drag_cancelled: Cell<bool>,
+        pub move_start: Cell<Option<(f64, f64)>>,
+        pub zoom_start: Cell<f64>,
+        // The folder whose custom icon is being edited, saving updates the icon of this folder
+        pub edited_folder: RefCell<Option<PathBuf>>,
+        // Stops the top or bottom image that is currently loading
+        pub loading_cancellable: RefCell<Option<gio::Cancellable>>,
+        // The part of the top image that is used, reset when another top image is loaded
+        pub top_image_crop: Cell<TopImageCrop>,
     }
 
     impl Default for IconicWindow {
@@ -138,11 +183,25 @@ pub mod imp {
             Self {
                 toolbar: TemplateChild::default(),
                 header_bar: TemplateChild::default(),
+                window_title: TemplateChild::default(),
                 toast_overlay: TemplateChild::default(),
                 open_top_icon: TemplateChild::default(),
                 image_view: TemplateChild::default(),
                 save_button: TemplateChild::default(),
                 threshold_scale: TemplateChild::default(),
+                transform_row: TemplateChild::default(),
+                rotation_scale: TemplateChild::default(),
+                flip_horizontal: TemplateChild::default(),
+                flip_vertical: TemplateChild::default(),
+                opacity_scale: TemplateChild::default(),
+                blend_mode_row: TemplateChild::default(),
+                crop_row: TemplateChild::default(),
+                brightness_scale: TemplateChild::default(),
+                contrast_scale: TemplateChild::default(),
+                saturation_scale: TemplateChild::default(),
+                hue_scale: TemplateChild::default(),
+                match_accent_switch: TemplateChild::default(),
+                regeneration_policy_row: TemplateChild::default(),
                 reset_color: TemplateChild::default(),
                 monochrome_action_row: TemplateChild::default(),
                 monochrome_color: TemplateChild::default(),
@@ -158,6 +217,8 @@ pub mod imp {
                 main_status_page: TemplateChild::default(),
                 monochrome_invert: TemplateChild::default(),
                 image_loading_spinner: TemplateChild::default(),
+                image_loading_box: TemplateChild::default(),
+                image_loading_progress: TemplateChild::default(),
                 popover_menu: TemplateChild::default(),
                 gesture_click: TemplateChild::default(),
                 regeneration_revealer: TemplateChild::default(),
@@ -178,6 +239,11 @@ pub mod imp {
                 drag_active: Rc::new(Cell::new(false)),
                 file_properties: RefCell::new(FileProperties::default()),
                 drag_cancelled: Cell::new(false),
+                move_start: Cell::new(None),
+                zoom_start: Cell::new(0.0),
+                edited_folder: RefCell::new(None),
+                loading_cancellable: RefCell::new(None),
+                top_image_crop: Cell::new(TopImageCrop::default()),
             }
         }
     }
@@ -201,6 +267,35 @@ pub mod imp {
                 ));
                 debug!("References: {}", Arc::strong_count(&win.imp().app_busy));
             });
+            klass.install_action("app.browse_icons", None, move |win, _, _| {
+                glib::spawn_future_local(clone!(
+                    #[weak]
+                    win,
+                    async move {
+                        win.load_installed_icon().await;
+                    }
+                ));
+            });
+            klass.install_action("app.browse_applications", None, move |win, _, _| {
+                glib::spawn_future_local(clone!(
+                    #[weak]
+                    win,
+                    async move {
+                        win.load_application_icon().await;
+                    }
+                ));
+            });
+            klass.install_action("app.open_folder_icon", None, move |win, _, _| {
+                glib::spawn_future_local(clone!(
+                    #[weak]
+                    win,
+                    async move {
+                        if let Err(error) = win.pick_folder_icon().await {
+                            show_error_popup(&win, "", true, Some(error));
+                        }
+                    }
+                ));
+            });
             klass.install_action("app.open_file_location", None, move |win, _, _| {
                 glib::spawn_future_local(clone!(
                     #[weak]
@@ -274,6 +369,70 @@ pub mod imp {
                     }
                 ));
             });
+            klass.install_action("app.revert_regeneration", None, move |win, _, _| {
+                glib::spawn_future_local(clone!(
+                    #[weak]
+                    win,
+                    async move {
+                        if let Err(error) = win.revert_last_regeneration().await {
+                            show_error_popup(&win, "", true, Some(error));
+                        }
+                    }
+                ));
+            });
+            klass.install_action("app.retarget_icons", None, move |win, _, _| {
+                glib::spawn_future_local(clone!(
+                    #[weak]
+                    win,
+                    async move {
+                        if let Err(error) = win.retarget_icons().await {
+                            show_error_popup(&win, "", true, Some(error));
+                        }
+                    }
+                ));
+            });
+            klass.install_action("app.set_regeneration_policy", None, move |win, _, _| {
+                glib::spawn_future_local(clone!(
+                    #[weak]
+                    win,
+                    async move {
+                        if let Err(error) = win.set_regeneration_policies().await {
+                            show_error_popup(&win, "", true, Some(error));
+                        }
+                    }
+                ));
+            });
+            klass.install_action("app.scan_icon_usage", None, move |win, _, _| {
+                glib::spawn_future_local(clone!(
+                    #[weak]
+                    win,
+                    async move {
+                        if let Err(error) = win.scan_icon_usage().await {
+                            show_error_popup(&win, "", true, Some(error));
+                        }
+                    }
+                ));
+            });
+            klass.install_action("app.regeneration_report", None, move |win, _, _| {
+                glib::spawn_future_local(clone!(
+                    #[weak]
+                    win,
+                    async move {
+                        if let Err(error) = win.show_regeneration_report().await {
+                            show_error_popup(&win, "", true, Some(error));
+                        }
+                    }
+                ));
+            });
+            klass.install_action("app.migrate_legacy_icons", None, move |win, _, _| {
+                glib::spawn_future_local(clone!(
+                    #[weak]
+                    win,
+                    async move {
+                        win.migrate_legacy_icons().await;
+                    }
+                ));
+            });
             klass.install_action("app.save_button", None, move |win, _, _| {
                 glib::spawn_future_local(clone!(
                     #[weak]
@@ -295,6 +454,21 @@ pub mod imp {
             klass.install_action("app.reset_color", None, move |win, _, _| {
                 win.reset_colors();
             });
+            klass.install_action("app.rotate_left", None, move |win, _, _| {
+                win.rotate_top_image(-90.0);
+            });
+            klass.install_action("app.rotate_right", None, move |win, _, _| {
+                win.rotate_top_image(90.0);
+            });
+            klass.install_action("app.crop_top_image", None, move |win, _, _| {
+                glib::spawn_future_local(clone!(
+                    #[weak]
+                    win,
+                    async move {
+                        win.open_crop_dialog().await;
+                    }
+                ));
+            });
         }
 
         fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
@@ -333,19 +507,8 @@ pub mod imp {
                     debug!("{value}");
                     if value != "" {
                         let mut properties = imp.file_properties.try_borrow().unwrap().clone();
-                        properties.bottom_image_type = match value.as_str() {
-                            "Custom" => {
-                                let custom_primary_color: String =
-                                    imp.settings.string("primary-folder-color").into();
-                                let custom_secondary_color: String =
-                                    imp.settings.string("secondary-folder-color").into();
-                                BottomImageType::FolderCustom(
-                                    custom_primary_color,
-                                    custom_secondary_color,
-                                )
-                            }
-                            _ => BottomImageType::Folder(value),
-                        };
+                        properties.bottom_image_type =
+                            BottomImageType::from_folder_color(&value, &imp.settings);
                         imp.file_properties.replace(properties);
                         win.load_bottom_image();
                     }
@@ -353,6 +516,10 @@ pub mod imp {
                 }
             ));
             self.obj().add_action(&temp_bottom_folder);
+            self.obj()
+                .add_action(&self.settings.create_action("show-guides"));
+            self.obj()
+                .add_action(&self.settings.create_action("snap-to-guides"));
         }
 
         fn dispose(&self) {
@@ -403,17 +570,7 @@ impl IconicWindow {
         if PROFILE == "Devel" {
             imp.main_status_page.set_icon_name(Some(APP_ICON));
         }
-        imp.default_color.replace(HashMap::from([
-            ("Blue".to_string(), RGBA::from_rgb(67, 141, 230)),
-            ("Teal".to_string(), RGBA::from_rgb(18, 158, 176)),
-            ("Green".to_string(), RGBA::from_rgb(61, 158, 79)),
-            ("Yellow".to_string(), RGBA::from_rgb(203, 147, 26)),
-            ("Orange".to_string(), RGBA::from_rgb(241, 119, 56)),
-            ("Red".to_string(), RGBA::from_rgb(232, 64, 83)),
-            ("Pink".to_string(), RGBA::from_rgb(230, 67, 146)),
-            ("Purple".to_string(), RGBA::from_rgb(149, 74, 181)),
-            ("Slate".to_string(), RGBA::from_rgb(99, 118, 146)),
-        ]));
+        imp.default_color.replace(default_accent_colors());
         win.setup_defaults();
         win
     }
@@ -429,16 +586,88 @@ impl IconicWindow {
         imp.y_scale.set_value(9.447);
         imp.size.set_value(24.0);
         imp.x_scale.set_value(0.0);
+        imp.rotation_scale.set_value(0.0);
+        imp.flip_horizontal.set_active(false);
+        imp.flip_vertical.set_active(false);
+        imp.opacity_scale.set_value(100.0);
+        self.set_top_image_crop(TopImageCrop::default());
+        imp.brightness_scale.set_value(0.0);
+        imp.contrast_scale.set_value(0.0);
+        imp.saturation_scale.set_value(0.0);
+        imp.hue_scale.set_value(0.0);
+        imp.match_accent_switch.set_active(false);
+        imp.blend_mode_row.set_selected(BlendMode::Normal.index());
+        imp.regeneration_policy_row
+            .set_selected(RegenerationPolicy::Automatic.index());
         let monochrome_switch_state = imp.settings.boolean("monochrome-mode-active");
         imp.monochrome_switch.set_active(monochrome_switch_state);
     }
 
+    // Shows the properties of an existing icon in the editor
+    pub fn apply_properties(&self, properties: &FileProperties) {
+        let imp = self.imp();
+        imp.x_scale.set_value(properties.x_val);
+        imp.y_scale.set_value(properties.y_val);
+        imp.size.set_value(properties.zoom_val);
+        imp.rotation_scale.set_value(properties.transform.rotation);
+        imp.flip_horizontal
+            .set_active(properties.transform.flip_horizontal);
+        imp.flip_vertical
+            .set_active(properties.transform.flip_vertical);
+        imp.opacity_scale.set_value(properties.transform.opacity);
+        self.set_top_image_crop(properties.crop);
+        imp.brightness_scale
+            .set_value(properties.color_adjustment.brightness);
+        imp.contrast_scale
+            .set_value(properties.color_adjustment.contrast);
+        imp.saturation_scale
+            .set_value(properties.color_adjustment.saturation);
+        imp.hue_scale
+            .set_value(properties.color_adjustment.hue_shift);
+        imp.match_accent_switch
+            .set_active(properties.color_adjustment.match_accent);
+        imp.blend_mode_row
+            .set_selected(properties.blend_mode.index());
+        imp.regeneration_policy_row
+            .set_selected(properties.regeneration_policy.index());
+        imp.monochrome_switch
+            .set_active(properties.monochrome_toggle);
+        imp.monochrome_invert
+            .set_active(properties.monochrome_invert);
+        imp.threshold_scale
+            .set_value(properties.monochrome_threshold_val as f64);
+        // Icons that use the default monochrome color get the default color of the current bottom image
+        if let Some((red, green, blue)) = properties.monochrome_color
+            && !properties.monochrome_default
+        {
+            imp.monochrome_color.set_rgba(&RGBA::new(
+                red as f32 / 255.0,
+                green as f32 / 255.0,
+                blue as f32 / 255.0,
+                1.0,
+            ));
+        }
+        imp.file_properties.borrow_mut().bottom_image_type = properties.bottom_image_type.clone();
+        self.load_bottom_image();
+    }
+
     pub fn setup_defaults(&self) {
         let imp = self.imp();
         imp.save_button.set_sensitive(false);
         self.default_sliders(true);
         self.adwaita_colors_popup();
         imp.reset_color.set_visible(false);
+        // A regeneration in another process is still writing its temporary files
+        if let Ok(Some(_regeneration_lock)) = RegenerationLock::try_acquire() {
+            remove_temporary_files(&Self::get_data_path());
+        }
+        glib::spawn_future_local(glib::clone!(
+            #[weak(rename_to = win)]
+            self,
+            async move {
+                win.clean_up_drag_outputs().await;
+            }
+        ));
         self.check_if_regeneration_needed();
         let _ = imp
             .settings
@@ -486,6 +715,26 @@ impl IconicWindow {
             .connect_changed(Some("selected-accent-color"), update_folder.clone());
         imp.settings
             .connect_changed(Some("manual-bottom-image-selection"), update_folder.clone());
+
+        let update_guides = glib::clone!(
+            #[weak(rename_to = win)]
+            self,
+            move |_: &gio::Settings, _: &str| {
+                glib::spawn_future_local(glib::clone!(
+                    #[weak]
+                    win,
+                    async move {
+                        if win.imp().stack.visible_child_name() == Some("stack_main_page".into()) {
+                            win.render_to_screen().await;
+                        }
+                    }
+                ));
+            }
+        );
+        imp.settings
+            .connect_changed(Some("show-guides"), update_guides.clone());
+        imp.settings
+            .connect_changed(Some("guide-grid-size"), update_guides.clone());
     }
 
     pub fn setup_update(&self) {
@@ -502,6 +751,36 @@ impl IconicWindow {
                 ));
             }
         ));
+        self.imp().match_accent_switch.connect_active_notify(clone!(
+            #[weak(rename_to = win)]
+            self,
+            move |_| {
+                glib::spawn_future_local(clone!(
+                    #[weak]
+                    win,
+                    async move {
+                        if win.imp().stack.visible_child_name() == Some("stack_main_page".into()) {
+                            win.render_to_screen().await;
+                        }
+                    }
+                ));
+            }
+        ));
+        self.imp().blend_mode_row.connect_selected_notify(clone!(
+            #[weak(rename_to = win)]
+            self,
+            move |_| {
+                glib::spawn_future_local(clone!(
+                    #[weak]
+                    win,
+                    async move {
+                        if win.imp().stack.visible_child_name() == Some("stack_main_page".into()) {
+                            win.render_to_screen().await;
+                        }
+                    }
+                ));
+            }
+        ));
         self.imp().monochrome_color.connect_rgba_notify(clone!(
             #[weak(rename_to = win)]
             self,
@@ -571,24 +850,33 @@ impl IconicWindow {
         accent_color
     }
 
-    // Find the custom bottom image in the cache
-    pub fn check_chache_icon(&self, file_name: &str) -> PathBuf {
+    // Find the custom bottom image in the cache, and return its hash
+    // An empty hash means no custom bottom image was stored yet
+    pub fn check_chache_icon(&self, hash: &str) -> Option<String> {
         let imp = self.imp();
-        let icon_path = PathBuf::from(&imp.settings.string("folder-svg-path"));
-        let cache_path = Self::get_cache_path();
-        let folder_icon_cache_path = cache_path.join(file_name);
-        if folder_icon_cache_path.exists() {
-            info!("File found in cache at: {:?}", folder_icon_cache_path);
-            return folder_icon_cache_path;
-        } else if icon_path.exists() {
-            info!(
-                "File not found in cache, copying to: {:?}",
-                folder_icon_cache_path
-            );
-            return self
-                .copy_folder_image_to_cache(&icon_path, &cache_path)
-                .unwrap()
-                .0;
+        let mut paths = vec![];
+        if !hash.is_empty() {
+            let folder_icon_cache_path = Self::bottom_image_cache_path(hash);
+            if folder_icon_cache_path.exists() {
+                info!("File found in cache at: {:?}", folder_icon_cache_path);
+                return Some(hash.to_string());
+            }
+            // Older versions stored a single custom bottom image directly in the cache
+            paths.push(Self::get_cache_path().join(hash));
+        }
+        paths.push(PathBuf::from(&imp.settings.string("folder-svg-path")));
+        for path in paths {
+            if !path.exists() {
+                continue;
+            }
+            info!("File not found in cache, copying from: {:?}", path);
+            match Self::store_bottom_image_in_cache(&path) {
+                Ok(hash) => {
+                    let _ = imp.settings.set_string("folder-cache-name", &hash);
+                    return Some(hash);
+                }
+                Err(error) => warn!("Copying bottom image to the cache failed: {error}"),
+            }
         }
         info!("File not found AT ALL");
         let _ = show_error_popup(
@@ -600,7 +888,7 @@ impl IconicWindow {
         .unwrap();
 
         imp.settings.default_value("manual-bottom-image-selection");
-        self.load_built_in_bottom_icon("None")
+        None
     }
 
     pub fn get_cache_path() -> PathBuf {
@@ -620,7 +908,7 @@ impl IconicWindow {
         cache_path
     }
 
-    pub fn get_data_path(&self) -> PathBuf {
+    pub fn get_data_path() -> PathBuf {
         let data_path = match env::var("XDG_DATA_HOME") {
             Ok(value) => PathBuf::from(value),
             Err(_) => {
@@ -671,7 +959,12 @@ impl IconicWindow {
 
     pub fn load_empty_top_image(&self, top_image: &mut std::sync::MutexGuard<'_, Option<File>>) {
         let empty_image = DynamicImage::new(1, 1, ColorType::Rgba8);
-        (*top_image).replace(File::from_image(empty_image, 1, None, ""));
+        match File::from_image(empty_image, 1, None, "") {
+            Ok(file) => {
+                (*top_image).replace(file);
+            }
+            Err(error) => warn!("Could not create an empty top image: {error}"),
+        }
         self.slider_control_sensitivity(false);
     }
 
@@ -685,6 +978,51 @@ impl IconicWindow {
         imp.monochrome_invert.set_sensitive(sensitive);
         imp.monochrome_switch.set_sensitive(sensitive);
         imp.monochrome_action_row.set_sensitive(sensitive);
+        imp.transform_row.set_sensitive(sensitive);
+    }
+
+    // Rotate in steps, the result is kept within the range of the rotation slider
+    pub fn rotate_top_image(&self, degrees: f64) {
+        let rotation_scale = &self.imp().rotation_scale;
+        let rotation = (rotation_scale.value() + degrees + 180.0).rem_euclid(360.0) - 180.0;
+        rotation_scale.set_value(rotation);
+    }
+
+    // The crop is not a slider, so its row shows what is cropped
+    pub fn set_top_image_crop(&self, crop: TopImageCrop) {
+        let imp = self.imp();
+        imp.top_image_crop.set(crop);
+        let whole_image = TopImageCrop {
+            pad_to_square: crop.pad_to_square,
+            ..Default::default()
+        } == crop;
+        let subtitle = match (whole_image, crop.pad_to_square) {
+            (true, false) => gettext("Whole image"),
+            (true, true) => gettext("Padded to square"),
+            (false, false) => gettext("Cropped"),
+            (false, true) => gettext("Cropped and padded to square"),
+        };
+        imp.crop_row.set_subtitle(&subtitle);
+    }
+
+    pub async fn open_crop_dialog(&self) {
+        let imp = self.imp();
+        let Some(image) = imp
+            .top_image_file
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|file| file.dynamic_image.clone())
+        else {
+            return;
+        };
+        if let Some(crop) = CropDialog::new(&image, imp.top_image_crop.get())
+            .choose(self)
+            .await
+        {
+            self.set_top_image_crop(crop);
+            self.check_icon_update();
+        }
     }
 
     pub async fn open_file_chooser(&self) -> Option<gio::File> {
@@ -0,0 +1,46 @@
+use std::cell::RefCell;
+
+use adw::subclass::prelude::*;
+use gio::glib::subclass::InitializingObject;
+use gtk::glib;
+
+use crate::objects::icon_library::InstalledApplication;
+
+#[derive(Default, gtk::CompositeTemplate)]
+#[template(resource = "/nl/emphisia/icon/windows/application_browser/window.ui")]
+pub struct ApplicationBrowser {
+    #[template_child]
+    pub search_entry: TemplateChild<gtk::SearchEntry>,
+    #[template_child]
+    pub stack: TemplateChild<gtk::Stack>,
+    #[template_child]
+    pub application_list: TemplateChild<gtk::ListBox>,
+    pub applications: RefCell<Vec<InstalledApplication>>,
+    pub sender: RefCell<Option<async_channel::Sender<InstalledApplication>>>,
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for ApplicationBrowser {
+    const NAME: &'static str = "ApplicationBrowser";
+    type Type = super::ApplicationBrowser;
+    type ParentType = adw::Dialog;
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.bind_template();
+    }
+
+    fn instance_init(obj: &InitializingObject<Self>) {
+        obj.init_template();
+    }
+}
+
+impl ObjectImpl for ApplicationBrowser {
+    fn constructed(&self) {
+        self.parent_constructed();
+        self.obj().setup_list();
+    }
+}
+
+impl WidgetImpl for ApplicationBrowser {}
+
+impl AdwDialogImpl for ApplicationBrowser {}
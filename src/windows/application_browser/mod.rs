@@ -0,0 +1,113 @@
+mod imp;
+
+use adw::prelude::*;
+use adw::subclass::prelude::ObjectSubclassIsExt;
+use gtk::glib;
+use gtk::glib::clone;
+use log::*;
+
+use crate::objects::icon_library::{self, InstalledApplication};
+
+glib::wrapper! {
+    pub struct ApplicationBrowser(ObjectSubclass<imp::ApplicationBrowser>)
+        @extends gtk::Widget, adw::Dialog,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::ShortcutManager;
+}
+
+impl Default for ApplicationBrowser {
+    fn default() -> Self {
+        glib::Object::new()
+    }
+}
+
+impl ApplicationBrowser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Shows the browser and waits until an application is picked.
+    // Returns None if the dialog is closed without picking anything
+    pub async fn choose(&self, parent: &impl IsA<gtk::Widget>) -> Option<InstalledApplication> {
+        let (sender, receiver) = async_channel::bounded(1);
+        self.imp().sender.replace(Some(sender));
+        // Dropping the sender makes the receiver return an error, which ends the wait
+        self.connect_closed(|browser| {
+            browser.imp().sender.take();
+        });
+        self.present(Some(parent));
+        receiver.recv().await.ok()
+    }
+
+    fn setup_list(&self) {
+        let imp = self.imp();
+        // Listing the applications is fast, so it is done while the dialog is created
+        let applications = icon_library::find_installed_applications();
+        for application in applications.iter() {
+            let row = adw::ActionRow::builder()
+                .title(glib::markup_escape_text(&application.name))
+                .subtitle(glib::markup_escape_text(&application.id))
+                .activatable(true)
+                .build();
+            row.add_prefix(
+                &gtk::Image::builder()
+                    .gicon(&application.icon)
+                    .pixel_size(32)
+                    .build(),
+            );
+            imp.application_list.append(&row);
+        }
+        imp.applications.replace(applications);
+
+        imp.application_list.set_filter_func(clone!(
+            #[weak(rename_to = browser)]
+            self,
+            #[upgrade_or]
+            false,
+            move |row| browser.application_visible(row.index())
+        ));
+        imp.search_entry.connect_search_changed(clone!(
+            #[weak(rename_to = browser)]
+            self,
+            move |_| browser.update_filter()
+        ));
+        imp.application_list.connect_row_activated(clone!(
+            #[weak(rename_to = browser)]
+            self,
+            move |_, row| browser.application_activated(row.index())
+        ));
+        self.update_filter();
+    }
+
+    fn application_visible(&self, index: i32) -> bool {
+        let imp = self.imp();
+        let search = imp.search_entry.text().to_lowercase();
+        imp.applications
+            .borrow()
+            .get(index as usize)
+            .is_some_and(|application| {
+                application.name.to_lowercase().contains(&search)
+                    || application.id.to_lowercase().contains(&search)
+            })
+    }
+
+    fn update_filter(&self) {
+        let imp = self.imp();
+        imp.application_list.invalidate_filter();
+        let any_visible = (0..imp.applications.borrow().len() as i32)
+            .any(|index| self.application_visible(index));
+        imp.stack
+            .set_visible_child_name(if any_visible { "applications" } else { "empty" });
+    }
+
+    fn application_activated(&self, index: i32) {
+        let imp = self.imp();
+        let Some(application) = imp.applications.borrow().get(index as usize).cloned() else {
+            return;
+        };
+        debug!("Picked application {:?}", application.id);
+        if let Some(sender) = imp.sender.borrow().as_ref() {
+            let _ = sender.try_send(application);
+        }
+        self.close();
+    }
+}
@@ -1,6 +1,7 @@
 use crate::IconicWindow;
 use crate::glib;
 use crate::objects::properties::BottomImageType;
+use crate::windows::guides::GuideAxis;
 use adw::subclass::prelude::*;
 use gio::glib::object::Cast;
 use gio::glib::object::ObjectExt;
@@ -11,6 +12,8 @@ use gtk::GestureClick;
 use gtk::GestureLongPress;
 use gtk::gdk;
 use gtk::prelude::PopoverExt;
+use gtk::prelude::{RangeExt, WidgetExt};
+use image::GenericImageView;
 use log::debug;
 
 #[gtk::template_callbacks]
@@ -65,4 +68,151 @@ impl IconicWindow {
             imp.popover_menu.popup();
         }
     }
+
+    #[template_callback]
+    fn move_begin(&self, x: f64, y: f64) {
+        let imp = self.imp();
+        imp.image_view.grab_focus();
+        if self.point_on_top_image(x, y) {
+            imp.move_start
+                .set(Some((imp.x_scale.value(), imp.y_scale.value())));
+        }
+    }
+
+    #[template_callback]
+    fn move_update(&self, offset_x: f64, offset_y: f64) {
+        let imp = self.imp();
+        let (Some((start_x, start_y)), Some(bounds)) =
+            (imp.move_start.get(), imp.image_view.image_bounds())
+        else {
+            return;
+        };
+        let x = start_x + offset_x / bounds.width() as f64 * 100.0;
+        let y = start_y + offset_y / bounds.height() as f64 * 100.0;
+        imp.x_scale.set_value(self.snap_to_guides(x, GuideAxis::X));
+        imp.y_scale.set_value(self.snap_to_guides(y, GuideAxis::Y));
+    }
+
+    // Only snap when the slider is dragged, stepping with the keyboard would get stuck on a guide
+    #[template_callback]
+    fn snap_slider(&self, scroll: gtk::ScrollType, value: f64, scale: gtk::Scale) -> bool {
+        let imp = self.imp();
+        if scroll != gtk::ScrollType::Jump {
+            return false;
+        }
+        let axis = if scale == *imp.x_scale {
+            GuideAxis::X
+        } else {
+            GuideAxis::Y
+        };
+        scale.set_value(self.snap_to_guides(value, axis));
+        true
+    }
+
+    #[template_callback]
+    fn move_end(&self) {
+        self.imp().move_start.set(None);
+    }
+
+    #[template_callback]
+    fn zoom_scroll(&self, _dx: f64, dy: f64) -> bool {
+        let imp = self.imp();
+        if !imp.size.is_sensitive() || imp.top_image_file.lock().unwrap().is_none() {
+            return false;
+        }
+        imp.size.set_value(imp.size.value() + dy);
+        true
+    }
+
+    #[template_callback]
+    fn zoom_begin(&self) {
+        let imp = self.imp();
+        imp.zoom_start.set(imp.size.value());
+    }
+
+    #[template_callback]
+    fn zoom_update(&self, scale: f64) {
+        let imp = self.imp();
+        if !imp.size.is_sensitive() || scale <= 0.0 {
+            return;
+        }
+        // The size slider divides the top image, so pinching out has to lower it
+        let start_factor = (imp.zoom_start.get() + 10.0) / 10.0;
+        imp.size.set_value((start_factor / scale) * 10.0 - 10.0);
+    }
+
+    #[template_callback]
+    fn nudge_key(&self, key: gdk::Key, _code: u32, state: gdk::ModifierType) -> bool {
+        let imp = self.imp();
+        if imp.top_image_file.lock().unwrap().is_none() {
+            return false;
+        }
+        let step = if state.contains(gdk::ModifierType::SHIFT_MASK) {
+            5.0
+        } else {
+            1.0
+        };
+        match key {
+            gdk::Key::Left => imp.x_scale.set_value(imp.x_scale.value() - step),
+            gdk::Key::Right => imp.x_scale.set_value(imp.x_scale.value() + step),
+            gdk::Key::Up => imp.y_scale.set_value(imp.y_scale.value() - step),
+            gdk::Key::Down => imp.y_scale.set_value(imp.y_scale.value() + step),
+            _ => return false,
+        }
+        true
+    }
+
+    #[template_callback]
+    fn preview_motion(&self, x: f64, y: f64) {
+        let cursor = if self.point_on_top_image(x, y) {
+            Some("move")
+        } else {
+            None
+        };
+        self.imp().image_view.set_cursor_from_name(cursor);
+    }
+
+    #[template_callback]
+    fn cancel_image_loading(&self) {
+        if let Some(cancellable) = self.imp().loading_cancellable.borrow().as_ref() {
+            cancellable.cancel();
+        }
+    }
+
+    // Checks if a point on the preview, relative to the preview widget, lies on the top image
+    pub fn point_on_top_image(&self, x: f64, y: f64) -> bool {
+        let imp = self.imp();
+        let Some(bounds) = imp.image_view.image_bounds() else {
+            return false;
+        };
+        let Some(base_dimensions) = imp
+            .bottom_image_file
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|file| file.thumbnail.dimensions())
+        else {
+            return false;
+        };
+        let Some(top_dimensions) = imp.top_image_file.lock().unwrap().as_ref().map(|file| {
+            imp.top_image_crop
+                .get()
+                .cropped_size(file.thumbnail.dimensions())
+        }) else {
+            return false;
+        };
+        let (width, height) = IconicWindow::top_image_extent(
+            base_dimensions,
+            top_dimensions,
+            imp.size.value(),
+            imp.rotation_scale.value(),
+        );
+        // The sliders place the center of the top image as a percentage of the bottom image
+        let relative_x = (x - bounds.x() as f64) / bounds.width() as f64;
+        let relative_y = (y - bounds.y() as f64) / bounds.height() as f64;
+        let center_x = (imp.x_scale.value() + 50.0) / 100.0;
+        let center_y = (imp.y_scale.value() + 50.0) / 100.0;
+        (relative_x - center_x).abs() <= width / 2.0
+            && (relative_y - center_y).abs() <= height / 2.0
+    }
 }
@@ -0,0 +1,57 @@
+use std::cell::{Cell, RefCell};
+
+use adw::subclass::prelude::*;
+use gio::glib::subclass::InitializingObject;
+use gtk::glib;
+
+use crate::objects::properties::TopImageCrop;
+
+// Where a drag started, in fractions of the image, and the crop at that moment
+// Moving is true if the drag started inside the crop, which moves it instead of drawing a new one
+#[derive(Debug, Clone, Copy)]
+pub struct CropDrag {
+    pub start: (f64, f64),
+    pub crop: TopImageCrop,
+    pub moving: bool,
+}
+
+#[derive(Default, gtk::CompositeTemplate)]
+#[template(resource = "/nl/emphisia/icon/windows/crop_dialog/window.ui")]
+pub struct CropDialog {
+    #[template_child]
+    pub crop_area: TemplateChild<gtk::DrawingArea>,
+    #[template_child]
+    pub square_button: TemplateChild<gtk::ToggleButton>,
+    #[template_child]
+    pub size_label: TemplateChild<gtk::Label>,
+    #[template_child]
+    pub pad_row: TemplateChild<adw::SwitchRow>,
+    pub preview: RefCell<Option<cairo::ImageSurface>>,
+    // The size of the top image itself, the preview is smaller
+    pub image_size: Cell<(u32, u32)>,
+    pub crop: Cell<TopImageCrop>,
+    pub drag: Cell<Option<CropDrag>>,
+    pub sender: RefCell<Option<async_channel::Sender<TopImageCrop>>>,
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for CropDialog {
+    const NAME: &'static str = "CropDialog";
+    type Type = super::CropDialog;
+    type ParentType = adw::Dialog;
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.bind_template();
+        klass.bind_template_instance_callbacks();
+    }
+
+    fn instance_init(obj: &InitializingObject<Self>) {
+        obj.init_template();
+    }
+}
+
+impl ObjectImpl for CropDialog {}
+
+impl WidgetImpl for CropDialog {}
+
+impl AdwDialogImpl for CropDialog {}
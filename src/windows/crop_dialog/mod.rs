@@ -0,0 +1,309 @@
+mod imp;
+
+use adw::prelude::*;
+use adw::subclass::prelude::ObjectSubclassIsExt;
+use gettextrs::gettext;
+use gtk::glib;
+use gtk::glib::clone;
+use image::{DynamicImage, GenericImageView, imageops};
+use log::*;
+
+use crate::GenResult;
+use crate::objects::properties::TopImageCrop;
+use imp::CropDrag;
+
+// The largest size the image is shown at while cropping
+const PREVIEW_SIZE: u32 = 512;
+
+glib::wrapper! {
+    pub struct CropDialog(ObjectSubclass<imp::CropDialog>)
+        @extends gtk::Widget, adw::Dialog,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::ShortcutManager;
+}
+
+#[gtk::template_callbacks]
+impl CropDialog {
+    // Starts with the crop that is used now, so it can be adjusted
+    pub fn new(image: &DynamicImage, crop: TopImageCrop) -> Self {
+        let dialog: Self = glib::Object::new();
+        let imp = dialog.imp();
+        let preview = match image.width() > PREVIEW_SIZE || image.height() > PREVIEW_SIZE {
+            true => image.resize(PREVIEW_SIZE, PREVIEW_SIZE, imageops::FilterType::Triangle),
+            false => image.clone(),
+        };
+        match image_to_surface(&preview) {
+            Ok(surface) => {
+                imp.preview.replace(Some(surface));
+            }
+            Err(error) => error!("Could not create a preview of the top image: {error}"),
+        }
+        imp.image_size.set(image.dimensions());
+        imp.crop.set(crop);
+        imp.pad_row.set_active(crop.pad_to_square);
+        imp.crop_area.set_draw_func(clone!(
+            #[weak(rename_to = dialog)]
+            dialog,
+            move |_, context, width, height| {
+                dialog.draw(context, width, height);
+            }
+        ));
+        dialog.update_size_label();
+        dialog
+    }
+
+    // Shows the dialog and waits until the crop is applied
+    // Returns None if the dialog is closed
+    pub async fn choose(&self, parent: &impl IsA<gtk::Widget>) -> Option<TopImageCrop> {
+        let (sender, receiver) = async_channel::bounded(1);
+        self.imp().sender.replace(Some(sender));
+        // Dropping the sender makes the receiver return an error, which ends the wait
+        self.connect_closed(|dialog| {
+            dialog.imp().sender.take();
+        });
+        self.present(Some(parent));
+        receiver.recv().await.ok()
+    }
+
+    // Where the preview is drawn in the drawing area, as x, y and scale
+    fn preview_area(&self) -> Option<(f64, f64, f64)> {
+        let imp = self.imp();
+        let preview = imp.preview.borrow();
+        let preview = preview.as_ref()?;
+        let (width, height) = (preview.width() as f64, preview.height() as f64);
+        let (area_width, area_height) =
+            (imp.crop_area.width() as f64, imp.crop_area.height() as f64);
+        if width <= 0.0 || height <= 0.0 || area_width <= 0.0 || area_height <= 0.0 {
+            return None;
+        }
+        let scale = (area_width / width).min(area_height / height);
+        Some((
+            (area_width - width * scale) / 2.0,
+            (area_height - height * scale) / 2.0,
+            scale,
+        ))
+    }
+
+    // A point in the drawing area as fractions of the image, clamped to the image
+    fn to_image(&self, x: f64, y: f64) -> Option<(f64, f64)> {
+        let (offset_x, offset_y, scale) = self.preview_area()?;
+        let preview = self.imp().preview.borrow();
+        let preview = preview.as_ref()?;
+        Some((
+            ((x - offset_x) / (preview.width() as f64 * scale)).clamp(0.0, 1.0),
+            ((y - offset_y) / (preview.height() as f64 * scale)).clamp(0.0, 1.0),
+        ))
+    }
+
+    fn draw(&self, context: &cairo::Context, _width: i32, _height: i32) {
+        let imp = self.imp();
+        let Some((offset_x, offset_y, scale)) = self.preview_area() else {
+            return;
+        };
+        let preview = imp.preview.borrow();
+        let Some(preview) = preview.as_ref() else {
+            return;
+        };
+        let (width, height) = (
+            preview.width() as f64 * scale,
+            preview.height() as f64 * scale,
+        );
+        context.save().ok();
+        context.translate(offset_x, offset_y);
+        context.scale(scale, scale);
+        if context.set_source_surface(preview, 0.0, 0.0).is_ok() {
+            context.paint().ok();
+        }
+        context.restore().ok();
+
+        // The parts that are cropped away are darkened
+        let crop = imp.crop.get();
+        let (x, y, crop_width, crop_height) = (
+            offset_x + crop.x * width,
+            offset_y + crop.y * height,
+            crop.width * width,
+            crop.height * height,
+        );
+        context.set_fill_rule(cairo::FillRule::EvenOdd);
+        context.rectangle(offset_x, offset_y, width, height);
+        context.rectangle(x, y, crop_width, crop_height);
+        context.set_source_rgba(0.0, 0.0, 0.0, 0.6);
+        context.fill().ok();
+        context.rectangle(x, y, crop_width, crop_height);
+        context.set_source_rgb(1.0, 1.0, 1.0);
+        context.set_line_width(2.0);
+        context.stroke().ok();
+    }
+
+    fn set_crop(&self, crop: TopImageCrop) {
+        let imp = self.imp();
+        imp.crop.set(crop);
+        imp.crop_area.queue_draw();
+        self.update_size_label();
+    }
+
+    fn update_size_label(&self) {
+        let imp = self.imp();
+        let (_, _, width, height) = imp.crop.get().pixel_rect(imp.image_size.get());
+        imp.size_label
+            .set_label(&format!("{width} × {height} {}", gettext("pixels")));
+    }
+
+    // Shrinks the crop to a square around its center, in pixels of the image
+    fn squared(&self, crop: TopImageCrop) -> TopImageCrop {
+        let (image_width, image_height) = self.imp().image_size.get();
+        let (image_width, image_height) = (image_width.max(1) as f64, image_height.max(1) as f64);
+        let side = (crop.width * image_width).min(crop.height * image_height);
+        let (width, height) = (side / image_width, side / image_height);
+        TopImageCrop {
+            x: crop.x + (crop.width - width) / 2.0,
+            y: crop.y + (crop.height - height) / 2.0,
+            width,
+            height,
+            ..crop
+        }
+    }
+
+    #[template_callback]
+    fn drag_begin(&self, x: f64, y: f64) {
+        let imp = self.imp();
+        let Some(start) = self.to_image(x, y) else {
+            return;
+        };
+        let crop = imp.crop.get();
+        let inside = start.0 >= crop.x
+            && start.0 <= crop.x + crop.width
+            && start.1 >= crop.y
+            && start.1 <= crop.y + crop.height;
+        // A crop of the whole image can not be moved, so dragging always draws a new one
+        let moving = inside && (crop.width < 1.0 || crop.height < 1.0);
+        imp.drag.set(Some(CropDrag {
+            start,
+            crop,
+            moving,
+        }));
+    }
+
+    #[template_callback]
+    fn drag_update(&self, offset_x: f64, offset_y: f64) {
+        let imp = self.imp();
+        let (Some(drag), Some((_, _, scale)), Some((width, height))) = (
+            imp.drag.get(),
+            self.preview_area(),
+            imp.preview
+                .borrow()
+                .as_ref()
+                .map(|preview| (preview.width() as f64, preview.height() as f64)),
+        ) else {
+            return;
+        };
+        let (delta_x, delta_y) = (offset_x / (width * scale), offset_y / (height * scale));
+        let (start_x, start_y) = drag.start;
+        let crop = if drag.moving {
+            TopImageCrop {
+                x: (drag.crop.x + delta_x).min(1.0 - drag.crop.width).max(0.0),
+                y: (drag.crop.y + delta_y).min(1.0 - drag.crop.height).max(0.0),
+                ..drag.crop
+            }
+        } else {
+            let end_x = (start_x + delta_x).clamp(0.0, 1.0);
+            let end_y = (start_y + delta_y).clamp(0.0, 1.0);
+            let mut crop = TopImageCrop {
+                x: start_x.min(end_x),
+                y: start_y.min(end_y),
+                width: (end_x - start_x).abs(),
+                height: (end_y - start_y).abs(),
+                ..drag.crop
+            };
+            if imp.square_button.is_active() {
+                // The square is kept in the corner where the drag started
+                let square = self.squared(crop);
+                crop.x = if end_x < start_x {
+                    start_x - square.width
+                } else {
+                    start_x
+                };
+                crop.y = if end_y < start_y {
+                    start_y - square.height
+                } else {
+                    start_y
+                };
+                crop.width = square.width;
+                crop.height = square.height;
+            }
+            crop
+        };
+        // A click without dragging would otherwise crop everything away
+        let (image_width, image_height) = imp.image_size.get();
+        if crop.width * image_width as f64 >= 1.0 && crop.height * image_height as f64 >= 1.0 {
+            self.set_crop(crop);
+        }
+    }
+
+    #[template_callback]
+    fn drag_end(&self) {
+        self.imp().drag.set(None);
+    }
+
+    #[template_callback]
+    fn aspect_changed(&self) {
+        if self.imp().square_button.is_active() {
+            self.set_crop(self.squared(self.imp().crop.get()));
+        }
+    }
+
+    #[template_callback]
+    fn pad_changed(&self) {
+        let imp = self.imp();
+        self.set_crop(TopImageCrop {
+            pad_to_square: imp.pad_row.is_active(),
+            ..imp.crop.get()
+        });
+    }
+
+    #[template_callback]
+    fn reset_clicked(&self) {
+        let imp = self.imp();
+        let crop = TopImageCrop {
+            pad_to_square: imp.pad_row.is_active(),
+            ..Default::default()
+        };
+        match imp.square_button.is_active() {
+            true => self.set_crop(self.squared(crop)),
+            false => self.set_crop(crop),
+        }
+    }
+
+    #[template_callback]
+    fn apply_clicked(&self) {
+        let imp = self.imp();
+        if let Some(sender) = imp.sender.take() {
+            let _ = sender.try_send(imp.crop.get());
+        }
+        self.close();
+    }
+}
+
+// Cairo stores premultiplied pixels as native endian 32 bit ARGB values
+fn image_to_surface(image: &DynamicImage) -> GenResult<cairo::ImageSurface> {
+    let image = image.to_rgba8();
+    let mut surface = cairo::ImageSurface::create(
+        cairo::Format::ARgb32,
+        image.width() as i32,
+        image.height() as i32,
+    )?;
+    let stride = surface.stride() as usize;
+    {
+        let mut data = surface.data()?;
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let [red, green, blue, alpha] = pixel.0;
+            let premultiply = |channel: u8| channel as u32 * alpha as u32 / 255;
+            let value = (alpha as u32) << 24
+                | premultiply(red) << 16
+                | premultiply(green) << 8
+                | premultiply(blue);
+            let offset = y as usize * stride + x as usize * 4;
+            data[offset..offset + 4].copy_from_slice(&value.to_ne_bytes());
+        }
+    }
+    Ok(surface)
+}
@@ -1,11 +1,17 @@
+use crate::GenResult;
+use crate::objects::drag_index::{DragIndex, DragState};
 use crate::objects::errors::ErrorPopup;
+use crate::objects::file::File;
+use crate::objects::icon_usage::IconUsage;
+use crate::objects::properties::FileProperties;
+use crate::objects::regeneration_backup::temporary_path;
 use crate::{IconicWindow, window};
 use crate::{glib::clone, objects::errors::show_error_popup};
 use adw::subclass::prelude::*;
 use gio::{glib, prelude::*};
 use gtk::gdk;
 use gtk::prelude::WidgetExt;
-use image::imageops;
+use image::{DynamicImage, imageops};
 use log::*;
 use random_str::random::{CharBuilder, RandomStringBuilder};
 
@@ -83,7 +89,7 @@ pub fn setup_drag_drop_logic(win: &window::imp::IconicWindow) {
         obj,
         #[upgrade_or]
         None,
-        move |drag, _, _| win.drag_connect_prepare(drag)
+        move |drag, x, y| win.drag_connect_prepare(drag, x, y)
     ));
 
     drag_source.connect_drag_end(clone!(
@@ -103,8 +109,17 @@ pub fn setup_drag_drop_logic(win: &window::imp::IconicWindow) {
 }
 
 impl IconicWindow {
-    pub fn drag_connect_prepare(&self, source: &gtk::DragSource) -> Option<gdk::ContentProvider> {
+    pub fn drag_connect_prepare(
+        &self,
+        source: &gtk::DragSource,
+        x: f64,
+        y: f64,
+    ) -> Option<gdk::ContentProvider> {
         let imp = self.imp();
+        // Dragging the top image moves it instead of exporting the icon
+        if self.point_on_top_image(x, y) {
+            return None;
+        }
         imp.drag_active.set(true);
         let generated_image = imp.generated_image.borrow().clone().unwrap();
         let file_hash = imp.top_image_file.lock().unwrap().clone().unwrap().hash;
@@ -114,11 +129,31 @@ impl IconicWindow {
             imageops::FilterType::Nearest,
         ));
         source.set_icon(Some(&icon), 0 as i32, 0 as i32);
-        let gio_file = self.create_drag_file(false);
-        let gio_file_temp = self.create_drag_file(true);
-        imp.last_drag_n_drop_generated_name
-            .replace(Some(gio_file.clone()));
+        // Dragging the same design again reuses the file of the earlier drag
+        let design_hash = match self.drag_design_hash(&generated_image, file_hash.clone()) {
+            Ok(design_hash) => design_hash,
+            Err(error) => {
+                error!("Could not create the properties of the dragged icon: {error}");
+                return None;
+            }
+        };
+        let data_path = Self::get_data_path();
+        if let Some(output) = DragIndex::load().find(&design_hash, &data_path) {
+            debug!("Reusing drag output {}", output.file_name);
+            let gio_file = gio::File::for_path(data_path.join(&output.file_name));
+            self.start_drag_output(&gio_file, &design_hash);
+            return Some(gdk::ContentProvider::for_value(&glib::Value::from(
+                &gio_file,
+            )));
+        }
+        let gio_file = self.create_drag_file();
+        self.start_drag_output(&gio_file, &design_hash);
         let gio_file_clone = gio_file.clone();
+        // The full size icon is written to a temporary file first, which is left over if Iconic stops during the drag
+        let Ok(temp_path) = temporary_path(&gio_file.path()?, "drag") else {
+            return None;
+        };
+        let gio_file_temp = gio::File::for_path(&temp_path);
         // I think it is quite cursed what i am doing. But it works amazingly for speeding up drag responsiveness
         glib::spawn_future_local(clone!(
             #[weak (rename_to = win)]
@@ -129,7 +164,7 @@ impl IconicWindow {
                         gio_file_clone.clone(),
                         win.imp().monochrome_switch.is_active(),
                         None,
-                        Some(file_hash),
+                        Some(file_hash.clone()),
                         true,
                     )
                     .await;
@@ -158,8 +193,44 @@ impl IconicWindow {
         )))
     }
 
-    pub fn create_drag_file(&self, temp: bool) -> gio::File {
-        let data_path = self.get_data_path();
+    fn start_drag_output(&self, gio_file: &gio::File, design_hash: &str) {
+        self.imp()
+            .last_drag_n_drop_generated_name
+            .replace(Some(gio_file.clone()));
+        if let Some(file_name) = gio_file.basename() {
+            DragIndex::update(|index| index.start(&file_name.to_string_lossy(), design_hash)).log();
+        }
+    }
+
+    // Marks the output of the last drag as dropped or abandoned
+    fn end_drag_output(&self, state: DragState) {
+        let Some(file_name) = self
+            .imp()
+            .last_drag_n_drop_generated_name
+            .borrow()
+            .as_ref()
+            .and_then(|gio_file| gio_file.basename())
+        else {
+            return;
+        };
+        DragIndex::update(|index| index.end(&file_name.to_string_lossy(), state)).log();
+    }
+
+    // Two drags of the same design create the same image with the same properties
+    // The properties are hashed as they are stored in the icon
+    fn drag_design_hash(
+        &self,
+        generated_image: &DynamicImage,
+        top_image_hash: String,
+    ) -> GenResult<String> {
+        let properties = FileProperties::new(self, Some(top_image_hash), self.get_default_color());
+        let mut data = generated_image.as_bytes().to_vec();
+        data.extend(properties.to_xmp()?.to_string().into_bytes());
+        File::create_hash(&data)
+    }
+
+    pub fn create_drag_file(&self) -> gio::File {
+        let data_path = Self::get_data_path();
         debug!("data path: {:?}", data_path);
         let mut file_path = data_path.clone();
         let random_string = RandomStringBuilder::new()
@@ -169,11 +240,7 @@ impl IconicWindow {
             .with_uppercase()
             .build()
             .unwrap();
-        let generated_file_name = format!(
-            "folder-{}{}.png",
-            random_string,
-            if temp { "-temp" } else { "" }
-        );
+        let generated_file_name = format!("folder-{}.png", random_string);
         debug!("generated_file_name: {}", generated_file_name);
         file_path.push(generated_file_name.clone());
         debug!("generated file path: {:?}", file_path);
@@ -182,26 +249,14 @@ impl IconicWindow {
     }
 
     pub fn drag_connect_cancel(&self, reason: gdk::DragCancelReason) -> bool {
-        // let imp = self.imp();
-        // let gio_file = imp
-        //     .last_drag_n_drop_generated_name
-        //     .borrow()
-        //     .clone()
-        //     .unwrap();
         self.image_save_sensitive(true);
+        // Some drop targets still use the file after reporting a cancel, so it is not removed right away
+        // The deferred cleanup removes it later, if no folder uses it
         warn!(
-            "Drag operation cancelled, removing file. Reason: {:?}\n(Currently disabled, treating as a succesful drag)",
+            "Drag operation cancelled, marking the file as abandoned. Reason: {:?}",
             reason
         );
-        // match gio_file.delete(None::<&Cancellable>) {
-        //     Ok(_) => {
-        //         debug!("Deletion succesfull!");
-        //     }
-        //     Err(e) => {
-        //         warn!("Could not delete drag file, error: {:?}", e);
-        //     }
-        // };
-        // imp.drag_cancelled.set(true);
+        self.end_drag_output(DragState::Abandoned);
         false
     }
 
@@ -209,6 +264,7 @@ impl IconicWindow {
         let imp = self.imp();
         imp.drag_active.set(false);
         debug!("drag end");
+        self.end_drag_output(DragState::Dropped);
         if !imp.drag_cancelled.get() {
             // Drag event was not cancelled. I couldn't find a signal that fires only on a succseful drag
             debug!("succesful drag");
@@ -224,4 +280,36 @@ impl IconicWindow {
         }
         imp.drag_cancelled.set(false);
     }
+
+    // Removes the files of drags that were abandoned a while ago, and that are not used by any folder
+    // Finding the folders that use them needs a scan of the home folder, so it only happens if there is something to remove
+    pub async fn clean_up_drag_outputs(&self) {
+        let expired = DragIndex::load().expired();
+        if expired.is_empty() {
+            return;
+        }
+        // Without access to the folders of the user, it is unknown if the files are in use, so they are kept
+        let home_visible = [
+            glib::UserDirectory::Desktop,
+            glib::UserDirectory::Documents,
+            glib::UserDirectory::Downloads,
+            glib::UserDirectory::Pictures,
+        ]
+        .into_iter()
+        .any(|directory| glib::user_special_dir(directory).is_some_and(|path| path.exists()));
+        if !home_visible {
+            info!("The home folder can not be scanned, keeping abandoned drag outputs");
+            return;
+        }
+        let data_path = Self::get_data_path();
+        let scan_data_path = data_path.clone();
+        let usage =
+            gio::spawn_blocking(move || IconUsage::scan(&[glib::home_dir()], &scan_data_path))
+                .await
+                .unwrap();
+        match DragIndex::update(|index| index.clean_up(&expired, &usage.used_icons(), &data_path)) {
+            Ok(removed) => info!("Removed {} abandoned drag outputs", removed.len()),
+            Err(error) => warn!("Cleaning up the drag outputs failed: {error}"),
+        }
+    }
 }
@@ -1,6 +1,18 @@
+use crate::objects::animation::AnimationFormat;
 use crate::objects::errors::{ErrorPopup, IntoResult, show_error_popup};
-use crate::objects::file::File;
-use crate::objects::properties::{BottomImageType, FileProperties};
+use crate::objects::file::{File, LoadProgress};
+use crate::objects::icon_container::{ContainerKind, IconContainer};
+use crate::objects::icon_library;
+use crate::objects::icon_usage::{IconUsage, set_custom_icon};
+use crate::objects::output_format::OutputFormat;
+use crate::objects::properties::{BottomImageType, FileProperties, TopImageCrop, TopImageSource};
+use crate::objects::regeneration_backup::replace_file_atomically;
+use crate::windows::application_browser::ApplicationBrowser;
+use crate::windows::frame_dialog::FrameDialog;
+use crate::windows::generation::CompositeParams;
+use crate::windows::icon_browser::IconBrowser;
+use crate::windows::icon_size_dialog::IconSizeDialog;
+use crate::windows::icon_usage_dialog::IconUsageDialog;
 use adw::{prelude::*, subclass::prelude::*};
 use gettextrs::gettext;
 use gio::*;
@@ -8,9 +20,9 @@ use gtk::{gdk, glib};
 use image::*;
 use log::*;
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use xmp_toolkit::{OpenFileOptions, XmpFile, XmpMeta, XmpValue, xmp_ns};
+use xmp_toolkit::{OpenFileOptions, XmpFile};
 
 use crate::{GenResult, IconicWindow};
 
@@ -42,9 +54,12 @@ impl IconicWindow {
                 let icon_path = match bottom_image_type {
                     BottomImageType::Folder(color) => win.load_built_in_bottom_icon(&color),
                     BottomImageType::FolderCustom(fg, bg) => {
-                        win.create_custom_folder_color(&fg, &bg, false).await
+                        IconicWindow::create_custom_folder_color(&fg, &bg).await
                     }
                     BottomImageType::Custom(path) => path,
+                    BottomImageType::CustomCached(hash) => {
+                        IconicWindow::bottom_image_cache_path(&hash)
+                    }
                     _ => win.load_built_in_bottom_icon("None"),
                 };
 
@@ -57,32 +72,12 @@ impl IconicWindow {
         ));
     }
 
-    // Replace the
-    pub async fn create_custom_folder_color(
-        &self,
-        foreground: &str,
-        background: &str,
-        regeneration: bool,
-    ) -> PathBuf {
+    // Replace the colors of the custom folder, and store it in the cache
+    pub async fn create_custom_folder_color(foreground: &str, background: &str) -> PathBuf {
         info!("Creating custom folder colors");
-        let folder_svg_file =
-            std::fs::read_to_string("/app/share/Iconic/folders/folder_Custom.svg").unwrap();
-        let folder_svg_lines = folder_svg_file.lines();
-        let new_custom_folder: String = folder_svg_lines
-            .map(|row| {
-                let row_clone = row.to_string();
-                let row_clone = row_clone.replace("a4caee", &foreground);
-                let mut row_clone = row_clone.replace("438de6", &background);
-                row_clone.push_str("\n");
-                row_clone
-            })
-            .collect();
-        let new_custom_folder_bytes = new_custom_folder.as_bytes().to_owned();
+        let new_custom_folder_bytes = Self::custom_folder_svg(foreground, background).into_bytes();
         let mut cache_location = Self::get_cache_path();
-        cache_location.push(format!(
-            "custom_folder{}.svg",
-            if regeneration { "_regeneration" } else { "" }
-        ));
+        cache_location.push("custom_folder.svg");
         let cache_location_clone = cache_location.clone();
 
         gio::spawn_blocking(move || {
@@ -93,6 +88,22 @@ impl IconicWindow {
         cache_location
     }
 
+    // The SVG of the custom folder, with the given colors
+    pub fn custom_folder_svg(foreground: &str, background: &str) -> String {
+        let folder_svg_file =
+            std::fs::read_to_string("/app/share/Iconic/folders/folder_Custom.svg").unwrap();
+        let folder_svg_lines = folder_svg_file.lines();
+        folder_svg_lines
+            .map(|row| {
+                let row_clone = row.to_string();
+                let row_clone = row_clone.replace("a4caee", foreground);
+                let mut row_clone = row_clone.replace("438de6", background);
+                row_clone.push_str("\n");
+                row_clone
+            })
+            .collect()
+    }
+
     pub fn load_built_in_bottom_icon(&self, accent_color_setting: &str) -> PathBuf {
         // let imp = self.imp();
         let folder_color_name = match accent_color_setting {
@@ -129,26 +140,37 @@ impl IconicWindow {
                     match top_file_selected {
                         Some(true) => {
                             // Pasting from a clipboard does not get a file path, which the new_iconic_file creation function needs
-                            let iconic_file = gio::spawn_blocking(move || {
+                            let iconic_file = match gio::spawn_blocking(move || {
                                 File::from_image(image, thumbnail_size, Some(svg_size), "pasted")
                             })
                             .await
-                            .unwrap();
+                            .unwrap()
+                            {
+                                Ok(iconic_file) => iconic_file,
+                                Err(error) => {
+                                    show_error_popup(&self, "", true, Some(error));
+                                    return;
+                                }
+                            };
+                            self.edit_folder(None);
+                            self.set_top_image_crop(TopImageCrop::default());
                             imp.top_image_file.lock().unwrap().replace(iconic_file);
                         }
                         _ => {
-                            imp.bottom_image_file.lock().unwrap().replace(
-                                gio::spawn_blocking(move || {
-                                    File::from_image(
-                                        image.clone(),
-                                        thumbnail_size,
-                                        Some(svg_size),
-                                        "pasted",
-                                    )
-                                })
-                                .await
-                                .unwrap(),
-                            );
+                            match gio::spawn_blocking(move || {
+                                File::from_image(image, thumbnail_size, Some(svg_size), "pasted")
+                            })
+                            .await
+                            .unwrap()
+                            {
+                                Ok(iconic_file) => {
+                                    imp.bottom_image_file.lock().unwrap().replace(iconic_file);
+                                }
+                                Err(error) => {
+                                    show_error_popup(&self, "", true, Some(error));
+                                    return;
+                                }
+                            }
                         }
                     }
                     self.check_icon_update();
@@ -238,6 +260,12 @@ impl IconicWindow {
         };
         debug!("file type: {:?}", mime_type);
         match mime_type {
+            // Dropping a folder opens its custom icon
+            _ if file_info.file_type() == gio::FileType::Directory => {
+                if let Err(error) = self.open_folder_icon(file).await {
+                    show_error_popup(&self, "", true, Some(error));
+                }
+            }
             Some(x) if x == String::from("image") => {
                 let top_file_selected = self.top_or_bottom_popup().await;
                 imp.image_loading_spinner.set_visible(true);
@@ -254,14 +282,18 @@ impl IconicWindow {
                     }
                     Some(false) => {
                         imp.stack.set_visible_child_name("stack_main_page");
-                        self.new_iconic_file_creation(
-                            Some(file),
-                            None,
-                            svg_render_size,
-                            thumbnail_size,
-                            false,
-                        )
-                        .await;
+                        if let Some(file) = self
+                            .new_iconic_file_creation(
+                                Some(file),
+                                None,
+                                svg_render_size,
+                                thumbnail_size,
+                                false,
+                            )
+                            .await
+                        {
+                            self.set_custom_bottom_image(&file);
+                        }
                     }
                     _ => (),
                 };
@@ -282,13 +314,18 @@ impl IconicWindow {
         let imp = self.imp();
         let thumbnail_size: u32 = imp.settings.get("thumbnail-size");
         let svg_size: u32 = imp.settings.get("svg-render-size");
-        if let Ok(image) =
+        if let Ok(file) =
             image::load_from_memory_with_format(&file.save_to_png_bytes(), image::ImageFormat::Png)
+                .map_err(Into::into)
+                .and_then(|image| {
+                    File::from_image(image, thumbnail_size, Some(svg_size), "dragged.png")
+                })
         {
-            let file = File::from_image(image, thumbnail_size, Some(svg_size), "dragged.png");
             let top_file_selected = self.top_or_bottom_popup().await;
             match top_file_selected {
                 Some(true) => {
+                    self.edit_folder(None);
+                    self.set_top_image_crop(TopImageCrop::default());
                     imp.top_image_file.lock().unwrap().replace(file);
                     self.check_icon_update();
                 }
@@ -312,6 +349,10 @@ impl IconicWindow {
                 .add_toast(adw::Toast::new(&gettext("Nothing to save")));
             return Ok(false);
         };
+        let edited_folder = imp.edited_folder.borrow().clone();
+        if let Some(folder) = edited_folder {
+            return self.save_folder_icon(&folder).await;
+        }
         let file_name = format!(
             "folder-{}.png",
             imp.top_image_file
@@ -321,17 +362,36 @@ impl IconicWindow {
                 .into_reason_result("No top image found")?
                 .filename
         );
+        let filters = gio::ListStore::new::<gtk::FileFilter>();
+        for format in OutputFormat::ALL {
+            let filter = gtk::FileFilter::new();
+            filter.set_name(Some(&format.name()));
+            filter.add_mime_type(format.mime_type());
+            filter.add_suffix(format.extension());
+            filters.append(&filter);
+        }
         let file_chooser = gtk::FileDialog::builder()
             .initial_name(file_name)
+            .filters(&filters)
             .modal(true)
             .build();
         self.imp().stack.set_visible_child_name("stack_saving_page");
         match file_chooser.save_future(Some(self)).await {
             Ok(file) => {
+                let Some(file) = self
+                    .with_chosen_format(file, file_chooser.default_filter())
+                    .await
+                else {
+                    self.imp().stack.set_visible_child_name("stack_main_page");
+                    imp.toast_overlay
+                        .add_toast(adw::Toast::new(&gettext("File not saved")));
+                    return Ok(false);
+                };
                 let saved_file = self
                     .save_file(file, imp.monochrome_switch.is_active(), None, None, false)
-                    .await?;
+                    .await;
                 self.imp().stack.set_visible_child_name("stack_main_page");
+                let saved_file = saved_file?;
                 imp.toast_overlay.add_toast(
                     adw::Toast::builder()
                         .button_label(gettext("Open Folder"))
@@ -362,34 +422,12 @@ impl IconicWindow {
         Ok(true)
     }
 
-    pub fn copy_folder_image_to_cache(
-        &self,
-        original_path: &PathBuf,
-        cache_dir: &PathBuf,
-    ) -> GenResult<(PathBuf, String)> {
-        let file_name = format!(
-            "folder.{}",
-            original_path
-                .extension()
-                .into_reason_result("Failed to get folder file extension")?
-                .to_str()
-                .into_result()?
-        );
-        self.imp()
-            .settings
-            .set("folder-cache-name", file_name.clone())
-            .unwrap();
-        let cache_path = cache_dir.join(file_name.clone());
-        std::fs::copy(original_path, cache_path.clone())?;
-        Ok((cache_path, file_name))
-    }
-
     pub async fn save_file(
         &self,
         file: gio::File,
         use_monochrome: bool,
         manual_monochrome_values: Option<(u8, gtk::gdk::RGBA)>,
-        top_image_hash: Option<u64>,
+        top_image_hash: Option<String>,
         small: bool,
     ) -> GenResult<bool> {
         let imp = self.imp();
@@ -444,21 +482,71 @@ impl IconicWindow {
                 base_image,
                 top_image_dynamicimage,
                 imageops::FilterType::Gaussian,
-                imp.x_scale.value(),
-                imp.y_scale.value(),
-                imp.size.value(),
+                CompositeParams::from_window(self, use_monochrome),
             )
             .await;
         let path = file.path().unwrap();
-        let path_clone = path.clone();
-        let _ =
-            gio::spawn_blocking(move || generated_image.save_with_format(path, ImageFormat::Png))
-                .await
-                .unwrap()?;
-        self.add_image_metadata(path_clone, top_image_hash, None)?;
+        let format = OutputFormat::from_extension(&path).unwrap_or(OutputFormat::Png);
+        let properties = FileProperties::new(self, top_image_hash, self.get_default_color());
+        gio::spawn_blocking(move || format.save(&generated_image, &path, Some(&properties)))
+            .await
+            .unwrap()?;
         Ok(true)
     }
 
+    // The format is chosen by the extension of the file
+    // If another file type is picked while the name still has the initial extension, the extension is changed to match
+    // None if the user does not want to replace the file with the changed extension
+    async fn with_chosen_format(
+        &self,
+        file: gio::File,
+        filter: Option<gtk::FileFilter>,
+    ) -> Option<gio::File> {
+        let Some(path) = file.path() else {
+            return Some(file);
+        };
+        let chosen = filter.and_then(|filter| {
+            let name = filter.name()?;
+            OutputFormat::ALL
+                .into_iter()
+                .find(|format| format.name() == name.as_str())
+        });
+        let Some(chosen) = chosen else {
+            return Some(file);
+        };
+        let changed_path = path.with_extension(chosen.extension());
+        if !matches!(
+            OutputFormat::from_extension(&path),
+            None | Some(OutputFormat::Png)
+        ) || changed_path == path
+        {
+            return Some(file);
+        }
+        // The dialog only asked to overwrite the file with the original name
+        if changed_path.exists() {
+            let file_name = changed_path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            if self
+                .show_alert_dialog(
+                    &gettext("Replace File?"),
+                    &format!(
+                        "{} “{file_name}” {}",
+                        gettext("A file named"),
+                        gettext("already exists. Do you want to replace it?")
+                    ),
+                    vec![&gettext("Replace"), &gettext("Cancel")],
+                )
+                .await
+                != Some(0)
+            {
+                return None;
+            }
+        }
+        Some(gio::File::for_path(changed_path))
+    }
+
     pub fn reset_bottom_icon(&self) {
         self.imp()
             .toast_overlay
@@ -481,6 +569,204 @@ impl IconicWindow {
         imp.image_loading_spinner.set_visible(false);
     }
 
+    // Let the user pick one of the icons installed on the system as top image
+    pub async fn load_installed_icon(&self) {
+        let imp = self.imp();
+        let Some(icon) = IconBrowser::new().choose(self).await else {
+            imp.toast_overlay
+                .add_toast(adw::Toast::new(&gettext("Nothing selected")));
+            return;
+        };
+        // The name is stored so the icon can be found again during regeneration
+        // A failed or cancelled load keeps the previous top image, which keeps its own source
+        if self
+            .load_top_file(gio::File::for_path(&icon.path))
+            .await
+            .is_some()
+        {
+            self.set_top_image_source(TopImageSource::Icon(icon.name));
+        }
+        imp.image_loading_spinner.set_visible(false);
+    }
+
+    // Let the user pick an installed application, and use its icon as top image
+    pub async fn load_application_icon(&self) {
+        let imp = self.imp();
+        let Some(application) = ApplicationBrowser::new().choose(self).await else {
+            imp.toast_overlay
+                .add_toast(adw::Toast::new(&gettext("Nothing selected")));
+            return;
+        };
+        let svg_render_size: u32 = imp.settings.get("svg-render-size");
+        let app_id = application.id.clone();
+        let Some(path) = gio::spawn_blocking(move || {
+            icon_library::resolve_application_icon(&app_id, svg_render_size)
+        })
+        .await
+        .unwrap() else {
+            imp.toast_overlay.add_toast(adw::Toast::new(&gettext(
+                "The icon of this application could not be found",
+            )));
+            return;
+        };
+        // The id is stored so the icon can be looked up again during regeneration
+        if self
+            .load_top_file(gio::File::for_path(&path))
+            .await
+            .is_some()
+        {
+            self.set_top_image_source(TopImageSource::Application(application.id));
+        }
+        imp.image_loading_spinner.set_visible(false);
+    }
+
+    // Lets the user pick a folder, and opens its custom icon for editing
+    pub async fn pick_folder_icon(&self) -> GenResult<()> {
+        let dialog = gtk::FileDialog::builder()
+            .title(gettext("Choose a Folder with a Custom Icon"))
+            .initial_folder(&gio::File::for_path(glib::home_dir()))
+            .modal(true)
+            .build();
+        let folder = match dialog.select_folder_future(Some(self)).await {
+            Ok(folder) => folder,
+            Err(error) => {
+                debug!("No folder chosen: {error}");
+                return Ok(());
+            }
+        };
+        self.open_folder_icon(folder).await
+    }
+
+    // Opens the custom icon of a folder for editing, saving then updates the icon of that folder
+    // Icons made by Iconic are opened with their top image and properties, other icons are used as top image
+    pub async fn open_folder_icon(&self, folder: gio::File) -> GenResult<()> {
+        let imp = self.imp();
+        let folder_path = folder.path().into_reason_result("Can't get folder path")?;
+        let Some(icon_path) = IconUsage::custom_icon(&folder_path) else {
+            imp.toast_overlay.add_toast(adw::Toast::new(&gettext(
+                "This folder does not have a custom icon",
+            )));
+            return Ok(());
+        };
+        if !icon_path.exists() {
+            return Err(gettext(
+                "The custom icon of this folder no longer exists, Find Broken Folder Icons can repair it",
+            )
+            .into());
+        }
+        imp.stack.set_visible_child_name("stack_main_page");
+        imp.image_loading_spinner.set_visible(true);
+        let properties_path = icon_path.clone();
+        let (properties, top_image_path) = gio::spawn_blocking(move || {
+            let properties = FileProperties::from_path(&properties_path);
+            let top_image_path = properties.as_ref().ok().and_then(|(properties, _)| {
+                let installed_icons = Self::resolve_top_image_icons([properties]);
+                Self::find_top_image(properties, &installed_icons).filter(|path| path.exists())
+            });
+            (properties, top_image_path)
+        })
+        .await
+        .unwrap();
+        if let Err(error) = &properties {
+            info!("{icon_path:?} is not an Iconic icon, using it as top image: {error}");
+        }
+        let svg_render_size: u32 = imp.settings.get("svg-render-size");
+        let thumbnail_size: u32 = imp.settings.get("thumbnail-size");
+        let loaded = match (properties, top_image_path) {
+            (Ok((properties, _)), Some(top_image_path)) => {
+                let loaded = self
+                    .new_iconic_file_creation(
+                        None,
+                        Some(top_image_path),
+                        svg_render_size,
+                        thumbnail_size,
+                        true,
+                    )
+                    .await
+                    .is_some();
+                if let Some(top_image) = imp.top_image_file.lock().unwrap().as_mut() {
+                    top_image.source = properties.top_image_source.clone();
+                }
+                self.apply_properties(&properties);
+                loaded
+            }
+            _ => {
+                warn!(
+                    "The top image of {icon_path:?} can not be found, using the icon as top image"
+                );
+                self.default_sliders(false);
+                self.new_iconic_file_creation(
+                    None,
+                    Some(icon_path),
+                    svg_render_size,
+                    thumbnail_size,
+                    true,
+                )
+                .await
+                .is_some()
+            }
+        };
+        imp.image_loading_spinner.set_visible(false);
+        if loaded {
+            // Loading the top image stops editing the previous folder, so this is set afterwards
+            self.edit_folder(Some(folder_path));
+        }
+        Ok(())
+    }
+
+    // Shows which folder is being edited, None stops editing a folder
+    pub fn edit_folder(&self, folder: Option<PathBuf>) {
+        let imp = self.imp();
+        let subtitle = match &folder {
+            Some(folder) => format!(
+                "{} {}",
+                gettext("Editing the icon of"),
+                folder
+                    .file_name()
+                    .unwrap_or(folder.as_os_str())
+                    .to_string_lossy()
+            ),
+            None => String::new(),
+        };
+        imp.window_title.set_subtitle(&subtitle);
+        imp.edited_folder.replace(folder);
+    }
+
+    // Saves the icon as a new file in the data directory, and points the edited folder to it
+    // The old icon is kept, as other folders may still use it
+    async fn save_folder_icon(&self, folder: &Path) -> GenResult<bool> {
+        let imp = self.imp();
+        let file = self.create_drag_file();
+        let top_image = imp
+            .top_image_file
+            .lock()
+            .map_err_to_str()?
+            .clone()
+            .into_reason_result("No top image found")?;
+        self.save_file(
+            file.clone(),
+            imp.monochrome_switch.is_active(),
+            None,
+            Some(top_image.hash.clone()),
+            false,
+        )
+        .await?;
+        // Like dragged icons, the icon can be regenerated with the top image in the cache
+        self.store_top_image_in_cache(&top_image)?;
+        set_custom_icon(
+            folder,
+            &file.path().into_reason_result("Can't get file path")?,
+        )?;
+        imp.toast_overlay.add_toast(
+            adw::Toast::builder()
+                .button_label(gettext("Open Folder"))
+                .action_name("app.open_file_location")
+                .title(gettext("Folder Icon Updated"))
+                .build(),
+        );
+        Ok(true)
+    }
+
     pub async fn load_temp_folder_icon(&self) {
         let imp = self.imp();
         let thumbnail_size: u32 = imp.settings.get("thumbnail-size");
@@ -488,8 +774,12 @@ impl IconicWindow {
         match self.open_file_chooser().await {
             Some(x) => {
                 imp.stack.set_visible_child_name("stack_main_page");
-                self.new_iconic_file_creation(Some(x), None, size, thumbnail_size, false)
-                    .await;
+                if let Some(file) = self
+                    .new_iconic_file_creation(Some(x), None, size, thumbnail_size, false)
+                    .await
+                {
+                    self.set_custom_bottom_image(&file);
+                }
             }
             None => {
                 imp.toast_overlay
@@ -498,6 +788,28 @@ impl IconicWindow {
         };
     }
 
+    // Bottom images loaded from a file are also stored in the cache, so icons made with them can be regenerated
+    // Only the chosen image of an icon container or animation is stored, so the icon uses the same image when it is regenerated
+    fn set_custom_bottom_image(&self, file: &File) {
+        let stored = match file.image_index {
+            Some(_) => {
+                let mut data = std::io::Cursor::new(vec![]);
+                file.dynamic_image
+                    .write_to(&mut data, ImageFormat::Png)
+                    .map_err(|error| error.into())
+                    .and_then(|_| Self::store_bottom_image_data_in_cache(data.into_inner()))
+            }
+            None => Self::store_bottom_image_in_cache(&file.path),
+        };
+        match stored {
+            Ok(hash) => {
+                self.imp().file_properties.borrow_mut().bottom_image_type =
+                    BottomImageType::CustomCached(hash)
+            }
+            Err(error) => warn!("Storing the bottom image in the cache failed: {error}"),
+        }
+    }
+
     pub async fn load_folder_icon(&self, path: PathBuf) {
         let size: u32 = self.imp().settings.get("thumbnail-size");
         self.new_iconic_file_creation(
@@ -510,7 +822,14 @@ impl IconicWindow {
         .await;
     }
 
-    pub async fn load_top_file(&self, filename: gio::File) {
+    fn set_top_image_source(&self, source: TopImageSource) {
+        if let Some(top_image) = self.imp().top_image_file.lock().unwrap().as_mut() {
+            top_image.source = source;
+        }
+    }
+
+    // Returns None if the file could not be loaded, or loading was cancelled
+    pub async fn load_top_file(&self, filename: gio::File) -> Option<File> {
         let imp = self.imp();
         if imp.stack.visible_child_name() == Some("stack_welcome_page".into()) {
             imp.stack.set_visible_child_name("stack_main_page");
@@ -519,7 +838,7 @@ impl IconicWindow {
         let svg_render_size: u32 = imp.settings.get("svg-render-size");
         let thumbnail_size: u32 = imp.settings.get("thumbnail-size");
         self.new_iconic_file_creation(Some(filename), None, svg_render_size, thumbnail_size, true)
-            .await;
+            .await
     }
 
     // Creates a new folder_icon::File from a gio::file or path.
@@ -542,25 +861,76 @@ impl IconicWindow {
             return None;
         }
         let imp = self.imp();
+        // Only files picked by the user can hold several images that need a choice
+        let choose_image = file.is_some();
         let file_temp = if let Some(path_temp) = path {
             gio::File::for_path(path_temp)
         } else {
             file.unwrap()
         };
-        let new_file = match gio::spawn_blocking(move || {
-            File::new(file_temp, svg_render_size, thumbnail_render_size)
-                .map_err(|err| err.to_string())
+        let image_index = match choose_image {
+            true => match self
+                .choose_image_index(&file_temp, svg_render_size, thumbnail_render_size)
+                .await
+            {
+                Ok(index) => index,
+                Err(_) => return None,
+            },
+            false => None,
+        };
+        // Large images report how much has been read, the progress bar only shows up for those
+        let cancellable = gio::Cancellable::new();
+        let (sender, receiver) = async_channel::unbounded();
+        let progress = LoadProgress {
+            cancellable: cancellable.clone(),
+            sender,
+        };
+        imp.loading_cancellable.replace(Some(cancellable.clone()));
+        glib::spawn_future_local(glib::clone!(
+            #[weak(rename_to = win)]
+            self,
+            async move {
+                let imp = win.imp();
+                while let Ok(fraction) = receiver.recv().await {
+                    imp.image_loading_box.set_visible(true);
+                    imp.image_loading_progress.set_fraction(fraction);
+                }
+                imp.image_loading_box.set_visible(false);
+                imp.image_loading_progress.set_fraction(0.0);
+            }
+        ));
+        let result = gio::spawn_blocking(move || {
+            File::with_image_index(
+                file_temp,
+                svg_render_size,
+                thumbnail_render_size,
+                image_index,
+                Some(&progress),
+            )
+            .map_err(|err| err.to_string())
         })
         .await
-        .unwrap()
-        {
+        .unwrap();
+        imp.loading_cancellable.replace(None);
+        let new_file = match result {
             Ok(x) => x,
+            Err(_) if cancellable.is_cancelled() => {
+                imp.toast_overlay
+                    .add_toast(adw::Toast::new(&gettext("Loading cancelled")));
+                return None;
+            }
             Err(e) => {
                 show_error_popup(&self, &e.to_string(), true, None::<String>);
                 return None;
             }
         };
 
+        // A new top image starts a new icon, which is not saved to the folder that was being edited,
+        // and does not keep the crop of the previous top image
+        if change_top_icon {
+            self.edit_folder(None);
+            self.set_top_image_crop(TopImageCrop::default());
+        }
         match change_top_icon {
             true => imp.top_image_file.lock().unwrap().replace(new_file.clone()),
             false => imp
@@ -574,89 +944,151 @@ impl IconicWindow {
         Some(new_file)
     }
 
-    pub fn add_image_metadata(
+    // Lets the user pick one of the sizes in an .ico or .icns file, or one of the frames of an animation
+    // Ok(None) if the file holds only one image, Err if the dialog is closed
+    async fn choose_image_index(
+        &self,
+        file: &gio::File,
+        size: u32,
+        preview_size: u32,
+    ) -> Result<Option<usize>, ()> {
+        let (Some(mime_type), Some(path)) = (
+            file.query_info(
+                gio::FILE_ATTRIBUTE_STANDARD_CONTENT_TYPE,
+                FileQueryInfoFlags::NONE,
+                Cancellable::NONE,
+            )
+            .ok()
+            .and_then(|info| info.content_type()),
+            file.path(),
+        ) else {
+            return Ok(None);
+        };
+        if let Some(kind) = ContainerKind::from_mime_type(&mime_type) {
+            self.choose_container_entry(kind, path, size).await
+        } else if let Some(format) = AnimationFormat::from_mime_type(&mime_type) {
+            self.choose_animation_frame(format, path, preview_size)
+                .await
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn choose_container_entry(
         &self,
+        kind: ContainerKind,
         path: PathBuf,
-        top_image_hash: Option<u64>,
-        custom_properties: Option<FileProperties>,
-    ) -> GenResult<()> {
-        let properties = custom_properties.unwrap_or(FileProperties::new(
-            &self,
-            top_image_hash,
-            self.get_default_color(),
-        ));
-        let mut file = XmpFile::new()?;
-        file.open_file(path, OpenFileOptions::default().for_update())?;
-        let mut metadata = XmpMeta::new()?;
-        metadata.set_property(
-            xmp_ns::XMP,
-            "x_val",
-            &XmpValue::new(properties.x_val.to_string()),
-        )?;
-        metadata.set_property(
-            xmp_ns::XMP,
-            "y_val",
-            &XmpValue::new(properties.y_val.to_string()),
-        )?;
-        metadata.set_property(
-            xmp_ns::XMP,
-            "zoom_val",
-            &XmpValue::new(properties.zoom_val.to_string()),
-        )?;
-        metadata.set_property(
-            xmp_ns::XMP,
-            "monochrome_toggle",
-            &XmpValue::new(properties.monochrome_toggle.to_string()),
-        )?;
-        if let Some(colors) = properties.monochrome_color {
-            metadata.set_property(
-                xmp_ns::XMP,
-                "monochrome_red",
-                &XmpValue::new(colors.0.to_string()),
-            )?;
-            metadata.set_property(
-                xmp_ns::XMP,
-                "monochrome_green",
-                &XmpValue::new(colors.1.to_string()),
-            )?;
-            metadata.set_property(
-                xmp_ns::XMP,
-                "monochrome_blue",
-                &XmpValue::new(colors.2.to_string()),
-            )?;
+        size: u32,
+    ) -> Result<Option<usize>, ()> {
+        // Invalid containers are reported when the file is loaded
+        let Ok((container, previews)) = gio::spawn_blocking(move || -> GenResult<_> {
+            let container = IconContainer::parse(kind, &std::fs::read(path)?)?;
+            let previews: Vec<DynamicImage> = container
+                .entries
+                .iter()
+                .map(|entry| {
+                    entry
+                        .decode()
+                        .unwrap_or_else(|_| DynamicImage::new_rgba8(1, 1))
+                })
+                .collect();
+            Ok((container, previews))
+        })
+        .await
+        .unwrap() else {
+            return Ok(None);
+        };
+        if container.entries.len() < 2 {
+            return Ok(None);
         }
-        metadata.set_property(
-            xmp_ns::XMP,
-            "monochrome_default",
-            &XmpValue::new(properties.monochrome_default.to_string()),
-        )?;
-        metadata.set_property(
-            xmp_ns::XMP,
-            "monochrome_invert",
-            &XmpValue::new(properties.monochrome_invert.to_string()),
-        )?;
-        metadata.set_property(
-            xmp_ns::XMP,
-            "monochrome_threshold",
-            &XmpValue::new(properties.monochrome_threshold_val.to_string()),
-        )?;
-        if let Some(hash) = properties.top_image_hash {
-            metadata.set_property(
-                xmp_ns::XMP,
-                "top_image_hash",
-                &XmpValue::new(hash.to_string()),
-            )?;
+        let textures: Vec<gdk::Texture> = previews
+            .iter()
+            .map(|preview| self.dynamic_image_to_texture(preview))
+            .collect();
+        IconSizeDialog::new(&container, &textures, container.best_match(size))
+            .choose(self)
+            .await
+            .map(Some)
+            .ok_or(())
+    }
+
+    async fn choose_animation_frame(
+        &self,
+        format: AnimationFormat,
+        path: PathBuf,
+        preview_size: u32,
+    ) -> Result<Option<usize>, ()> {
+        // Invalid animations are reported when the file is loaded
+        // The frames are read from the file as they are needed, instead of reading the whole file first
+        let Ok(previews) = gio::spawn_blocking(move || -> GenResult<_> {
+            let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+            format.frame_previews(reader, preview_size)
+        })
+        .await
+        .unwrap() else {
+            return Ok(None);
+        };
+        if previews.len() < 2 {
+            return Ok(None);
         }
-        metadata.set_property(
-            xmp_ns::XMP,
-            "bottom_image_type",
-            &XmpValue::new(serde_json::to_string(&properties.bottom_image_type)?),
-        )?;
-        metadata.set_property(
-            xmp_ns::XMP,
-            "default",
-            &XmpValue::new(properties.default.to_string()),
-        )?;
+        let textures: Vec<gdk::Texture> = previews
+            .iter()
+            .map(|preview| self.dynamic_image_to_texture(preview))
+            .collect();
+        FrameDialog::new(textures)
+            .choose(self)
+            .await
+            .map(Some)
+            .ok_or(())
+    }
+
+    // Finds the folders that use an Iconic icon in the chosen folders, and shows the ones whose icon is gone
+    pub async fn scan_icon_usage(&self) -> GenResult<()> {
+        let imp = self.imp();
+        let dialog = gtk::FileDialog::builder()
+            .title(gettext("Choose Folders to Scan"))
+            .initial_folder(&gio::File::for_path(glib::home_dir()))
+            .modal(true)
+            .build();
+        let folders = match dialog.select_multiple_folders_future(Some(self)).await {
+            Ok(folders) => folders,
+            Err(error) => {
+                debug!("No folders chosen: {error}");
+                return Ok(());
+            }
+        };
+        let roots: Vec<PathBuf> = folders
+            .iter::<gio::File>()
+            .flatten()
+            .filter_map(|folder| folder.path())
+            .collect();
+        imp.toast_overlay
+            .add_toast(adw::Toast::new(&gettext("Scanning folders")));
+        let _iconic_busy = Arc::clone(&imp.app_busy);
+        let usage = gio::spawn_blocking(move || IconUsage::scan(&roots, &Self::get_data_path()))
+            .await
+            .unwrap();
+        IconUsageDialog::new(&usage, &imp.settings, self.get_accent_color()).present(Some(self));
+        self.close_iconic_busy_popup();
+        Ok(())
+    }
+
+    // Writes the properties into the XMP metadata of an existing image
+    pub fn write_image_metadata(path: PathBuf, properties: &FileProperties) -> GenResult<()> {
+        // The XMP toolkit can not write icon containers, the metadata goes into their largest image
+        if let Some(kind) = OutputFormat::from_path(&path).container_kind() {
+            // The whole container is rewritten, so it is written next to the icon and then renamed over it
+            let mut container = IconContainer::parse(kind, &std::fs::read(&path)?)?;
+            container.set_xmp_packet(&properties.to_xmp()?.to_string())?;
+            let data = container.to_bytes(kind)?;
+            return replace_file_atomically(&path, "metadata", |temporary_path| {
+                std::fs::write(temporary_path, &data)?;
+                Ok(())
+            });
+        }
+        let mut file = XmpFile::new()?;
+        file.open_file(path, OpenFileOptions::default().for_update())?;
+        let metadata = properties.to_xmp()?;
         file.put_xmp(&metadata)?;
         file.close();
         Ok(())
@@ -0,0 +1,40 @@
+use std::cell::RefCell;
+
+use adw::subclass::prelude::*;
+use gio::glib::subclass::InitializingObject;
+use gtk::{gdk, glib};
+
+#[derive(Default, gtk::CompositeTemplate)]
+#[template(resource = "/nl/emphisia/icon/windows/frame_dialog/window.ui")]
+pub struct FrameDialog {
+    #[template_child]
+    pub frame_picture: TemplateChild<gtk::Picture>,
+    #[template_child]
+    pub frame_scale: TemplateChild<gtk::Scale>,
+    #[template_child]
+    pub frame_label: TemplateChild<gtk::Label>,
+    pub frames: RefCell<Vec<gdk::Texture>>,
+    pub sender: RefCell<Option<async_channel::Sender<usize>>>,
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for FrameDialog {
+    const NAME: &'static str = "FrameDialog";
+    type Type = super::FrameDialog;
+    type ParentType = adw::Dialog;
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.bind_template();
+        klass.bind_template_instance_callbacks();
+    }
+
+    fn instance_init(obj: &InitializingObject<Self>) {
+        obj.init_template();
+    }
+}
+
+impl ObjectImpl for FrameDialog {}
+
+impl WidgetImpl for FrameDialog {}
+
+impl AdwDialogImpl for FrameDialog {}
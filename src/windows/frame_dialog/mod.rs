@@ -0,0 +1,83 @@
+mod imp;
+
+use adw::prelude::*;
+use adw::subclass::prelude::ObjectSubclassIsExt;
+use gettextrs::gettext;
+use gtk::{gdk, glib};
+
+glib::wrapper! {
+    pub struct FrameDialog(ObjectSubclass<imp::FrameDialog>)
+        @extends gtk::Widget, adw::Dialog,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::ShortcutManager;
+}
+
+#[gtk::template_callbacks]
+impl FrameDialog {
+    // frames has a preview of every frame of the animation, the first frame is selected
+    pub fn new(frames: Vec<gdk::Texture>) -> Self {
+        let dialog: Self = glib::Object::new();
+        let imp = dialog.imp();
+        imp.frame_scale
+            .adjustment()
+            .set_upper(frames.len().saturating_sub(1) as f64);
+        imp.frames.replace(frames);
+        dialog.show_frame(0);
+        dialog
+    }
+
+    // Shows the dialog and waits until a frame is picked
+    // Returns the index of the frame, or None if the dialog is closed
+    pub async fn choose(&self, parent: &impl IsA<gtk::Widget>) -> Option<usize> {
+        let (sender, receiver) = async_channel::bounded(1);
+        self.imp().sender.replace(Some(sender));
+        // Dropping the sender makes the receiver return an error, which ends the wait
+        self.connect_closed(|dialog| {
+            dialog.imp().sender.take();
+        });
+        self.present(Some(parent));
+        receiver.recv().await.ok()
+    }
+
+    fn selected_frame(&self) -> usize {
+        self.imp().frame_scale.value().round() as usize
+    }
+
+    fn show_frame(&self, index: usize) {
+        let imp = self.imp();
+        let frames = imp.frames.borrow();
+        imp.frame_picture.set_paintable(frames.get(index));
+        imp.frame_label.set_label(&format!(
+            "{} {} {} {}",
+            gettext("Frame"),
+            index + 1,
+            gettext("of"),
+            frames.len()
+        ));
+    }
+
+    #[template_callback]
+    fn frame_changed(&self) {
+        self.show_frame(self.selected_frame());
+    }
+
+    #[template_callback]
+    fn previous_clicked(&self) {
+        let frame_scale = &self.imp().frame_scale;
+        frame_scale.set_value(frame_scale.value() - 1.0);
+    }
+
+    #[template_callback]
+    fn next_clicked(&self) {
+        let frame_scale = &self.imp().frame_scale;
+        frame_scale.set_value(frame_scale.value() + 1.0);
+    }
+
+    #[template_callback]
+    fn open_clicked(&self) {
+        let imp = self.imp();
+        if let Some(sender) = imp.sender.take() {
+            let _ = sender.try_send(self.selected_frame());
+        }
+        self.close();
+    }
+}
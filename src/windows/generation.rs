@@ -3,6 +3,59 @@ use gtk::gdk;
 use image::*;
 
 use crate::IconicWindow;
+use crate::objects::properties::{
+    BlendMode, ColorAdjustment, FileProperties, TopImageCrop, TopImageTransform,
+};
+
+// Everything that decides where and how the top image is placed on the bottom image
+#[derive(Debug, Clone, Copy)]
+pub struct CompositeParams {
+    pub x_val: f64,
+    pub y_val: f64,
+    pub zoom_val: f64,
+    pub crop: TopImageCrop,
+    pub transform: TopImageTransform,
+    pub blend_mode: BlendMode,
+    pub color_adjustment: ColorAdjustment,
+    // The color the hues are matched to, if the adjustment matches the accent
+    pub folder_color: gdk::RGBA,
+}
+
+impl CompositeParams {
+    // A monochrome top image already has its color, so it is not adjusted
+    pub fn from_window(window: &IconicWindow, monochrome: bool) -> Self {
+        let imp = window.imp();
+        Self {
+            x_val: imp.x_scale.value(),
+            y_val: imp.y_scale.value(),
+            zoom_val: imp.size.value(),
+            crop: imp.top_image_crop.get(),
+            transform: TopImageTransform::from_window(window),
+            blend_mode: BlendMode::from_window(window),
+            color_adjustment: match monochrome {
+                true => ColorAdjustment::default(),
+                false => ColorAdjustment::from_window(window),
+            },
+            folder_color: window.get_default_color(),
+        }
+    }
+
+    pub fn from_properties(properties: &FileProperties, folder_color: gdk::RGBA) -> Self {
+        Self {
+            x_val: properties.x_val,
+            y_val: properties.y_val,
+            zoom_val: properties.zoom_val,
+            crop: properties.crop,
+            transform: properties.transform,
+            blend_mode: properties.blend_mode,
+            color_adjustment: match properties.monochrome_toggle {
+                true => ColorAdjustment::default(),
+                false => properties.color_adjustment,
+            },
+            folder_color,
+        }
+    }
+}
 
 impl IconicWindow {
     pub async fn render_to_screen(&self) {
@@ -23,7 +76,8 @@ impl IconicWindow {
             .unwrap()
             .thumbnail
             .clone();
-        if imp.monochrome_switch.state() {
+        let monochrome = imp.monochrome_switch.state();
+        if monochrome {
             top_image = self.to_monochrome(
                 top_image,
                 imp.threshold_scale.value() as u8,
@@ -32,18 +86,19 @@ impl IconicWindow {
             );
         }
         self.image_save_sensitive(true);
-        let texture = self.dynamic_image_to_texture(
-            &self
-                .generate_image(
-                    base,
-                    top_image,
-                    imageops::FilterType::Nearest,
-                    imp.x_scale.value(),
-                    imp.y_scale.value(),
-                    imp.size.value(),
-                )
-                .await,
-        );
+        let mut image = self
+            .generate_image(
+                base,
+                top_image,
+                imageops::FilterType::Nearest,
+                CompositeParams::from_window(self, monochrome),
+            )
+            .await;
+        // The guides are drawn on a copy, so they never end up in a saved or dragged image
+        if imp.settings.boolean("show-guides") {
+            image = self.draw_guides(image);
+        }
+        let texture = self.dynamic_image_to_texture(&image);
         imp.image_view.set_paintable(&texture);
         imp.image_view.queue_draw();
     }
@@ -57,26 +112,34 @@ impl IconicWindow {
         threshold: u8,
         color: gdk::RGBA,
         invert: Option<bool>,
+    ) -> DynamicImage {
+        let invert = match invert {
+            Some(invert_value) => invert_value,
+            None => self.imp().monochrome_invert.is_active(),
+        };
+        Self::monochrome_image(image, threshold, color, invert)
+    }
+
+    // Does not need a window, so it can also be used during headless regeneration
+    pub fn monochrome_image(
+        image: DynamicImage,
+        threshold: u8,
+        color: gdk::RGBA,
+        invert: bool,
     ) -> DynamicImage {
         // Convert the image to RGBA8
         let rgba_img = image.to_rgba8();
-        // Define a threshold value
-        let threshold = threshold; // Adjust the threshold value as needed
 
         // Create a new image buffer for the monochrome image
         let mut mono_img: RgbaImage = ImageBuffer::new(rgba_img.width(), rgba_img.height());
-        let switch_state = match invert {
-            Some(invert_value) => invert_value,
-            None => self.imp().monochrome_invert.is_active(),
-        };
         // Apply the threshold to create a black and white image, keeping the alpha channel
         for (x, y, pixel) in rgba_img.enumerate_pixels() {
             let rgba = pixel.0;
             let luma = 0.299 * rgba[0] as f32 + 0.587 * rgba[1] as f32 + 0.114 * rgba[2] as f32;
             let threshold_reached = luma >= threshold as f32 && rgba[3] > 0;
-            let mono_pixel = match (threshold_reached, switch_state) {
+            let mono_pixel = match (threshold_reached, invert) {
                 (false, false) | (true, true) => Rgba([0u8, 0u8, 0u8, 0u8]), // Black
-                _ => self.create_colored_pixel(color, rgba[3]), // White with original alpha
+                _ => create_colored_pixel(color, rgba[3]), // White with original alpha
             };
             mono_img.put_pixel(x, y, mono_pixel);
         }
@@ -85,13 +148,61 @@ impl IconicWindow {
         DynamicImage::ImageRgba8(mono_img)
     }
 
-    fn create_colored_pixel(&self, color: gdk::RGBA, original_alpha: u8) -> Rgba<u8> {
-        Rgba([
-            (color.red() * 255.0) as u8,
-            (color.green() * 255.0) as u8,
-            (color.blue() * 255.0) as u8,
-            original_alpha,
-        ])
+    // Changes the colors of the top image, this is done instead of making it monochrome
+    // Matching the accent rotates the hues, so the most common hue of the image becomes the hue of the folder
+    pub fn adjust_colors(
+        image: DynamicImage,
+        adjustment: ColorAdjustment,
+        folder_color: gdk::RGBA,
+    ) -> DynamicImage {
+        if adjustment.is_identity() {
+            return image;
+        }
+        let mut image = image.to_rgba8();
+        let mut hue_shift = adjustment.hue_shift;
+        if adjustment.match_accent {
+            let (folder_hue, folder_saturation, _) = rgb_to_hsv([
+                folder_color.red() as f64,
+                folder_color.green() as f64,
+                folder_color.blue() as f64,
+            ]);
+            // A gray folder has no hue to match
+            if let Some(image_hue) = dominant_hue(&image)
+                && folder_saturation > 0.05
+            {
+                hue_shift += folder_hue - image_hue;
+            }
+        }
+        let hue_shift = hue_shift.rem_euclid(360.0);
+        let brightness = adjustment.brightness.clamp(-100.0, 100.0) / 100.0;
+        let contrast = ((adjustment.contrast.clamp(-100.0, 100.0) + 100.0) / 100.0).powi(2);
+        let saturation = (adjustment.saturation.clamp(-100.0, 100.0) + 100.0) / 100.0;
+        for pixel in image.pixels_mut() {
+            if pixel[3] == 0 {
+                continue;
+            }
+            let mut color = [
+                pixel[0] as f64 / 255.0,
+                pixel[1] as f64 / 255.0,
+                pixel[2] as f64 / 255.0,
+            ];
+            if hue_shift != 0.0 {
+                let (hue, saturation, value) = rgb_to_hsv(color);
+                color = hsv_to_rgb(hue + hue_shift, saturation, value);
+            }
+            let luma = 0.299 * color[0] + 0.587 * color[1] + 0.114 * color[2];
+            for (channel, value) in color.iter().enumerate() {
+                let mut value = luma + (value - luma) * saturation;
+                value = ((value - 0.5) * contrast + 0.5).clamp(0.0, 1.0);
+                // Full brightness makes the image white, no brightness makes it black
+                value = match brightness >= 0.0 {
+                    true => value + (1.0 - value) * brightness,
+                    false => value * (1.0 + brightness),
+                };
+                pixel[channel] = (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+            }
+        }
+        DynamicImage::ImageRgba8(image)
     }
 
     pub async fn generate_image(
@@ -99,33 +210,11 @@ impl IconicWindow {
         base_image: image::DynamicImage,
         top_image: image::DynamicImage,
         filter: imageops::FilterType,
-        x_scale_value: f64,
-        y_scale_value: f64,
-        scale: f64,
+        params: CompositeParams,
     ) -> DynamicImage {
         let imp = self.imp();
-        let coordinates = ((x_scale_value + 50.0) as i64, (y_scale_value + 50.0) as i64);
         let texture = gio::spawn_blocking(move || {
-            let mut base = base_image;
-            let top = top_image;
-            let base_dimension: (i64, i64) =
-                ((base.dimensions().0).into(), (base.dimensions().1).into());
-            let top = IconicWindow::resize_top_image(top, base.dimensions(), scale, filter);
-            let top_dimension: (i64, i64) = (
-                (top.dimensions().0 / 2).into(),
-                (top.dimensions().1 / 2).into(),
-            );
-            let final_coordinates: (i64, i64) = (
-                ((base_dimension.0 * coordinates.0) / 100) - top_dimension.0,
-                ((base_dimension.1 * coordinates.1) / 100) - top_dimension.1,
-            );
-            imageops::overlay(
-                &mut base,
-                &top,
-                final_coordinates.0.into(),
-                final_coordinates.1.into(),
-            );
-            base
+            IconicWindow::composite_image(base_image, top_image, filter, params)
         })
         .await
         .unwrap();
@@ -134,6 +223,155 @@ impl IconicWindow {
         texture
     }
 
+    // Places the top image on the bottom image, this is blocking
+    pub fn composite_image(
+        base_image: image::DynamicImage,
+        top_image: image::DynamicImage,
+        filter: imageops::FilterType,
+        params: CompositeParams,
+    ) -> DynamicImage {
+        let coordinates = ((params.x_val + 50.0) as i64, (params.y_val + 50.0) as i64);
+        let mut base = base_image;
+        let base_dimension: (i64, i64) =
+            ((base.dimensions().0).into(), (base.dimensions().1).into());
+        // Cropped first, so a matched accent only looks at the part of the image that is shown
+        let top = IconicWindow::crop_top_image(top_image, params.crop);
+        let top = IconicWindow::adjust_colors(top, params.color_adjustment, params.folder_color);
+        let top = IconicWindow::resize_top_image(top, base.dimensions(), params.zoom_val, filter);
+        let top = IconicWindow::transform_top_image(top, params.transform);
+        let top_dimension: (i64, i64) = (
+            (top.dimensions().0 / 2).into(),
+            (top.dimensions().1 / 2).into(),
+        );
+        let final_coordinates: (i64, i64) = (
+            ((base_dimension.0 * coordinates.0) / 100) - top_dimension.0,
+            ((base_dimension.1 * coordinates.1) / 100) - top_dimension.1,
+        );
+        match params.blend_mode {
+            BlendMode::Normal => {
+                imageops::overlay(
+                    &mut base,
+                    &top,
+                    final_coordinates.0.into(),
+                    final_coordinates.1.into(),
+                );
+                base
+            }
+            _ => IconicWindow::blend_top_image(
+                base,
+                &top.to_rgba8(),
+                final_coordinates,
+                params.blend_mode,
+            ),
+        }
+    }
+
+    // The size of the top image as a fraction of the bottom image, calculated the same way as in resize_top_image
+    // A rotated top image takes up the size of its bounding box
+    pub fn top_image_extent(
+        base_dimensions: (u32, u32),
+        top_dimensions: (u32, u32),
+        slider_position: f64,
+        rotation: f64,
+    ) -> (f64, f64) {
+        let (base_width, base_height) = (base_dimensions.0 as f64, base_dimensions.1 as f64);
+        let (top_width, top_height) = (top_dimensions.0 as f64, top_dimensions.1 as f64);
+        if base_width <= 0.0 || base_height <= 0.0 || top_width <= 0.0 || top_height <= 0.0 {
+            return (0.0, 0.0);
+        }
+        let scale_factor: f64 = (slider_position + 10.0) / 10.0;
+        let fit =
+            (base_width / scale_factor / top_width).min(base_height / scale_factor / top_height);
+        let (width, height) = rotated_size(top_width * fit, top_height * fit, rotation);
+        (width / base_width, height / base_height)
+    }
+
+    // Composites the top image onto the bottom image, following the W3C compositing spec
+    // The blended color only shows where the bottom image is opaque, so the top image keeps its own shape
+    pub fn blend_top_image(
+        base: DynamicImage,
+        top: &RgbaImage,
+        coordinates: (i64, i64),
+        blend_mode: BlendMode,
+    ) -> DynamicImage {
+        let mut base = base.to_rgba8();
+        let (base_width, base_height) = (base.width() as i64, base.height() as i64);
+        for (x, y, source) in top.enumerate_pixels() {
+            let (base_x, base_y) = (x as i64 + coordinates.0, y as i64 + coordinates.1);
+            if base_x < 0 || base_y < 0 || base_x >= base_width || base_y >= base_height {
+                continue;
+            }
+            let backdrop = base.get_pixel_mut(base_x as u32, base_y as u32);
+            let source_alpha = source[3] as f64 / 255.0;
+            if source_alpha == 0.0 {
+                continue;
+            }
+            let backdrop_alpha = backdrop[3] as f64 / 255.0;
+            let alpha = source_alpha + backdrop_alpha * (1.0 - source_alpha);
+            for channel in 0..3 {
+                let source_color = source[channel] as f64 / 255.0;
+                let backdrop_color = backdrop[channel] as f64 / 255.0;
+                let blended = blend_channel(backdrop_color, source_color, blend_mode);
+                let color = source_alpha * (1.0 - backdrop_alpha) * source_color
+                    + source_alpha * backdrop_alpha * blended
+                    + (1.0 - source_alpha) * backdrop_alpha * backdrop_color;
+                backdrop[channel] = (color / alpha * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+            backdrop[3] = (alpha * 255.0).round() as u8;
+        }
+        DynamicImage::ImageRgba8(base)
+    }
+
+    // Cropping happens before resizing, so the part that is kept fills the size of the top image
+    pub fn crop_top_image(image: DynamicImage, crop: TopImageCrop) -> DynamicImage {
+        if crop.is_identity() {
+            return image;
+        }
+        let (x, y, width, height) = crop.pixel_rect(image.dimensions());
+        let cropped = image.crop_imm(x, y, width, height);
+        if !crop.pad_to_square || width == height {
+            return cropped;
+        }
+        let side = width.max(height);
+        let mut padded = RgbaImage::new(side, side);
+        imageops::overlay(
+            &mut padded,
+            &cropped.to_rgba8(),
+            ((side - width) / 2) as i64,
+            ((side - height) / 2) as i64,
+        );
+        DynamicImage::ImageRgba8(padded)
+    }
+
+    pub fn transform_top_image(image: DynamicImage, transform: TopImageTransform) -> DynamicImage {
+        if transform.is_identity() {
+            return image;
+        }
+        let mut image = image.to_rgba8();
+        if transform.flip_horizontal {
+            imageops::flip_horizontal_in_place(&mut image);
+        }
+        if transform.flip_vertical {
+            imageops::flip_vertical_in_place(&mut image);
+        }
+        let rotation = transform.rotation.rem_euclid(360.0);
+        // Quarter turns are done losslessly
+        image = match rotation {
+            0.0 => image,
+            90.0 => imageops::rotate90(&image),
+            180.0 => imageops::rotate180(&image),
+            270.0 => imageops::rotate270(&image),
+            _ => rotate_free(&image, rotation),
+        };
+        if transform.opacity < 100.0 {
+            let opacity = transform.opacity.clamp(0.0, 100.0) / 100.0;
+            for pixel in image.pixels_mut() {
+                pixel[3] = (pixel[3] as f64 * opacity).round() as u8;
+            }
+        }
+        DynamicImage::ImageRgba8(image)
+    }
+
     pub fn resize_top_image(
         image: DynamicImage,
         dimensions: (u32, u32),
@@ -148,3 +386,203 @@ impl IconicWindow {
         image.resize(new_width, new_height, filter)
     }
 }
+
+fn create_colored_pixel(color: gdk::RGBA, original_alpha: u8) -> Rgba<u8> {
+    Rgba([
+        (color.red() * 255.0) as u8,
+        (color.green() * 255.0) as u8,
+        (color.blue() * 255.0) as u8,
+        original_alpha,
+    ])
+}
+
+// Blend a single color channel, both values are between 0 and 1
+fn blend_channel(backdrop: f64, source: f64, blend_mode: BlendMode) -> f64 {
+    let screen = |backdrop: f64, source: f64| backdrop + source - backdrop * source;
+    match blend_mode {
+        BlendMode::Normal => source,
+        BlendMode::Multiply => backdrop * source,
+        BlendMode::Screen => screen(backdrop, source),
+        BlendMode::Overlay => {
+            if backdrop <= 0.5 {
+                2.0 * backdrop * source
+            } else {
+                screen(2.0 * backdrop - 1.0, source)
+            }
+        }
+        BlendMode::SoftLight => {
+            if source <= 0.5 {
+                backdrop - (1.0 - 2.0 * source) * backdrop * (1.0 - backdrop)
+            } else {
+                let darkened = if backdrop <= 0.25 {
+                    ((16.0 * backdrop - 12.0) * backdrop + 4.0) * backdrop
+                } else {
+                    backdrop.sqrt()
+                };
+                backdrop + (2.0 * source - 1.0) * (darkened - backdrop)
+            }
+        }
+    }
+}
+
+// Hue in degrees, saturation and value from 0 to 1
+fn rgb_to_hsv(color: [f64; 3]) -> (f64, f64, f64) {
+    let [red, green, blue] = color;
+    let max = red.max(green).max(blue);
+    let min = red.min(green).min(blue);
+    let delta = max - min;
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == red {
+        60.0 * ((green - blue) / delta).rem_euclid(6.0)
+    } else if max == green {
+        60.0 * ((blue - red) / delta + 2.0)
+    } else {
+        60.0 * ((red - green) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+    (hue, saturation, max)
+}
+
+fn hsv_to_rgb(hue: f64, saturation: f64, value: f64) -> [f64; 3] {
+    let hue = hue.rem_euclid(360.0) / 60.0;
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (hue.rem_euclid(2.0) - 1.0).abs());
+    let (red, green, blue) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let offset = value - chroma;
+    [red + offset, green + offset, blue + offset]
+}
+
+// The average hue of an image, weighted by how colorful and opaque every pixel is
+// None if the image has hardly any color, or its colors cancel each other out
+fn dominant_hue(image: &RgbaImage) -> Option<f64> {
+    let (mut x, mut y) = (0.0, 0.0);
+    for pixel in image.pixels() {
+        let (hue, saturation, value) = rgb_to_hsv([
+            pixel[0] as f64 / 255.0,
+            pixel[1] as f64 / 255.0,
+            pixel[2] as f64 / 255.0,
+        ]);
+        let weight = saturation * value * pixel[3] as f64 / 255.0;
+        let (sin, cos) = hue.to_radians().sin_cos();
+        x += cos * weight;
+        y += sin * weight;
+    }
+    let pixels = (image.width() as f64 * image.height() as f64).max(1.0);
+    if (x * x + y * y).sqrt() / pixels < 0.01 {
+        return None;
+    }
+    Some(y.atan2(x).to_degrees().rem_euclid(360.0))
+}
+
+// The size of the bounding box of a rectangle after it is rotated
+fn rotated_size(width: f64, height: f64, degrees: f64) -> (f64, f64) {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    (
+        width * cos.abs() + height * sin.abs(),
+        width * sin.abs() + height * cos.abs(),
+    )
+}
+
+// Rotates an image clockwise around its center by any angle, using bilinear sampling
+// The canvas grows to fit the rotated image, so nothing is cut off
+fn rotate_free(image: &RgbaImage, degrees: f64) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let (new_width, new_height) = rotated_size(width as f64, height as f64, degrees);
+    let (new_width, new_height) = (new_width.ceil() as u32, new_height.ceil() as u32);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let center = (width as f64 / 2.0, height as f64 / 2.0);
+    let new_center = (new_width as f64 / 2.0, new_height as f64 / 2.0);
+    let mut rotated = RgbaImage::new(new_width, new_height);
+    for (x, y, pixel) in rotated.enumerate_pixels_mut() {
+        // Map the center of the destination pixel back onto the source image
+        let dx = x as f64 + 0.5 - new_center.0;
+        let dy = y as f64 + 0.5 - new_center.1;
+        let source_x = dx * cos + dy * sin + center.0 - 0.5;
+        let source_y = -dx * sin + dy * cos + center.1 - 0.5;
+        *pixel = sample_bilinear(image, source_x, source_y);
+    }
+    rotated
+}
+
+// Pixels outside of the image are transparent
+// Colors are weighted by their alpha, so transparent pixels don't darken the edges
+fn sample_bilinear(image: &RgbaImage, x: f64, y: f64) -> Rgba<u8> {
+    let (width, height) = (image.width() as i64, image.height() as i64);
+    let (x0, y0) = (x.floor() as i64, y.floor() as i64);
+    let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+    let mut color = [0.0f64; 3];
+    let mut alpha = 0.0f64;
+    for (sx, sy, weight) in [
+        (x0, y0, (1.0 - fx) * (1.0 - fy)),
+        (x0 + 1, y0, fx * (1.0 - fy)),
+        (x0, y0 + 1, (1.0 - fx) * fy),
+        (x0 + 1, y0 + 1, fx * fy),
+    ] {
+        if sx < 0 || sy < 0 || sx >= width || sy >= height || weight == 0.0 {
+            continue;
+        }
+        let source = image.get_pixel(sx as u32, sy as u32);
+        let source_alpha = source[3] as f64 * weight;
+        for (channel, value) in color.iter_mut().enumerate() {
+            *value += source[channel] as f64 * source_alpha;
+        }
+        alpha += source_alpha;
+    }
+    if alpha <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    Rgba([
+        (color[0] / alpha).round() as u8,
+        (color[1] / alpha).round() as u8,
+        (color[2] / alpha).round() as u8,
+        alpha.round() as u8,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matched_accent_ignores_cropped_away_colors() {
+        // Mostly red, with a blue strip on the right that is the only part kept by the crop
+        let top = RgbaImage::from_fn(8, 8, |x, _| match x < 6 {
+            true => Rgba([255, 0, 0, 255]),
+            false => Rgba([0, 0, 255, 255]),
+        });
+        let params = CompositeParams {
+            x_val: 0.0,
+            y_val: 0.0,
+            zoom_val: 0.0,
+            crop: TopImageCrop {
+                x: 0.75,
+                width: 0.25,
+                ..Default::default()
+            },
+            transform: TopImageTransform::default(),
+            blend_mode: BlendMode::Normal,
+            color_adjustment: ColorAdjustment {
+                match_accent: true,
+                ..Default::default()
+            },
+            folder_color: gdk::RGBA::new(0.0, 1.0, 0.0, 1.0),
+        };
+        let result = IconicWindow::composite_image(
+            DynamicImage::ImageRgba8(RgbaImage::new(8, 8)),
+            DynamicImage::ImageRgba8(top),
+            imageops::FilterType::Nearest,
+            params,
+        )
+        .to_rgba8();
+        // The blue strip takes the hue of the folder, instead of being shifted by the red
+        assert_eq!(result.get_pixel(4, 4), &Rgba([0, 255, 0, 255]));
+    }
+}
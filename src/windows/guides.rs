@@ -0,0 +1,131 @@
+use adw::subclass::prelude::*;
+use gtk::prelude::*;
+use image::*;
+
+use crate::IconicWindow;
+use crate::objects::properties::BottomImageType;
+
+// The front face of the built-in folder, in slider units (-50 to 50)
+// Measured from the 128x128 folder SVG, the front face spans x 12 to 116 and y 32 to 116
+const FOLDER_FRONT_FACE: (f64, f64, f64, f64) = (-40.625, 40.625, -25.0, 40.625);
+
+// How close, in slider units, the top image needs to be before it snaps to a guide
+const SNAP_DISTANCE: f64 = 1.5;
+
+const CENTER_COLOR: Rgba<u8> = Rgba([230, 67, 146, 200]);
+const FRONT_FACE_COLOR: Rgba<u8> = Rgba([53, 132, 228, 200]);
+const GRID_COLOR: Rgba<u8> = Rgba([128, 128, 128, 110]);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GuideAxis {
+    X,
+    Y,
+}
+
+impl IconicWindow {
+    // The guides that run along the given axis, in slider units, with the color to draw them in
+    fn guide_positions(&self, axis: GuideAxis) -> Vec<(f64, Rgba<u8>)> {
+        let imp = self.imp();
+        let mut guides = vec![];
+        let grid_size = imp.settings.uint("guide-grid-size") as f64;
+        if grid_size > 0.0 {
+            let mut offset = grid_size;
+            while offset < 50.0 {
+                guides.push((offset, GRID_COLOR));
+                guides.push((-offset, GRID_COLOR));
+                offset += grid_size;
+            }
+        }
+        let has_front_face = matches!(
+            imp.file_properties.borrow().bottom_image_type,
+            BottomImageType::FolderSystem
+                | BottomImageType::Folder(_)
+                | BottomImageType::FolderCustom(_, _)
+        );
+        if has_front_face {
+            let (left, right, top, bottom) = FOLDER_FRONT_FACE;
+            match axis {
+                GuideAxis::X => {
+                    guides.extend([(left, FRONT_FACE_COLOR), (right, FRONT_FACE_COLOR)])
+                }
+                GuideAxis::Y => {
+                    guides.extend([(top, FRONT_FACE_COLOR), (bottom, FRONT_FACE_COLOR)])
+                }
+            }
+        }
+        guides.push((0.0, CENTER_COLOR));
+        guides
+    }
+
+    // Snaps the center or one of the edges of the top image to the nearest guide
+    // Returns the position unchanged if snapping is disabled or no guide is close enough
+    pub fn snap_to_guides(&self, position: f64, axis: GuideAxis) -> f64 {
+        let imp = self.imp();
+        if !imp.settings.boolean("snap-to-guides") {
+            return position;
+        }
+        let extent = self.top_image_half_extent(axis);
+        let mut snapped = position;
+        let mut closest = SNAP_DISTANCE;
+        for (guide, _) in self.guide_positions(axis) {
+            for anchor in [0.0, -extent, extent] {
+                let distance = (position + anchor - guide).abs();
+                if distance < closest {
+                    closest = distance;
+                    snapped = guide - anchor;
+                }
+            }
+        }
+        snapped
+    }
+
+    // Half of the width or height of the top image, in slider units
+    fn top_image_half_extent(&self, axis: GuideAxis) -> f64 {
+        let imp = self.imp();
+        let base = imp
+            .bottom_image_file
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|file| file.thumbnail.dimensions());
+        let top = imp.top_image_file.lock().unwrap().as_ref().map(|file| {
+            imp.top_image_crop
+                .get()
+                .cropped_size(file.thumbnail.dimensions())
+        });
+        let (Some(base), Some(top)) = (base, top) else {
+            return 0.0;
+        };
+        let (width, height) =
+            IconicWindow::top_image_extent(base, top, imp.size.value(), imp.rotation_scale.value());
+        match axis {
+            GuideAxis::X => width * 50.0,
+            GuideAxis::Y => height * 50.0,
+        }
+    }
+
+    // Draws the guides on top of an image, this is only ever used for the preview
+    pub fn draw_guides(&self, image: DynamicImage) -> DynamicImage {
+        let mut image = image.to_rgba8();
+        let (width, height) = image.dimensions();
+        // Keep the lines visible on large previews
+        let thickness = (width.max(height) / 256).max(1);
+        for (position, color) in self.guide_positions(GuideAxis::X) {
+            let x = ((position + 50.0) / 100.0 * width as f64) as u32;
+            for column in x.saturating_sub(thickness / 2)..(x + thickness.div_ceil(2)).min(width) {
+                for row in 0..height {
+                    image.get_pixel_mut(column, row).blend(&color);
+                }
+            }
+        }
+        for (position, color) in self.guide_positions(GuideAxis::Y) {
+            let y = ((position + 50.0) / 100.0 * height as f64) as u32;
+            for row in y.saturating_sub(thickness / 2)..(y + thickness.div_ceil(2)).min(height) {
+                for column in 0..width {
+                    image.get_pixel_mut(column, row).blend(&color);
+                }
+            }
+        }
+        DynamicImage::ImageRgba8(image)
+    }
+}
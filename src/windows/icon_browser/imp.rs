@@ -0,0 +1,51 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use adw::subclass::prelude::*;
+use gio::glib::subclass::InitializingObject;
+use gtk::glib;
+
+use crate::objects::icon_library::InstalledIcon;
+
+#[derive(Default, gtk::CompositeTemplate)]
+#[template(resource = "/nl/emphisia/icon/windows/icon_browser/window.ui")]
+pub struct IconBrowser {
+    #[template_child]
+    pub search_entry: TemplateChild<gtk::SearchEntry>,
+    #[template_child]
+    pub kind_dropdown: TemplateChild<gtk::DropDown>,
+    #[template_child]
+    pub stack: TemplateChild<gtk::Stack>,
+    #[template_child]
+    pub icon_grid: TemplateChild<gtk::GridView>,
+    pub icons: RefCell<HashMap<String, InstalledIcon>>,
+    pub names: gtk::StringList,
+    pub loaded: Cell<bool>,
+    pub sender: RefCell<Option<async_channel::Sender<InstalledIcon>>>,
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for IconBrowser {
+    const NAME: &'static str = "IconBrowser";
+    type Type = super::IconBrowser;
+    type ParentType = adw::Dialog;
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.bind_template();
+    }
+
+    fn instance_init(obj: &InitializingObject<Self>) {
+        obj.init_template();
+    }
+}
+
+impl ObjectImpl for IconBrowser {
+    fn constructed(&self) {
+        self.parent_constructed();
+        self.obj().setup_grid();
+    }
+}
+
+impl WidgetImpl for IconBrowser {}
+
+impl AdwDialogImpl for IconBrowser {}
@@ -0,0 +1,205 @@
+mod imp;
+
+use adw::prelude::*;
+use adw::subclass::prelude::ObjectSubclassIsExt;
+use gtk::glib::clone;
+use gtk::{gio, glib};
+use log::*;
+
+use crate::objects::icon_library::{self, InstalledIcon};
+
+glib::wrapper! {
+    pub struct IconBrowser(ObjectSubclass<imp::IconBrowser>)
+        @extends gtk::Widget, adw::Dialog,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::ShortcutManager;
+}
+
+impl Default for IconBrowser {
+    fn default() -> Self {
+        glib::Object::new()
+    }
+}
+
+impl IconBrowser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Shows the browser and waits until an icon is picked.
+    // Returns None if the dialog is closed without picking anything
+    pub async fn choose(&self, parent: &impl IsA<gtk::Widget>) -> Option<InstalledIcon> {
+        let (sender, receiver) = async_channel::bounded(1);
+        self.imp().sender.replace(Some(sender));
+        // Dropping the sender makes the receiver return an error, which ends the wait
+        self.connect_closed(|browser| {
+            browser.imp().sender.take();
+        });
+        self.present(Some(parent));
+        self.load_icons().await;
+        receiver.recv().await.ok()
+    }
+
+    fn setup_grid(&self) {
+        let imp = self.imp();
+        let filter = gtk::CustomFilter::new(clone!(
+            #[weak(rename_to = browser)]
+            self,
+            #[upgrade_or]
+            false,
+            move |item| browser.icon_visible(item)
+        ));
+        let filter_model = gtk::FilterListModel::new(Some(imp.names.clone()), Some(filter.clone()));
+        imp.icon_grid
+            .set_model(Some(&gtk::NoSelection::new(Some(filter_model.clone()))));
+        imp.icon_grid.set_factory(Some(&self.create_factory()));
+
+        imp.search_entry.connect_search_changed(clone!(
+            #[weak]
+            filter,
+            move |_| filter.changed(gtk::FilterChange::Different)
+        ));
+        imp.kind_dropdown.connect_selected_notify(clone!(
+            #[weak]
+            filter,
+            move |_| filter.changed(gtk::FilterChange::Different)
+        ));
+        filter_model.connect_items_changed(clone!(
+            #[weak(rename_to = browser)]
+            self,
+            move |model, _, _, _| browser.show_results(model.n_items())
+        ));
+        imp.icon_grid.connect_activate(clone!(
+            #[weak(rename_to = browser)]
+            self,
+            move |grid, position| browser.icon_activated(grid, position)
+        ));
+    }
+
+    fn create_factory(&self) -> gtk::SignalListItemFactory {
+        let factory = gtk::SignalListItemFactory::new();
+        factory.connect_setup(|_, item| {
+            let Some(item) = item.downcast_ref::<gtk::ListItem>() else {
+                return;
+            };
+            let content = gtk::Box::builder()
+                .orientation(gtk::Orientation::Vertical)
+                .spacing(6)
+                .margin_top(6)
+                .margin_bottom(6)
+                .build();
+            content.append(&gtk::Image::builder().pixel_size(48).build());
+            content.append(
+                &gtk::Label::builder()
+                    .ellipsize(gtk::pango::EllipsizeMode::End)
+                    .max_width_chars(12)
+                    .build(),
+            );
+            item.set_child(Some(&content));
+        });
+        factory.connect_bind(clone!(
+            #[weak(rename_to = browser)]
+            self,
+            move |_, item| {
+                let Some(item) = item.downcast_ref::<gtk::ListItem>() else {
+                    return;
+                };
+                let Some(name) = item
+                    .item()
+                    .and_downcast::<gtk::StringObject>()
+                    .map(|object| object.string())
+                else {
+                    return;
+                };
+                let Some(content) = item.child() else {
+                    return;
+                };
+                if let Some(image) = content.first_child().and_downcast::<gtk::Image>() {
+                    image.set_from_file(
+                        browser
+                            .imp()
+                            .icons
+                            .borrow()
+                            .get(name.as_str())
+                            .map(|icon| icon.path.clone()),
+                    );
+                }
+                if let Some(label) = content.last_child().and_downcast::<gtk::Label>() {
+                    label.set_label(&name);
+                }
+                content.set_tooltip_text(Some(&name));
+            }
+        ));
+        factory
+    }
+
+    async fn load_icons(&self) {
+        let imp = self.imp();
+        let icons = gio::spawn_blocking(icon_library::find_installed_icons)
+            .await
+            .unwrap_or_default();
+        let names: Vec<String> = icons.iter().map(|icon| icon.name.clone()).collect();
+        // The icons need to be known before the names are added, as the filter looks them up
+        imp.icons.replace(
+            icons
+                .into_iter()
+                .map(|icon| (icon.name.clone(), icon))
+                .collect(),
+        );
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        imp.names.splice(0, imp.names.n_items(), &names);
+        imp.loaded.set(true);
+        self.show_results(names.len() as u32);
+    }
+
+    fn icon_visible(&self, item: &glib::Object) -> bool {
+        let imp = self.imp();
+        let Some(name) = item
+            .downcast_ref::<gtk::StringObject>()
+            .map(|object| object.string())
+        else {
+            return false;
+        };
+        let symbolic = imp
+            .icons
+            .borrow()
+            .get(name.as_str())
+            .is_some_and(|icon| icon.symbolic);
+        let kind_matches = match imp.kind_dropdown.selected() {
+            1 => symbolic,
+            2 => !symbolic,
+            _ => true,
+        };
+        let search = imp.search_entry.text().to_lowercase();
+        kind_matches && name.to_lowercase().contains(&search)
+    }
+
+    fn show_results(&self, n_items: u32) {
+        let imp = self.imp();
+        if !imp.loaded.get() {
+            return;
+        }
+        imp.stack
+            .set_visible_child_name(if n_items == 0 { "empty" } else { "icons" });
+    }
+
+    fn icon_activated(&self, grid: &gtk::GridView, position: u32) {
+        let imp = self.imp();
+        let Some(name) = grid
+            .model()
+            .and_then(|model| model.item(position))
+            .and_downcast::<gtk::StringObject>()
+            .map(|object| object.string())
+        else {
+            return;
+        };
+        let Some(icon) = imp.icons.borrow().get(name.as_str()).cloned() else {
+            warn!("Icon {name} not found");
+            return;
+        };
+        debug!("Picked icon {:?}", icon);
+        if let Some(sender) = imp.sender.borrow().as_ref() {
+            let _ = sender.try_send(icon);
+        }
+        self.close();
+    }
+}
@@ -0,0 +1,46 @@
+use std::cell::RefCell;
+
+use adw::subclass::prelude::*;
+use gio::glib::subclass::InitializingObject;
+use gtk::glib;
+
+use crate::objects::regeneration_report::ReportEntry;
+
+#[derive(Default, gtk::CompositeTemplate)]
+#[template(resource = "/nl/emphisia/icon/windows/icon_selection_dialog/window.ui")]
+pub struct IconSelectionDialog {
+    #[template_child]
+    pub option_group: TemplateChild<adw::PreferencesGroup>,
+    #[template_child]
+    pub option_row: TemplateChild<adw::ComboRow>,
+    #[template_child]
+    pub options: TemplateChild<gtk::StringList>,
+    #[template_child]
+    pub icons_group: TemplateChild<adw::PreferencesGroup>,
+    #[template_child]
+    pub apply_button: TemplateChild<gtk::Button>,
+    pub entries: RefCell<Vec<(ReportEntry, gtk::CheckButton)>>,
+    pub sender: RefCell<Option<async_channel::Sender<(Vec<ReportEntry>, u32)>>>,
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for IconSelectionDialog {
+    const NAME: &'static str = "IconSelectionDialog";
+    type Type = super::IconSelectionDialog;
+    type ParentType = adw::Dialog;
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.bind_template();
+        klass.bind_template_instance_callbacks();
+    }
+
+    fn instance_init(obj: &InitializingObject<Self>) {
+        obj.init_template();
+    }
+}
+
+impl ObjectImpl for IconSelectionDialog {}
+
+impl WidgetImpl for IconSelectionDialog {}
+
+impl AdwDialogImpl for IconSelectionDialog {}
@@ -0,0 +1,119 @@
+mod imp;
+
+use adw::prelude::*;
+use adw::subclass::prelude::ObjectSubclassIsExt;
+use gtk::glib;
+use gtk::glib::clone;
+
+use crate::objects::regeneration_report::ReportEntry;
+
+glib::wrapper! {
+    pub struct IconSelectionDialog(ObjectSubclass<imp::IconSelectionDialog>)
+        @extends gtk::Widget, adw::Dialog,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::ShortcutManager;
+}
+
+#[gtk::template_callbacks]
+impl IconSelectionDialog {
+    // Lets the user pick some of the entries, and one of the options to apply to them
+    // describe creates the subtitle of every entry
+    pub fn new(
+        title: &str,
+        apply_label: &str,
+        entries: Vec<ReportEntry>,
+        describe: impl Fn(&ReportEntry) -> String,
+    ) -> Self {
+        let dialog: Self = glib::Object::new();
+        dialog.set_title(title);
+        dialog.imp().apply_button.set_label(apply_label);
+        for entry in entries {
+            let subtitle = describe(&entry);
+            dialog.add_entry(entry, &subtitle);
+        }
+        dialog
+    }
+
+    pub fn set_options(&self, title: &str, description: &str, options: &[String]) {
+        let imp = self.imp();
+        imp.option_row.set_title(title);
+        imp.option_group.set_description(Some(description));
+        for option in options {
+            imp.options.append(option);
+        }
+    }
+
+    // Shows the dialog and waits until the icons and an option are picked
+    // Returns the selected entries and the index of the option,
+    // or None if the dialog is closed without changing anything
+    pub async fn choose(&self, parent: &impl IsA<gtk::Widget>) -> Option<(Vec<ReportEntry>, u32)> {
+        let (sender, receiver) = async_channel::bounded(1);
+        self.imp().sender.replace(Some(sender));
+        // Dropping the sender makes the receiver return an error, which ends the wait
+        self.connect_closed(|dialog| {
+            dialog.imp().sender.take();
+        });
+        self.present(Some(parent));
+        receiver.recv().await.ok()
+    }
+
+    fn add_entry(&self, entry: ReportEntry, subtitle: &str) {
+        let imp = self.imp();
+        let check_button = gtk::CheckButton::builder()
+            .valign(gtk::Align::Center)
+            .build();
+        check_button.connect_toggled(clone!(
+            #[weak(rename_to = dialog)]
+            self,
+            move |_| dialog.update_apply_button()
+        ));
+        let row = adw::ActionRow::builder()
+            .title(glib::markup_escape_text(&entry.file_name))
+            .subtitle(glib::markup_escape_text(subtitle))
+            .activatable_widget(&check_button)
+            .build();
+        row.add_prefix(&check_button);
+        imp.icons_group.add(&row);
+        imp.entries.borrow_mut().push((entry, check_button));
+    }
+
+    fn update_apply_button(&self) {
+        let imp = self.imp();
+        let any_selected = imp
+            .entries
+            .borrow()
+            .iter()
+            .any(|(_, check_button)| check_button.is_active());
+        imp.apply_button.set_sensitive(any_selected);
+    }
+
+    #[template_callback]
+    fn select_all_clicked(&self) {
+        // Clone the buttons first, as toggling them borrows the entries again
+        let check_buttons: Vec<gtk::CheckButton> = self
+            .imp()
+            .entries
+            .borrow()
+            .iter()
+            .map(|(_, check_button)| check_button.clone())
+            .collect();
+        for check_button in check_buttons {
+            check_button.set_active(true);
+        }
+    }
+
+    #[template_callback]
+    fn apply_clicked(&self) {
+        let imp = self.imp();
+        let selected: Vec<ReportEntry> = imp
+            .entries
+            .borrow()
+            .iter()
+            .filter(|(_, check_button)| check_button.is_active())
+            .map(|(entry, _)| entry.clone())
+            .collect();
+        if let Some(sender) = imp.sender.take() {
+            let _ = sender.try_send((selected, imp.option_row.selected()));
+        }
+        self.close();
+    }
+}
@@ -0,0 +1,38 @@
+use std::cell::RefCell;
+
+use adw::subclass::prelude::*;
+use gio::glib::subclass::InitializingObject;
+use gtk::glib;
+
+#[derive(Default, gtk::CompositeTemplate)]
+#[template(resource = "/nl/emphisia/icon/windows/icon_size_dialog/window.ui")]
+pub struct IconSizeDialog {
+    #[template_child]
+    pub sizes_group: TemplateChild<adw::PreferencesGroup>,
+    #[template_child]
+    pub open_button: TemplateChild<gtk::Button>,
+    pub check_buttons: RefCell<Vec<gtk::CheckButton>>,
+    pub sender: RefCell<Option<async_channel::Sender<usize>>>,
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for IconSizeDialog {
+    const NAME: &'static str = "IconSizeDialog";
+    type Type = super::IconSizeDialog;
+    type ParentType = adw::Dialog;
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.bind_template();
+        klass.bind_template_instance_callbacks();
+    }
+
+    fn instance_init(obj: &InitializingObject<Self>) {
+        obj.init_template();
+    }
+}
+
+impl ObjectImpl for IconSizeDialog {}
+
+impl WidgetImpl for IconSizeDialog {}
+
+impl AdwDialogImpl for IconSizeDialog {}
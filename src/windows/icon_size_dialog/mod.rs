@@ -0,0 +1,93 @@
+mod imp;
+
+use adw::prelude::*;
+use adw::subclass::prelude::ObjectSubclassIsExt;
+use gettextrs::gettext;
+use gtk::{gdk, glib};
+
+use crate::objects::icon_container::IconContainer;
+
+glib::wrapper! {
+    pub struct IconSizeDialog(ObjectSubclass<imp::IconSizeDialog>)
+        @extends gtk::Widget, adw::Dialog,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::ShortcutManager;
+}
+
+#[gtk::template_callbacks]
+impl IconSizeDialog {
+    // previews has a texture for every entry of the container, the recommended entry is selected
+    pub fn new(container: &IconContainer, previews: &[gdk::Texture], recommended: usize) -> Self {
+        let dialog: Self = glib::Object::new();
+        for (index, (entry, preview)) in container.entries.iter().zip(previews).enumerate() {
+            let mut subtitle = format!(
+                "{}, {} {}",
+                entry.format_name(),
+                entry.bit_depth,
+                gettext("bit")
+            );
+            if index == recommended {
+                subtitle = format!("{subtitle}, {}", gettext("best match for the render size"));
+            }
+            dialog.add_size(
+                &format!("{} × {}", entry.width, entry.height),
+                &subtitle,
+                preview,
+                index == recommended,
+            );
+        }
+        dialog
+    }
+
+    // Shows the dialog and waits until a size is picked
+    // Returns the index of the entry, or None if the dialog is closed
+    pub async fn choose(&self, parent: &impl IsA<gtk::Widget>) -> Option<usize> {
+        let (sender, receiver) = async_channel::bounded(1);
+        self.imp().sender.replace(Some(sender));
+        // Dropping the sender makes the receiver return an error, which ends the wait
+        self.connect_closed(|dialog| {
+            dialog.imp().sender.take();
+        });
+        self.present(Some(parent));
+        receiver.recv().await.ok()
+    }
+
+    fn add_size(&self, title: &str, subtitle: &str, preview: &gdk::Texture, active: bool) {
+        let imp = self.imp();
+        let check_button = gtk::CheckButton::builder()
+            .valign(gtk::Align::Center)
+            .active(active)
+            .build();
+        if let Some(first) = imp.check_buttons.borrow().first() {
+            check_button.set_group(Some(first));
+        }
+        let image = gtk::Image::builder()
+            .paintable(preview)
+            .pixel_size(32)
+            .build();
+        let row = adw::ActionRow::builder()
+            .title(title)
+            .subtitle(subtitle)
+            .activatable_widget(&check_button)
+            .build();
+        row.add_prefix(&check_button);
+        row.add_suffix(&image);
+        imp.sizes_group.add(&row);
+        imp.check_buttons.borrow_mut().push(check_button);
+    }
+
+    #[template_callback]
+    fn open_clicked(&self) {
+        let imp = self.imp();
+        let selected = imp
+            .check_buttons
+            .borrow()
+            .iter()
+            .position(|check_button| check_button.is_active());
+        if let Some(sender) = imp.sender.take()
+            && let Some(index) = selected
+        {
+            let _ = sender.try_send(index);
+        }
+        self.close();
+    }
+}
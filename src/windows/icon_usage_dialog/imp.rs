@@ -0,0 +1,47 @@
+use std::cell::{OnceCell, RefCell};
+
+use adw::subclass::prelude::*;
+use gio::glib::subclass::InitializingObject;
+use gtk::{gio, glib};
+
+use crate::objects::icon_usage::FolderReference;
+
+#[derive(Default, gtk::CompositeTemplate)]
+#[template(resource = "/nl/emphisia/icon/windows/icon_usage_dialog/window.ui")]
+pub struct IconUsageDialog {
+    #[template_child]
+    pub toast_overlay: TemplateChild<adw::ToastOverlay>,
+    #[template_child]
+    pub summary_group: TemplateChild<adw::PreferencesGroup>,
+    #[template_child]
+    pub broken_group: TemplateChild<adw::PreferencesGroup>,
+    #[template_child]
+    pub repair_all_button: TemplateChild<gtk::Button>,
+    #[template_child]
+    pub valid_group: TemplateChild<adw::PreferencesGroup>,
+    pub settings: OnceCell<gio::Settings>,
+    pub accent_color: OnceCell<String>,
+    pub broken_rows: RefCell<Vec<(FolderReference, adw::ActionRow)>>,
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for IconUsageDialog {
+    const NAME: &'static str = "IconUsageDialog";
+    type Type = super::IconUsageDialog;
+    type ParentType = adw::Dialog;
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.bind_template();
+        klass.bind_template_instance_callbacks();
+    }
+
+    fn instance_init(obj: &InitializingObject<Self>) {
+        obj.init_template();
+    }
+}
+
+impl ObjectImpl for IconUsageDialog {}
+
+impl WidgetImpl for IconUsageDialog {}
+
+impl AdwDialogImpl for IconUsageDialog {}
@@ -0,0 +1,225 @@
+mod imp;
+
+use std::path::Path;
+
+use adw::prelude::*;
+use adw::subclass::prelude::ObjectSubclassIsExt;
+use gettextrs::{gettext, ngettext};
+use gtk::glib::clone;
+use gtk::{gio, glib};
+use log::*;
+
+use crate::IconicWindow;
+use crate::objects::errors::ErrorPopup;
+use crate::objects::icon_usage::{FolderReference, IconUsage, ReferenceStatus};
+use crate::objects::regenerator::Regenerator;
+
+glib::wrapper! {
+    pub struct IconUsageDialog(ObjectSubclass<imp::IconUsageDialog>)
+        @extends gtk::Widget, adw::Dialog,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::ShortcutManager;
+}
+
+#[gtk::template_callbacks]
+impl IconUsageDialog {
+    // Icons restored from a backup are regenerated with these settings and accent color
+    pub fn new(usage: &IconUsage, settings: &gio::Settings, accent_color: String) -> Self {
+        let dialog: Self = glib::Object::new();
+        let _ = dialog.imp().settings.set(settings.clone());
+        let _ = dialog.imp().accent_color.set(accent_color);
+        dialog.show_usage(usage);
+        dialog
+    }
+
+    fn show_usage(&self, usage: &IconUsage) {
+        let imp = self.imp();
+        imp.summary_group.set_title(&format!(
+            "{} {}",
+            usage.references.len(),
+            ngettext(
+                "folder uses an Iconic icon",
+                "folders use an Iconic icon",
+                usage.references.len() as u32
+            )
+        ));
+        let used_icons = usage.used_icons().len();
+        imp.summary_group.set_description(Some(&format!(
+            "{} {}, {} {}",
+            usage.scanned_folders,
+            ngettext(
+                "folder scanned",
+                "folders scanned",
+                usage.scanned_folders as u32
+            ),
+            used_icons,
+            ngettext("icon in use", "icons in use", used_icons as u32)
+        )));
+        for reference in usage.broken() {
+            self.add_broken_row(reference.clone());
+        }
+        for reference in usage
+            .references
+            .iter()
+            .filter(|reference| reference.status == ReferenceStatus::Valid)
+        {
+            let row = adw::ActionRow::builder()
+                .title(glib::markup_escape_text(
+                    &reference.folder.to_string_lossy(),
+                ))
+                .subtitle(glib::markup_escape_text(&reference.status_text()))
+                .build();
+            imp.valid_group.add(&row);
+            imp.valid_group.set_visible(true);
+        }
+        self.update_repair_all_button();
+    }
+
+    fn add_broken_row(&self, reference: FolderReference) {
+        let imp = self.imp();
+        let row = adw::ActionRow::builder()
+            .title(glib::markup_escape_text(
+                &reference.folder.to_string_lossy(),
+            ))
+            .subtitle(glib::markup_escape_text(&reference.status_text()))
+            .build();
+        row.add_prefix(&gtk::Image::from_icon_name("dialog-warning-symbolic"));
+        if reference.can_repair() {
+            let repair_button = gtk::Button::builder()
+                .label(gettext("Repair"))
+                .valign(gtk::Align::Center)
+                .build();
+            repair_button.connect_clicked(clone!(
+                #[weak(rename_to = dialog)]
+                self,
+                #[strong]
+                reference,
+                move |_| {
+                    glib::spawn_future_local(clone!(
+                        #[weak]
+                        dialog,
+                        #[strong]
+                        reference,
+                        async move {
+                            dialog.repair(vec![reference]).await;
+                        }
+                    ));
+                }
+            ));
+            row.add_suffix(&repair_button);
+        }
+        let clear_button = gtk::Button::builder()
+            .label(gettext("Clear"))
+            .tooltip_text(gettext("Show the normal folder icon for this folder"))
+            .valign(gtk::Align::Center)
+            .build();
+        clear_button.add_css_class("destructive-action");
+        clear_button.connect_clicked(clone!(
+            #[weak(rename_to = dialog)]
+            self,
+            #[strong]
+            reference,
+            move |_| dialog.clear(&reference)
+        ));
+        row.add_suffix(&clear_button);
+        imp.broken_group.add(&row);
+        imp.broken_group.set_visible(true);
+        imp.broken_rows.borrow_mut().push((reference, row));
+    }
+
+    // Repairs the references one by one, the rows of repaired folders are removed
+    async fn repair(&self, references: Vec<FolderReference>) {
+        let imp = self.imp();
+        let Some(settings) = imp.settings.get() else {
+            return;
+        };
+        let accent_color = imp.accent_color.get().cloned().unwrap_or_default();
+        let regenerator = match Regenerator::new(settings, accent_color) {
+            Ok(regenerator) => regenerator,
+            Err(error) => {
+                self.show_toast(&error.to_string());
+                return;
+            }
+        };
+        imp.repair_all_button.set_sensitive(false);
+        let data_path = IconicWindow::get_data_path();
+        let mut repaired = 0;
+        for reference in references {
+            match reference.repair(&regenerator, &data_path).await {
+                Ok(_) => {
+                    repaired += 1;
+                    self.remove_broken_row(&reference.folder);
+                }
+                Err(error) => {
+                    error!("Repairing {:?} failed: {error}", reference.folder);
+                    self.show_toast(&format!("{} {error}", gettext("Repairing failed:")));
+                }
+            }
+        }
+        regenerator.finish().log();
+        if repaired > 0 {
+            self.show_toast(&format!(
+                "{} {}",
+                repaired,
+                ngettext("folder repaired", "folders repaired", repaired as u32)
+            ));
+        }
+        self.update_repair_all_button();
+    }
+
+    fn clear(&self, reference: &FolderReference) {
+        match reference.clear() {
+            Ok(_) => {
+                self.remove_broken_row(&reference.folder);
+                self.show_toast(&gettext("Custom icon cleared"));
+            }
+            Err(error) => self.show_toast(&error.to_string()),
+        }
+        self.update_repair_all_button();
+    }
+
+    fn remove_broken_row(&self, folder: &Path) {
+        let imp = self.imp();
+        let mut broken_rows = imp.broken_rows.borrow_mut();
+        if let Some(position) = broken_rows
+            .iter()
+            .position(|(reference, _)| reference.folder == folder)
+        {
+            let (_, row) = broken_rows.remove(position);
+            imp.broken_group.remove(&row);
+        }
+        imp.broken_group.set_visible(!broken_rows.is_empty());
+    }
+
+    fn update_repair_all_button(&self) {
+        let imp = self.imp();
+        let any_repairable = imp
+            .broken_rows
+            .borrow()
+            .iter()
+            .any(|(reference, _)| reference.can_repair());
+        imp.repair_all_button.set_sensitive(any_repairable);
+    }
+
+    fn show_toast(&self, message: &str) {
+        self.imp().toast_overlay.add_toast(adw::Toast::new(message));
+    }
+
+    #[template_callback]
+    fn repair_all_clicked(&self) {
+        let references: Vec<FolderReference> = self
+            .imp()
+            .broken_rows
+            .borrow()
+            .iter()
+            .filter(|(reference, _)| reference.can_repair())
+            .map(|(reference, _)| reference.clone())
+            .collect();
+        glib::spawn_future_local(clone!(
+            #[weak(rename_to = dialog)]
+            self,
+            async move {
+                dialog.repair(references).await;
+            }
+        ));
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::Arc;
+
+use adw::subclass::prelude::*;
+use gettextrs::{gettext, ngettext};
+use gio::prelude::SettingsExt;
+use log::*;
+
+use crate::GenResult;
+use crate::IconicWindow;
+use crate::objects::errors::ErrorPopup;
+use crate::objects::file::File;
+use crate::objects::properties::{FileProperties, PropertiesSource};
+use crate::objects::regeneration_backup::replace_file_atomically;
+
+// Increase this when a new migration step is added
+// 1: Top images are stored under the SHA-256 hash of the cached image instead of the std hash
+const CACHE_VERSION: u32 = 1;
+
+// Icons saved by older versions of Iconic have a filename starting with this
+const LEGACY_FILENAME_PREFIX: &str = "folder_new-";
+
+// The result of converting the icons that store their properties in the filename
+#[derive(Debug, Default)]
+pub struct LegacyIconReport {
+    pub converted: Vec<String>,
+    pub failed: Vec<(String, String)>,
+}
+
+impl IconicWindow {
+    // Runs all migrations of the cache and the stored icons that have not run yet
+    // Does not need a window, so it also runs before headless regeneration
+    // Every icon is read and the cache is rehashed, so this happens on a worker thread
+    // Only call this while holding the RegenerationLock
+    pub async fn run_migrations(settings: &gio::Settings) {
+        let cache_version = settings.uint("cache-version");
+        if cache_version >= CACHE_VERSION {
+            return;
+        }
+        info!("Migrating cache from version {cache_version} to {CACHE_VERSION}");
+        match gio::spawn_blocking(Self::migrate_top_image_hashes)
+            .await
+            .unwrap()
+        {
+            Ok(_) => {
+                let _ = settings.set_uint("cache-version", CACHE_VERSION);
+            }
+            Err(error) => error!("Migrating top image hashes failed: {error}"),
+        }
+    }
+
+    // Renames the top images in the cache from the std hash to the hash of the cached image,
+    // and updates the metadata of the icons that use them
+    // The original image is not available anymore, so the hash is stored as top_image_cache_hash,
+    // which never matches the top_image_hash of the original image
+    // Only call this while holding the RegenerationLock
+    fn migrate_top_image_hashes() -> GenResult<()> {
+        let cache_path = Self::get_cache_path().join("top_images");
+        if !cache_path.exists() {
+            return Ok(());
+        }
+        let mut icons = vec![];
+        for entry in fs::read_dir(Self::get_data_path())?.flatten() {
+            match FileProperties::get_file_properties(&entry) {
+                Ok((properties, source))
+                    if properties.top_image_hash.is_none()
+                        && properties.top_image_cache_hash.is_none()
+                        && properties.legacy_top_image_hash.is_some() =>
+                {
+                    icons.push((properties, entry, source))
+                }
+                Ok(_) => (),
+                Err(error) => debug!("Skipping {:?}: {error}", entry.file_name()),
+            }
+        }
+
+        // The hash of icons with the properties in the filename can't be changed,
+        // so their top image has to stay available under the old name
+        let keep_legacy: HashSet<u64> = icons
+            .iter()
+            .filter(|(_, _, source)| *source == PropertiesSource::Filename)
+            .filter_map(|(properties, _, _)| properties.legacy_top_image_hash)
+            .collect();
+        let legacy_hashes: HashSet<u64> = icons
+            .iter()
+            .filter_map(|(properties, _, _)| properties.legacy_top_image_hash)
+            .collect();
+        let mut new_hashes: HashMap<u64, String> = HashMap::new();
+        for legacy_hash in legacy_hashes {
+            let old_path = cache_path.join(legacy_hash.to_string());
+            if !old_path.exists() {
+                warn!("Top image {legacy_hash} is missing from the cache");
+                continue;
+            }
+            let hash = File::create_hash(&fs::read(&old_path)?)?;
+            let new_path = cache_path.join(&hash);
+            if keep_legacy.contains(&legacy_hash) {
+                if !new_path.exists() {
+                    fs::copy(&old_path, &new_path)?;
+                }
+            } else if new_path.exists() {
+                fs::remove_file(&old_path)?;
+            } else {
+                fs::rename(&old_path, &new_path)?;
+            }
+            debug!("Moved top image {legacy_hash} to {hash}");
+            new_hashes.insert(legacy_hash, hash);
+        }
+
+        for (mut properties, entry, source) in icons {
+            if source == PropertiesSource::Filename {
+                continue;
+            }
+            let Some(hash) = properties
+                .legacy_top_image_hash
+                .and_then(|legacy_hash| new_hashes.get(&legacy_hash))
+            else {
+                continue;
+            };
+            properties.top_image_cache_hash = Some(hash.clone());
+            properties.legacy_top_image_hash = None;
+            // Only the metadata changes, so the icon is copied and the new metadata is written into the copy
+            let icon_path = entry.path();
+            replace_file_atomically(&icon_path, "migrate", |temporary_path| {
+                fs::copy(&icon_path, temporary_path)?;
+                Self::write_image_metadata(temporary_path.to_path_buf(), &properties)
+            })
+            .log();
+        }
+        Ok(())
+    }
+
+    // Writes XMP metadata into every icon that only has its properties in the filename
+    // and shows which icons could not be converted
+    // Every icon is read and rewritten, so this happens on a worker thread
+    pub async fn migrate_legacy_icons(&self) {
+        let imp = self.imp();
+        let _busy_lock = Arc::clone(&imp.app_busy);
+        // The icons are rewritten, so a regeneration in another instance has to wait
+        let _write_lock = match self.try_regeneration_lock() {
+            Ok(Some(lock)) => lock,
+            Ok(None) => return,
+            Err(error) => {
+                error!("Converting legacy icons failed: {error}");
+                return;
+            }
+        };
+        imp.toast_overlay
+            .add_toast(adw::Toast::new(&gettext("Converting legacy icons")));
+        let report = gio::spawn_blocking(|| {
+            let report = Self::convert_legacy_icons();
+            // The converted icons can now also use the new top image hash
+            Self::migrate_top_image_hashes().log();
+            report
+        })
+        .await
+        .unwrap();
+        info!(
+            "Converted {} legacy icons, {} failed",
+            report.converted.len(),
+            report.failed.len()
+        );
+
+        let mut body = format!(
+            "{} {}",
+            report.converted.len(),
+            ngettext(
+                "icon was converted",
+                "icons were converted",
+                report.converted.len() as u32
+            )
+        );
+        if !report.failed.is_empty() {
+            body.push_str("\n\n");
+            body.push_str(&gettext("These icons could not be converted:"));
+            for (name, reason) in &report.failed {
+                body.push_str(&format!("\n{name}: {reason}"));
+            }
+        }
+        self.show_alert_dialog(
+            &gettext("Legacy Icons Converted"),
+            &body,
+            vec![&gettext("OK")],
+        )
+        .await;
+    }
+
+    fn convert_legacy_icons() -> LegacyIconReport {
+        let mut report = LegacyIconReport::default();
+        let entries = match fs::read_dir(Self::get_data_path()) {
+            Ok(entries) => entries,
+            Err(error) => {
+                report.failed.push((
+                    Self::get_data_path().display().to_string(),
+                    error.to_string(),
+                ));
+                return report;
+            }
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !name.starts_with(LEGACY_FILENAME_PREFIX) {
+                continue;
+            }
+            let result = match FileProperties::get_file_properties(&entry) {
+                Ok((properties, PropertiesSource::Filename)) => {
+                    Self::write_image_metadata(entry.path(), &properties)
+                }
+                // Already has metadata
+                Ok(_) => continue,
+                Err(error) => Err(error),
+            };
+            match result {
+                Ok(_) => report.converted.push(name),
+                Err(error) => {
+                    warn!("Converting {name} failed: {error}");
+                    report.failed.push((name, error.to_string()));
+                }
+            }
+        }
+        report
+    }
+}
@@ -1,8 +1,18 @@
 pub mod alert_dialogs;
+pub mod application_browser;
 pub mod callbacks;
+pub mod crop_dialog;
 pub mod drag_drop;
 pub mod drag_overlay;
 pub mod file_handling;
+pub mod frame_dialog;
 pub mod generation;
+pub mod guides;
+pub mod icon_browser;
+pub mod icon_selection_dialog;
+pub mod icon_size_dialog;
+pub mod icon_usage_dialog;
+pub mod migration;
 pub mod preview_window;
 pub mod regeneration;
+pub mod regeneration_report;
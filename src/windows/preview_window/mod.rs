@@ -2,7 +2,8 @@ mod imp;
 
 use adw::subclass::prelude::ObjectSubclassIsExt;
 use gdk4::Texture;
-use gtk::glib;
+use gtk::prelude::*;
+use gtk::{glib, graphene};
 
 glib::wrapper! {
     pub struct PreviewWindow(ObjectSubclass<imp::PreviewWindow>)
@@ -14,4 +15,28 @@ impl PreviewWindow {
     pub fn set_paintable(&self, paintable: &Texture) {
         self.imp().image_preview.set_paintable(Some(paintable));
     }
+
+    // The area in which the preview image is actually drawn, relative to this widget
+    // The picture scales the image down to fit, but never up
+    pub fn image_bounds(&self) -> Option<graphene::Rect> {
+        let picture = &self.imp().image_preview;
+        let bounds = picture.compute_bounds(self)?;
+        let paintable = picture.paintable()?;
+        let paintable_width = paintable.intrinsic_width() as f32;
+        let paintable_height = paintable.intrinsic_height() as f32;
+        if paintable_width <= 0.0 || paintable_height <= 0.0 {
+            return None;
+        }
+        let scale = (bounds.width() / paintable_width)
+            .min(bounds.height() / paintable_height)
+            .min(1.0);
+        let width = paintable_width * scale;
+        let height = paintable_height * scale;
+        Some(graphene::Rect::new(
+            bounds.x() + (bounds.width() - width) / 2.0,
+            bounds.y() + (bounds.height() - height) / 2.0,
+            width,
+            height,
+        ))
+    }
 }
@@ -1,23 +1,37 @@
 use crate::GenResult;
-use crate::objects::errors::IntoResult;
+use crate::IconicWindow;
+use crate::objects::errors::ErrorPopup;
 use crate::objects::file::File;
-use crate::objects::properties::{BottomImageType, FileProperties, PropertiesSource};
-use crate::{IconicWindow, objects::errors::show_error_popup};
+use crate::objects::icon_library::ResolvedIcons;
+use crate::objects::properties::{
+    BottomImageType, FileProperties, PropertiesSource, RegenerationPolicy, TopImageSource,
+};
+use crate::objects::regeneration_backup::{
+    RegenerationBackup, RegenerationLock, replace_file_atomically,
+};
+use crate::objects::regeneration_report::{RegenerationReport, ReportEntry, ReportStatus};
+use crate::objects::regenerator::Regenerator;
+use crate::windows::icon_selection_dialog::IconSelectionDialog;
+use crate::windows::regeneration_report::RegenerationReportDialog;
 
-use crate::objects::properties::CustomRGB;
 use adw::TimedAnimation;
 use adw::{prelude::*, subclass::prelude::*};
 use gettextrs::{gettext, ngettext};
 use gio::glib;
 use gio::prelude::SettingsExt;
-use gtk::gdk::RGBA;
 use gtk::gio;
 use image::*;
 use log::*;
-use std::fs::{self, DirEntry};
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+// The folder colors in the same order as the folder color dropdown, named like the selected-accent-color setting
+const FOLDER_COLORS: [&str; 11] = [
+    "None", "Blue", "Teal", "Green", "Yellow", "Orange", "Red", "Pink", "Purple", "Slate", "Custom",
+];
+
 impl IconicWindow {
     pub fn check_if_regeneration_needed(&self) -> bool {
         let imp = self.imp();
@@ -62,9 +76,8 @@ impl IconicWindow {
             debug!("Top icon cache dir does not yet exist, creating");
             fs::create_dir(&cache_path)?;
         }
-        let file_name = file.hash;
         let mut file_path = cache_path.clone();
-        file_path.push(file_name.to_string());
+        file_path.push(&file.hash);
         debug!("File path: {:?}", file_path);
         debug!("File name: {:?}", file.filename);
         match file_path.exists() {
@@ -79,11 +92,12 @@ impl IconicWindow {
         };
         // Only if the orignal image path is present, and it has not been shrunk
         // Save the original, else save the generated dynamic image
+        // Icon containers and animations hold several images, so only the chosen one is saved
         let new_file = gio::File::for_path(&file_path);
         let filestream = new_file.open_readwrite(gio::Cancellable::NONE)?;
         let test = filestream.output_stream();
         if let Some(original_file) = &file.files {
-            if !file.dynamic_image_resized {
+            if !file.dynamic_image_resized && file.image_index.is_none() {
                 info!("Saving original image to cache");
                 let buffer = original_file.load_bytes(gio::Cancellable::NONE)?;
                 test.write_bytes(&buffer.0, gio::Cancellable::NONE)?;
@@ -96,6 +110,40 @@ impl IconicWindow {
         Ok(())
     }
 
+    // Icons can not be changed while a regeneration is writing them, also not by one in another process
+    pub fn try_regeneration_lock(&self) -> GenResult<Option<RegenerationLock>> {
+        let imp = self.imp();
+        let lock = match imp.regeneration_revealer.reveals_child() {
+            true => None,
+            false => RegenerationLock::try_acquire()?,
+        };
+        if lock.is_none() {
+            imp.toast_overlay.add_toast(adw::Toast::new(&gettext(
+                "Wait for the regeneration to finish",
+            )));
+        }
+        Ok(lock)
+    }
+
+    // Scans the icons with the current settings on a worker thread, so the window keeps responding
+    async fn scan_regeneration_report(&self) -> GenResult<RegenerationReport> {
+        let imp = self.imp();
+        let strict = imp.settings.boolean("strict-regeneration");
+        let ignore_custom = imp.settings.boolean("ignore-custom");
+        gio::spawn_blocking(move || {
+            RegenerationReport::scan(&Self::get_data_path(), strict, ignore_custom)
+        })
+        .await
+        .unwrap()
+    }
+
+    // Shows which icons would be regenerated with the current settings, without changing any files
+    pub async fn show_regeneration_report(&self) -> GenResult<()> {
+        let report = self.scan_regeneration_report().await?;
+        RegenerationReportDialog::new(&report, true).present(Some(self));
+        Ok(())
+    }
+
     // This function regenerates icon, it replaces all images that were dragged and dropped with ones of the correct system accent color.
     pub async fn regenerate_icons(&self) -> GenResult<()> {
         let imp = self.imp();
@@ -103,10 +151,16 @@ impl IconicWindow {
         // First set iconic as busy. By getting a Arc reference
         // I doubt this is the best approach, but Hey it works!
         let _iconic_busy = Arc::clone(&imp.app_busy);
-        let data_path = self.get_data_path();
-        let mut incompatible_files_n: u32 = 0;
-        let compatible_files =
-            self.find_regeneratable_icons(data_path, &mut incompatible_files_n)?;
+        // Waits for a regeneration in another process, or one this one replaces, to finish writing
+        let regeneration_lock = imp.regeneration_lock.clone();
+        let Some(write_lock) =
+            RegenerationLock::acquire(move || regeneration_lock.get() != id).await?
+        else {
+            return Ok(());
+        };
+        Self::run_migrations(&imp.settings).await;
+        let mut report = self.scan_regeneration_report().await?;
+        let compatible_files: Vec<ReportEntry> = report.regeneratable().cloned().collect();
 
         // Stop if there are no files to regenerate
         match compatible_files.len() {
@@ -120,6 +174,7 @@ impl IconicWindow {
                     .add_toast(adw::Toast::new(&gettext("Regenerating icons")));
             }
         }
+        let regenerator = Regenerator::new(&imp.settings, self.get_accent_color())?;
         imp.regeneration_revealer.set_reveal_child(true);
         imp.regeneration_osd.set_fraction(0.0);
         imp.regeneration_osd_second.set_fraction(0.0);
@@ -129,58 +184,59 @@ impl IconicWindow {
         let step_size = 1.0 / files_n as f64;
         let mut regeneration_errors = vec![];
 
-        for file in compatible_files {
-            // In the regeneration lock, a value is saved, if it has changed.
-            // It means a new regeneration instance has started. So stop this one
-            // This is done to prevent two instances from fighting if for example
-            // The accent color is changed during regeneration
-            if imp.regeneration_lock.get() != id {
+        // In the regeneration lock, a value is saved, if it has changed.
+        // It means a new regeneration instance has started. So stop this one
+        // This is done to prevent two instances from fighting if for example
+        // The accent color is changed during regeneration
+        let regeneration_lock = imp.regeneration_lock.clone();
+        let superseded = move || {
+            let superseded = regeneration_lock.get() != id;
+            if superseded {
                 error!("Stopping regeneration");
-                break;
             }
-            let name = &file.1.file_name();
-            match self
-                .regenerate_and_save_single_icon(file.0, file.1, file.2)
-                .await
-            {
-                Ok(_) => (),
-                Err(error) => {
-                    error!("Error while generating {:?}: {}", &name, &error.to_string());
-                    // Everytime an error occurs. Push this to the errors list
+            superseded
+        };
+        regenerator
+            .regenerate_all(compatible_files, superseded, |entry, result| {
+                if let Err(error) = result {
+                    error!(
+                        "Error while generating {:?}: {}",
+                        &entry.file_name,
+                        &error.to_string()
+                    );
+                    // Everytime an error occurs. Mark it in the report, so the reason can be shown
+                    report.mark_failed(&entry.path, error.to_string());
                     regeneration_errors.push(error)
                 }
-            }
-            // Update the progress bars
-            // I need two as the animation needs to play on both welcome and main screen which is not possible
-            // with only one animation. Unless i'm missing something
-            last_animation = Some(self.progress_animation(
-                step_size,
-                last_animation,
-                imp.regeneration_osd.clone(),
-            ));
-            last_animation_second = Some(self.progress_animation(
-                step_size,
-                last_animation_second,
-                imp.regeneration_osd_second.clone(),
-            ));
-        }
+                // Icons of a stopped regeneration can still finish, they should not move the progress bars of the new one
+                if imp.regeneration_lock.get() != id {
+                    return;
+                }
+                // Update the progress bars
+                // I need two as the animation needs to play on both welcome and main screen which is not possible
+                // with only one animation. Unless i'm missing something
+                last_animation = Some(self.progress_animation(
+                    step_size,
+                    last_animation.take(),
+                    imp.regeneration_osd.clone(),
+                ));
+                last_animation_second = Some(self.progress_animation(
+                    step_size,
+                    last_animation_second.take(),
+                    imp.regeneration_osd_second.clone(),
+                ));
+            })
+            .await;
+        regenerator.finish().log();
+        drop(write_lock);
         // If the errors list is not empty
-        // Show a pop up with the ammount of errors
+        // Show the report, which lists why each file failed
         if !regeneration_errors.is_empty() {
-            show_error_popup(
-                &self,
-                &format!(
-                    "{} {}",
-                    regeneration_errors.len(),
-                    &ngettext(
-                        "file failed to regenerate\nview logs for more information",
-                        "files failed to regenerate\nview logs for more information",
-                        regeneration_errors.len() as u32
-                    )
-                ),
-                true,
-                None::<String>,
-            );
+            RegenerationReportDialog::new(&report, false).present(Some(self));
+        }
+        // A newer regeneration is still running, leave the progress bars to it
+        if imp.regeneration_lock.get() != id {
+            return Ok(());
         }
         imp.toast_overlay.add_toast(adw::Toast::new(&gettext(
             "Regeneration sucessful, restart nautilus",
@@ -191,256 +247,321 @@ impl IconicWindow {
         Ok(())
     }
 
-    // This function regenerates a single compatible icon
-    async fn regenerate_and_save_single_icon(
-        &self,
-        mut properties: FileProperties,
-        file: DirEntry,
-        property_source: PropertiesSource,
-    ) -> GenResult<()> {
+    // Moves the selected icons onto a folder with a different color, and regenerates them
+    // Icons that use the default top color get the color of the new folder
+    pub async fn retarget_icons(&self) -> GenResult<()> {
         let imp = self.imp();
-
-        // Get path and filename of icon saved in datadir
-        let file_path = file.path();
-
-        let strict_mode_enabled = imp.settings.boolean("strict-regeneration");
-        let ignore_custom_colored = imp.settings.boolean("ignore-custom");
-
-        // Icons that are compatible for regeneration are only allowed to use default folder images.
-        // So when regenerating icons, you need the folder which is the same color as the current accent color
-        let (bottom_image_file, custom_accent_color, custom_accent_color_hex) = match properties
-            .bottom_image_type
-            .clone()
-        {
-            BottomImageType::FolderSystem => (
-                self.get_bottom_icon_from_accent_color(None, strict_mode_enabled)
-                    .await?,
-                None,
-                None,
-            ),
-            BottomImageType::Folder(color) if !properties.default || !strict_mode_enabled => (
-                self.get_bottom_icon_from_accent_color(Some(color.clone()), strict_mode_enabled)
-                    .await?,
-                Some(color),
-                None,
+        let Some(_write_lock) = self.try_regeneration_lock()? else {
+            return Ok(());
+        };
+        Self::run_migrations(&imp.settings).await;
+        let report = self.scan_regeneration_report().await?;
+        // Only icons that can be recreated from their properties can be moved to another folder
+        let candidates: Vec<ReportEntry> = report
+            .entries
+            .into_iter()
+            .filter(|entry| entry.properties.is_some() && entry.top_image_available)
+            .collect();
+        if candidates.is_empty() {
+            imp.toast_overlay.add_toast(adw::Toast::new(&gettext(
+                "There are no icons that can change color",
+            )));
+            return Ok(());
+        }
+        let dialog = IconSelectionDialog::new(
+            &gettext("Change Folder Color"),
+            &gettext("Change Color"),
+            candidates,
+            ReportEntry::bottom_image_text,
+        );
+        let folder_names = [
+            gettext("Accent Color"),
+            gettext("Blue"),
+            gettext("Teal"),
+            gettext("Green"),
+            gettext("Yellow"),
+            gettext("Orange"),
+            gettext("Red"),
+            gettext("Pink"),
+            gettext("Purple"),
+            gettext("Slate"),
+            gettext("Custom"),
+        ];
+        dialog.set_options(
+            &gettext("New Folder Color"),
+            &gettext(
+                "The selected icons are regenerated on the new folder. Icons that use the default top color get the color of the new folder",
             ),
-            BottomImageType::FolderCustom(foreground, background)
-                if !properties.default || (!strict_mode_enabled || !ignore_custom_colored) =>
-            {
-                if strict_mode_enabled || ignore_custom_colored {
-                    let folder_path = self
-                        .create_custom_folder_color(&foreground, &background, true)
-                        .await;
-                    (
-                        gio::spawn_blocking(move || {
-                            File::from_path(folder_path, 1024, 0).map_err(|err| err.to_string())
-                        })
-                        .await
-                        .unwrap()?
-                        .dynamic_image,
-                        None,
-                        Some(background),
-                    )
-                } else {
-                    (
-                        self.get_bottom_icon_from_accent_color(None, strict_mode_enabled)
-                            .await?,
-                        None,
-                        Some(background),
-                    )
-                }
-            }
-            _ => return Ok(()),
+            &folder_names,
+        );
+        let Some((selected, color_index)) = dialog.choose(self).await else {
+            return Ok(());
         };
-        info!("Generating image");
+        let bottom_image_type =
+            BottomImageType::from_folder_color(FOLDER_COLORS[color_index as usize], &imp.settings);
+        let entries: Vec<ReportEntry> = selected
+            .into_iter()
+            .map(|mut entry| {
+                if let Some(properties) = entry.properties.as_mut() {
+                    properties.retarget(bottom_image_type.clone());
+                }
+                // Icons with properties in their file name get metadata, as the name can not store the new folder
+                entry.properties_source = Some(PropertiesSource::XMP);
+                entry.status = ReportStatus::Regenerate;
+                entry
+            })
+            .collect();
 
-        // If strict mode is disabled and the image is not regenerated during strict mode. And the image is regenerated, is has te be regenerated to mark it as no longer default
-        properties.default = if !strict_mode_enabled
-            && properties.bottom_image_type.is_strict_compatible() == Some(false)
-        {
-            false
-        } else {
-            true
+        // Stop a regeneration that is running, so they do not write the same icons
+        let id = imp.regeneration_lock.get() + 1;
+        imp.regeneration_lock.replace(id);
+        let _iconic_busy = Arc::clone(&imp.app_busy);
+        let mut report = RegenerationReport {
+            entries: entries.clone(),
         };
-
-        // Create the path where the top image of this file is located
-        // The top image has the same name as the hash of that image
-        let mut top_image_path = Self::get_cache_path().join("top_images");
-        top_image_path.push(
-            properties
-                .top_image_hash
-                .into_reason_result("Getting top image hash")?
-                .to_string(),
-        );
-        let top_image_file = gio::spawn_blocking(move || {
-            File::from_path(top_image_path, 1024, 0).map_err(|err| err.to_string())
-        })
-        .await
-        .unwrap()?
-        .dynamic_image;
-        // Create the top image
-        let top_image = self.set_correct_monochrome_values_based_on_image_properties(
-            &properties,
-            top_image_file,
-            custom_accent_color,
-            custom_accent_color_hex,
-            strict_mode_enabled,
-        )?;
-
-        // Using the generic generate_image function. The icon can faithfully be recreated
-        let generated_image = self
-            .generate_image(
-                bottom_image_file,
-                top_image,
-                imageops::FilterType::Gaussian,
-                properties.x_val,
-                properties.y_val,
-                properties.zoom_val,
+        let regenerator = Regenerator::exact(self.get_accent_color())?;
+        let mut changed = 0;
+        let regeneration_lock = imp.regeneration_lock.clone();
+        regenerator
+            .regenerate_all(
+                entries,
+                move || regeneration_lock.get() != id,
+                |entry, result| match result {
+                    Ok(_) => changed += 1,
+                    Err(error) => {
+                        error!(
+                            "Error while changing the color of {:?}: {}",
+                            &entry.file_name,
+                            &error.to_string()
+                        );
+                        report.mark_failed(&entry.path, error.to_string());
+                    }
+                },
             )
             .await;
-        info!("Saving image");
-        let file_path_clone = file_path.clone();
-        match gio::spawn_blocking(move || {
-            generated_image.save_with_format(file_path, ImageFormat::Png)
-        })
-        .await
-        .unwrap()
-        {
-            Ok(_) => {
-                info!("Saving Succesful");
-                if property_source == PropertiesSource::XMP {
-                    self.add_image_metadata(file_path_clone, None, Some(properties))?
-                }
-            }
-            Err(x) => error!("Saving failed: {:?}", x),
-        };
+        regenerator.finish().log();
+        if report.failed().count() > 0 {
+            RegenerationReportDialog::new(&report, false).present(Some(self));
+        }
+        imp.toast_overlay.add_toast(adw::Toast::new(&format!(
+            "{} {}",
+            changed,
+            ngettext("icon changed color", "icons changed color", changed as u32)
+        )));
+        self.close_iconic_busy_popup();
         Ok(())
     }
 
-    async fn get_bottom_icon_from_accent_color(
-        &self,
-        color: Option<String>,
-        strict: bool,
-    ) -> GenResult<DynamicImage> {
-        let accent_color = match color {
-            Some(color) if strict => color,
-            _ => self.get_accent_color(),
+    // Changes what happens to the selected icons when icons are regenerated, without regenerating them
+    pub async fn set_regeneration_policies(&self) -> GenResult<()> {
+        let imp = self.imp();
+        let Some(_write_lock) = self.try_regeneration_lock()? else {
+            return Ok(());
         };
-
-        // Icons that are compatible for regeneration are only allowed to use default folder images.
-        // So when regenerating icons, you need the folder which is the same color as the current accent color
-        let bottom_image_path = PathBuf::from(format!(
-            "/app/share/Iconic/folders/folder_{}.svg",
-            &accent_color
-        ));
-        Ok(gio::spawn_blocking(move || {
-            File::from_path(bottom_image_path, 1024, 0).map_err(|err| err.to_string())
+        let report = self.scan_regeneration_report().await?;
+        // Icons on a bottom image that is not a folder can never be regenerated
+        let candidates: Vec<ReportEntry> = report
+            .entries
+            .into_iter()
+            .filter(|entry| {
+                entry.properties.as_ref().is_some_and(|properties| {
+                    properties
+                        .bottom_image_type
+                        .is_strict_compatible()
+                        .is_some()
+                })
+            })
+            .collect();
+        if candidates.is_empty() {
+            imp.toast_overlay.add_toast(adw::Toast::new(&gettext(
+                "There are no icons that can be regenerated",
+            )));
+            return Ok(());
+        }
+        let dialog = IconSelectionDialog::new(
+            &gettext("Regeneration Policy"),
+            &gettext("Set Policy"),
+            candidates,
+            ReportEntry::policy_text,
+        );
+        let policy_names: Vec<String> = RegenerationPolicy::ALL
+            .iter()
+            .map(|policy| policy.name())
+            .collect();
+        dialog.set_options(
+            &gettext("Regeneration"),
+            &gettext("What happens to the selected icons when icons are regenerated"),
+            &policy_names,
+        );
+        let Some((selected, policy_index)) = dialog.choose(self).await else {
+            return Ok(());
+        };
+        let policy = RegenerationPolicy::from_index(policy_index);
+        let accent_color = self.get_accent_color();
+        let _iconic_busy = Arc::clone(&imp.app_busy);
+        let changed = gio::spawn_blocking(move || {
+            let mut changed = 0;
+            for entry in selected {
+                let Some(mut properties) = entry.properties else {
+                    continue;
+                };
+                properties.regeneration_policy = policy;
+                // A folder that follows the accent color can only be pinned to the color it has now
+                if policy == RegenerationPolicy::Pinned
+                    && properties.bottom_image_type == BottomImageType::FolderSystem
+                {
+                    properties.bottom_image_type = BottomImageType::Folder(accent_color.clone());
+                }
+                // Only the metadata changes, so the icon is copied and the new metadata is written into the copy
+                let result = replace_file_atomically(&entry.path, "policy", |temporary_path| {
+                    fs::copy(&entry.path, temporary_path)?;
+                    Self::write_image_metadata(temporary_path.to_path_buf(), &properties)
+                });
+                match result {
+                    Ok(_) => changed += 1,
+                    Err(error) => error!(
+                        "Setting the regeneration policy of {:?} failed: {error}",
+                        entry.file_name
+                    ),
+                }
+            }
+            changed
         })
         .await
-        .unwrap()?
-        .dynamic_image)
+        .unwrap();
+        imp.toast_overlay.add_toast(adw::Toast::new(&format!(
+            "{} {}",
+            changed,
+            ngettext("icon updated", "icons updated", changed as u32)
+        )));
+        self.close_iconic_busy_popup();
+        Ok(())
     }
 
-    // Search in the list of stored icons to see which ones are valid for regeneration
-    fn find_regeneratable_icons(
-        &self,
-        dir: PathBuf,
-        incompatible_files: &mut u32,
-    ) -> GenResult<Vec<(FileProperties, fs::DirEntry, PropertiesSource)>> {
-        let top_image_path = Self::get_cache_path().join("top_images");
-        let mut regeneratable: Vec<(FileProperties, fs::DirEntry, PropertiesSource)> = vec![];
-        // Walk the directory and loop over every file
-        let files: fs::ReadDir = fs::read_dir(&dir)?;
-        for file in files {
-            let current_file = file?;
-            let file_name = current_file.file_name();
-            debug!("File found: {:?}", file_name);
-            // Get the file properties of the file
-            // Currently does not care what
-            let properties = match FileProperties::get_file_properties(&current_file) {
-                Ok(file_properties) => file_properties,
-                Err(err) => {
-                    *incompatible_files += 1;
-                    warn!(
-                        "file {:?} failed to be parsed. Err: {}",
-                        file_name,
-                        err.to_string()
-                    );
-                    continue;
-                }
-            };
-            if properties
-                .0
-                .bottom_image_type
-                .is_strict_compatible()
-                .is_none()
-            {
-                *incompatible_files += 1;
-                warn!(
-                    "file {:?} is never compatible for be regeneration.",
-                    file_name
-                );
-                continue;
-            }
-            let mut top_image_path_clone = top_image_path.clone();
-            top_image_path_clone.push(properties.0.top_image_hash.unwrap_or_default().to_string());
+    // Custom bottom images are stored under the hash of their content, like top images
+    // So icons with different custom bottom images can all be regenerated
+    pub fn bottom_image_cache_path(hash: &str) -> PathBuf {
+        Self::get_cache_path().join("bottom_images").join(hash)
+    }
 
-            // If that top image does not exist, just mark it as not valid for regeneration
-            if !top_image_path.exists() {
-                warn!("Top image file not found");
-                *incompatible_files += 1;
-                continue;
-            }
-            regeneratable.push((properties.0, current_file, properties.1));
+    // Copies a custom bottom image into the cache and returns its hash
+    pub fn store_bottom_image_in_cache(original_path: &Path) -> GenResult<String> {
+        Self::store_bottom_image_data_in_cache(fs::read(original_path)?)
+    }
+
+    pub fn store_bottom_image_data_in_cache(data: Vec<u8>) -> GenResult<String> {
+        let hash = File::create_hash(&data)?;
+        let cache_path = Self::bottom_image_cache_path(&hash);
+        if !cache_path.exists() {
+            debug!("Storing bottom image {hash} in the cache");
+            fs::create_dir_all(Self::get_cache_path().join("bottom_images"))?;
+            fs::write(&cache_path, data)?;
         }
-        Ok(regeneratable)
+        Ok(hash)
     }
 
-    // Create the top image based on the properties of the to-be regenerated icon
-    // I am really bad at function names
-    fn set_correct_monochrome_values_based_on_image_properties(
-        &self,
+    // Where the top image of an icon is stored in the cache
+    // Migrated icons use the hash of the cached image, icons that have not been migrated yet still use the old hash as name
+    pub fn top_image_cache_path(properties: &FileProperties) -> Option<PathBuf> {
+        let cache_path = Self::get_cache_path().join("top_images");
+        let hash = properties
+            .top_image_hash
+            .as_ref()
+            .or(properties.top_image_cache_hash.as_ref())
+            .cloned()
+            .or(properties
+                .legacy_top_image_hash
+                .map(|legacy_hash| legacy_hash.to_string()));
+        hash.map(|hash| cache_path.join(hash))
+    }
+
+    // Where the top image of an icon can be loaded from
+    // Installed icons are looked up again if they are not cached, application icons are always looked up again
+    // so the icon follows when the application changes its icon
+    pub fn find_top_image(
         properties: &FileProperties,
-        top_image: DynamicImage,
-        accent_color: Option<String>,
-        rgb_string_color: Option<String>,
-        strict: bool,
-    ) -> GenResult<DynamicImage> {
-        let color = match properties.monochrome_default {
-            false => RGBA::new(
-                properties.monochrome_color.unwrap_or_default().0 as f32 / 255.0,
-                properties.monochrome_color.unwrap_or_default().1 as f32 / 255.0,
-                properties.monochrome_color.unwrap_or_default().2 as f32 / 255.0,
-                1.0,
-            ),
-            true if rgb_string_color.is_some() && strict => {
-                RGBA::from_hex(rgb_string_color.unwrap_or_default())
+        installed_icons: &ResolvedIcons,
+    ) -> Option<PathBuf> {
+        let cache_path = Self::top_image_cache_path(properties);
+        match &properties.top_image_source {
+            TopImageSource::File => cache_path,
+            TopImageSource::Icon(name) => cache_path
+                .filter(|path| path.exists())
+                .or_else(|| installed_icons.icon(name)),
+            // The cached top image is only used if the application is no longer installed
+            TopImageSource::Application(app_id) => {
+                installed_icons.application_icon(app_id).or(cache_path)
+            }
+        }
+    }
+
+    // Looks up every installed icon that find_top_image needs for these icons at once
+    // This blocks, so only call it from a worker thread
+    pub fn resolve_top_image_icons<'a>(
+        properties: impl IntoIterator<Item = &'a FileProperties>,
+    ) -> ResolvedIcons {
+        let mut icon_names = HashSet::new();
+        let mut app_ids = HashSet::new();
+        for properties in properties {
+            match &properties.top_image_source {
+                TopImageSource::File => (),
+                TopImageSource::Icon(name) => {
+                    if !Self::top_image_cache_path(properties).is_some_and(|path| path.exists()) {
+                        icon_names.insert(name.clone());
+                    }
+                }
+                TopImageSource::Application(app_id) => {
+                    app_ids.insert(app_id.clone());
+                }
             }
-            true => self.current_accent_rgba(if strict { accent_color } else { None })?,
-        };
-        match properties.monochrome_toggle {
-            true => Ok(self.to_monochrome(
-                top_image,
-                properties.monochrome_threshold_val,
-                color,
-                Some(properties.monochrome_invert),
-            )),
-            _ => Ok(top_image),
         }
+        ResolvedIcons::resolve(&icon_names, &app_ids, 1024)
     }
 
-    fn current_accent_rgba(&self, accent_color: Option<String>) -> GenResult<RGBA> {
+    // Puts back the icons as they were before the last regeneration
+    pub async fn revert_last_regeneration(&self) -> GenResult<()> {
         let imp = self.imp();
-        let accent_color = match accent_color {
-            Some(color) => color,
-            None => self.get_accent_color(),
+        let Some(_write_lock) = self.try_regeneration_lock()? else {
+            return Ok(());
         };
-        Ok(imp
-            .default_color
-            .borrow()
-            .get(&accent_color)
-            .into_result()?
-            .clone())
+        let Some(backup) = RegenerationBackup::latest()? else {
+            imp.toast_overlay.add_toast(adw::Toast::new(&gettext(
+                "There is no regeneration to revert",
+            )));
+            return Ok(());
+        };
+        let icon_count = backup.icon_count();
+        let body = format!(
+            "{} {}",
+            icon_count,
+            ngettext(
+                "icon will be restored to how it was before the last regeneration",
+                "icons will be restored to how they were before the last regeneration",
+                icon_count as u32
+            )
+        );
+        if self
+            .show_alert_dialog(
+                &gettext("Revert Last Regeneration?"),
+                &body,
+                vec![&gettext("Revert"), &gettext("Cancel")],
+            )
+            .await
+            != Some(0)
+        {
+            return Ok(());
+        }
+        let _iconic_busy = Arc::clone(&imp.app_busy);
+        let restored = gio::spawn_blocking(move || backup.restore(&IconicWindow::get_data_path()))
+            .await
+            .unwrap()?;
+        imp.toast_overlay.add_toast(adw::Toast::new(&format!(
+            "{} {}",
+            restored.len(),
+            ngettext("icon restored", "icons restored", restored.len() as u32)
+        )));
+        self.close_iconic_busy_popup();
+        Ok(())
     }
 
     fn progress_animation(
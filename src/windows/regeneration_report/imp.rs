@@ -0,0 +1,36 @@
+use adw::subclass::prelude::*;
+use gio::glib::subclass::InitializingObject;
+use gtk::glib;
+
+#[derive(Default, gtk::CompositeTemplate)]
+#[template(resource = "/nl/emphisia/icon/windows/regeneration_report/window.ui")]
+pub struct RegenerationReportDialog {
+    #[template_child]
+    pub summary_group: TemplateChild<adw::PreferencesGroup>,
+    #[template_child]
+    pub icons_group: TemplateChild<adw::PreferencesGroup>,
+    #[template_child]
+    pub action_bar: TemplateChild<gtk::ActionBar>,
+}
+
+#[glib::object_subclass]
+impl ObjectSubclass for RegenerationReportDialog {
+    const NAME: &'static str = "RegenerationReportDialog";
+    type Type = super::RegenerationReportDialog;
+    type ParentType = adw::Dialog;
+
+    fn class_init(klass: &mut Self::Class) {
+        klass.bind_template();
+        klass.bind_template_instance_callbacks();
+    }
+
+    fn instance_init(obj: &InitializingObject<Self>) {
+        obj.init_template();
+    }
+}
+
+impl ObjectImpl for RegenerationReportDialog {}
+
+impl WidgetImpl for RegenerationReportDialog {}
+
+impl AdwDialogImpl for RegenerationReportDialog {}
@@ -0,0 +1,112 @@
+mod imp;
+
+use adw::prelude::*;
+use adw::subclass::prelude::ObjectSubclassIsExt;
+use gettextrs::{gettext, ngettext};
+use gtk::glib;
+
+use crate::objects::regeneration_report::{RegenerationReport, ReportEntry, ReportStatus};
+
+glib::wrapper! {
+    pub struct RegenerationReportDialog(ObjectSubclass<imp::RegenerationReportDialog>)
+        @extends gtk::Widget, adw::Dialog,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget, gtk::ShortcutManager;
+}
+
+#[gtk::template_callbacks]
+impl RegenerationReportDialog {
+    // A dry run shows what will happen and allows starting the regeneration,
+    // otherwise the report shows the result of a regeneration that already happened
+    pub fn new(report: &RegenerationReport, dry_run: bool) -> Self {
+        let dialog: Self = glib::Object::new();
+        dialog.imp().action_bar.set_revealed(dry_run);
+        dialog.show_report(report, dry_run);
+        dialog
+    }
+
+    fn show_report(&self, report: &RegenerationReport, dry_run: bool) {
+        let imp = self.imp();
+        let regenerate_count = report.regeneratable().count();
+        let failed_count = report
+            .entries
+            .iter()
+            .filter(|entry| matches!(entry.status, ReportStatus::Failed(_)))
+            .count();
+        let mut summary = format!(
+            "{} {}",
+            regenerate_count,
+            if dry_run {
+                ngettext(
+                    "icon will be regenerated",
+                    "icons will be regenerated",
+                    regenerate_count as u32,
+                )
+            } else {
+                ngettext(
+                    "icon was regenerated",
+                    "icons were regenerated",
+                    regenerate_count as u32,
+                )
+            }
+        );
+        if failed_count > 0 {
+            summary.push_str(&format!(", {} {}", failed_count, gettext("failed")));
+        }
+        imp.summary_group.set_title(&summary);
+        imp.summary_group.set_description(Some(&format!(
+            "{} {}",
+            report.skipped_count(),
+            ngettext(
+                "icon is skipped",
+                "icons are skipped",
+                report.skipped_count() as u32
+            )
+        )));
+        if dry_run && regenerate_count == 0 {
+            imp.action_bar.set_revealed(false);
+        }
+        for entry in &report.entries {
+            imp.icons_group.add(&Self::create_row(entry));
+        }
+    }
+
+    fn create_row(entry: &ReportEntry) -> adw::ExpanderRow {
+        let row = adw::ExpanderRow::builder()
+            .title(glib::markup_escape_text(&entry.file_name))
+            .subtitle(glib::markup_escape_text(&entry.status_text()))
+            .build();
+        let icon_name = match entry.status {
+            ReportStatus::Regenerate => "emblem-ok-symbolic",
+            ReportStatus::Skipped(_) => "action-unavailable-symbolic",
+            ReportStatus::Failed(_) => "dialog-warning-symbolic",
+        };
+        row.add_prefix(&gtk::Image::from_icon_name(icon_name));
+        let yes_no = |value: bool| if value { gettext("Yes") } else { gettext("No") };
+        for (title, value) in [
+            (gettext("Properties source"), entry.source_text()),
+            (gettext("Bottom image"), entry.bottom_image_text()),
+            (gettext("Regeneration policy"), entry.policy_text()),
+            (
+                gettext("Regenerated in strict mode"),
+                yes_no(entry.strict_regenerates),
+            ),
+            (
+                gettext("Top image available"),
+                yes_no(entry.top_image_available),
+            ),
+        ] {
+            let property = adw::ActionRow::builder()
+                .title(title)
+                .subtitle(glib::markup_escape_text(&value))
+                .build();
+            property.add_css_class("property");
+            row.add_row(&property);
+        }
+        row
+    }
+
+    #[template_callback]
+    fn regenerate_clicked(&self) {
+        self.close();
+    }
+}